@@ -4,24 +4,45 @@ use axum::http::StatusCode;
 use axum::response::IntoResponse;
 
 use crate::api::controllers::auth::AuthUser;
-use crate::api::models::device::BindDeviceRequest;
+use crate::api::models::device::{BindDeviceRequest, DeviceListItem};
 use crate::api::state::ApiState;
 
 /// GET /user/devices
-/// List devices bound to the authenticated user.
+/// List devices bound to the authenticated user, with their tunnel availability.
 pub async fn list(State(state): State<ApiState>, AuthUser(claims): AuthUser) -> impl IntoResponse {
     let user_id = match claims.sub.parse() {
         Ok(id) => id,
         Err(_) => return StatusCode::UNAUTHORIZED.into_response(),
     };
 
-    match state.sqlx_client().get_user_devices(user_id).await {
-        Ok(devices) => Json(devices).into_response(),
+    let devices = match state.sqlx_client().get_user_devices(user_id).await {
+        Ok(devices) => devices,
         Err(e) => {
             tracing::error!("failed to get user devices: {e:#}");
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
-    }
+    };
+
+    // Presence is informational; the listing still works without it.
+    let device_ids: Vec<_> = devices.iter().map(|device| device.id).collect();
+    let presence = match state.get_tunnel_presence(&device_ids).await {
+        Ok(presence) => presence,
+        Err(e) => {
+            tracing::warn!("failed to get tunnel presence: {e:#}");
+            Vec::new()
+        }
+    };
+
+    let mut presence = presence.into_iter();
+    let devices: Vec<DeviceListItem> = devices
+        .into_iter()
+        .map(|device| DeviceListItem {
+            device,
+            tunnel: presence.next().flatten(),
+        })
+        .collect();
+
+    Json(devices).into_response()
 }
 
 /// POST /user/devices
@@ -36,7 +57,7 @@ pub async fn bind(
         Err(_) => return StatusCode::UNAUTHORIZED,
     };
 
-    match state.sqlx_client().bind_device(user_id, req.id).await {
+    match state.bind_device(user_id, req.id).await {
         Ok(_) => StatusCode::OK,
         Err(e) => {
             tracing::error!("failed to bind device: {e:#}");
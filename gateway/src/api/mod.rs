@@ -1,9 +1,11 @@
+use std::time::Duration;
+
 use anyhow::Context;
 use tokio_util::sync::CancellationToken;
 
 use crate::api::state::ApiState;
 use crate::config::AppConfig;
-use crate::redis::RedisClient;
+use crate::redis::{RedisClient, RedisConfig};
 use crate::sqlx::SqlxClient;
 
 pub mod config;
@@ -37,6 +39,18 @@ pub async fn http_service(config: AppConfig, token: CancellationToken) -> anyhow
 
     let sqlx_client = SqlxClient::new(pool);
 
+    tracing::info!("syncing device ownership cache");
+    sync_device_owners(&sqlx_client, &redis_client, config.redis.device_owner_ttl)
+        .await
+        .context("failed to sync device ownership cache")?;
+
+    let owner_sync_handle = tokio::task::spawn(run_device_owner_sync(
+        sqlx_client.clone(),
+        redis_client.clone(),
+        config.redis.clone(),
+        token.clone(),
+    ));
+
     tracing::info!(listen_addr = %config.api.listen_addr, "API server starting...");
 
     let state = ApiState::builder()
@@ -68,9 +82,46 @@ pub async fn http_service(config: AppConfig, token: CancellationToken) -> anyhow
         }
     });
 
-    let _ = tokio::join!(api_handle, kafka_handle);
+    let _ = tokio::join!(api_handle, kafka_handle, owner_sync_handle);
 
     tracing::info!("gateway stopped");
 
     Ok(())
 }
+
+/// Mirror `user_devices` into Redis so tunnel-server can check ownership without a DB.
+async fn sync_device_owners(
+    sqlx_client: &SqlxClient,
+    redis_client: &RedisClient,
+    ttl_secs: u64,
+) -> anyhow::Result<()> {
+    let owners = sqlx_client.get_device_owners().await?;
+    redis_client.store_device_owners(&owners, ttl_secs).await
+}
+
+/// Refresh the ownership cache until `token` is cancelled. Entries are only ever
+/// extended, so those of unbound or deleted devices run out.
+async fn run_device_owner_sync(
+    sqlx_client: SqlxClient,
+    redis_client: RedisClient,
+    config: RedisConfig,
+    token: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config.device_owner_sync_interval.max(1),
+    ));
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        if let Err(err) =
+            sync_device_owners(&sqlx_client, &redis_client, config.device_owner_ttl).await
+        {
+            tracing::warn!("device ownership cache sync failed: {err:#}");
+        }
+    }
+}
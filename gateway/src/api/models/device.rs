@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -17,3 +19,42 @@ pub struct DeviceInfo {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
+
+/// Device listing entry with the device's tunnel availability.
+#[derive(Debug, Serialize)]
+pub struct DeviceListItem {
+    #[serde(flatten)]
+    pub device: DeviceInfo,
+    /// `None` when tunnel-server has never seen the device or presence is unavailable.
+    pub tunnel: Option<TunnelPresence>,
+}
+
+/// Tunnel availability, read from the presence hash tunnel-server keeps in Redis.
+#[derive(Debug, Serialize)]
+pub struct TunnelPresence {
+    pub online: bool,
+    /// Unix timestamp of the last connect, in seconds.
+    pub connected_at: Option<u64>,
+    /// Unix timestamp of the last disconnect, in seconds. Not set while online.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disconnected_at: Option<u64>,
+    /// Why the device last disconnected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl TunnelPresence {
+    /// Parse a `presence:{device_id}` hash; `None` if there is none.
+    pub fn from_fields(fields: &HashMap<String, String>) -> Option<Self> {
+        let status = fields.get("status")?;
+        let timestamp = |name: &str| fields.get(name).and_then(|value| value.parse().ok());
+        let online = status == "online";
+
+        Some(Self {
+            online,
+            connected_at: timestamp("connected_at"),
+            disconnected_at: timestamp("disconnected_at").filter(|_| !online),
+            reason: fields.get("reason").filter(|_| !online).cloned(),
+        })
+    }
+}
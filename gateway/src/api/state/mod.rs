@@ -11,6 +11,7 @@ use uuid::Uuid;
 use crate::api::config::{ApiConfig, ApiSecrets};
 use crate::api::endpoint::ApiEndpoint;
 use crate::api::models::auth::Claims;
+use crate::api::models::device::TunnelPresence;
 use crate::config::AppConfig;
 use crate::redis::{RedisClient, RedisConfig};
 use crate::sqlx::SqlxClient;
@@ -141,6 +142,19 @@ impl ApiState {
         self.inner.redis_client.consume_oauth_state(state).await
     }
 
+    /// Tunnel availability of each device, in the order given.
+    pub async fn get_tunnel_presence(
+        &self,
+        device_ids: &[Uuid],
+    ) -> anyhow::Result<Vec<Option<TunnelPresence>>> {
+        let presence = self
+            .inner
+            .redis_client
+            .get_device_presence(device_ids)
+            .await?;
+        Ok(presence.iter().map(TunnelPresence::from_fields).collect())
+    }
+
     pub fn decode_jwt(&self, token: &str) -> Result<Claims> {
         let mut validation = Validation::new(Algorithm::RS256);
         validation.validate_exp = true;
@@ -188,6 +202,18 @@ impl ApiState {
             .await
     }
 
+    /// Bind a device to a user and refresh the ownership cache used by tunnel-server.
+    pub async fn bind_device(&self, user_id: Uuid, device_id: Uuid) -> anyhow::Result<()> {
+        self.inner
+            .sqlx_client
+            .bind_device(user_id, device_id)
+            .await?;
+        self.inner
+            .redis_client
+            .store_device_owner(device_id, user_id, self.inner.config.redis.device_owner_ttl)
+            .await
+    }
+
     /// Returns true if the JWT has been explicitly revoked.
     pub async fn is_jwt_revoked(&self, jti: &str) -> anyhow::Result<bool> {
         self.inner.redis_client.is_jwt_revoked(jti).await
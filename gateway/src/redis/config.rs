@@ -6,6 +6,12 @@ pub struct RedisConfig {
     pub url: String,
     /// TTL in seconds for OAuth CSRF state tokens.
     pub oauth_state_ttl: u64,
+    /// TTL in seconds for `device:owner:{device_id}` entries. Entries of devices
+    /// that were unbound or deleted expire after it.
+    pub device_owner_ttl: u64,
+    /// Seconds between refreshes of the device ownership cache from PostgreSQL.
+    /// Must be well below `device_owner_ttl`.
+    pub device_owner_sync_interval: u64,
 }
 
 impl Default for RedisConfig {
@@ -13,6 +19,8 @@ impl Default for RedisConfig {
         Self {
             url: "redis://localhost:6379".to_owned(),
             oauth_state_ttl: 300,
+            device_owner_ttl: 600,
+            device_owner_sync_interval: 60,
         }
     }
 }
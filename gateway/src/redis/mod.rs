@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+
 use anyhow::Context;
 use redis::AsyncCommands;
+use uuid::Uuid;
 
 pub use self::config::RedisConfig;
 
@@ -66,4 +69,67 @@ impl RedisClient {
             .context("failed to check JWT revocation in Redis")?;
         Ok(exists)
     }
+
+    /// Load the `presence:{device_id}` hashes tunnel-server keeps, in the order given.
+    /// Devices without one get an empty map.
+    pub async fn get_device_presence(
+        &self,
+        device_ids: &[Uuid],
+    ) -> anyhow::Result<Vec<HashMap<String, String>>> {
+        if device_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for device_id in device_ids {
+            pipe.hgetall(format!("presence:{device_id}"));
+        }
+
+        let mut conn = self.client.clone();
+        let presence: Vec<HashMap<String, String>> = pipe
+            .query_async(&mut conn)
+            .await
+            .context("failed to get device presence from Redis")?;
+        Ok(presence)
+    }
+
+    /// Cache `device:owner:{device_id} → user_id` with a TTL for tunnel-server
+    /// ownership checks.
+    pub async fn store_device_owner(
+        &self,
+        device_id: Uuid,
+        user_id: Uuid,
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        self.store_device_owners(&[(device_id, user_id)], ttl_secs)
+            .await
+    }
+
+    /// Cache several `device:owner:{device_id} → user_id` entries in one round trip.
+    pub async fn store_device_owners(
+        &self,
+        owners: &[(Uuid, Uuid)],
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        if owners.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for (device_id, user_id) in owners {
+            pipe.set_ex(
+                format!("device:owner:{device_id}"),
+                user_id.to_string(),
+                ttl_secs,
+            )
+            .ignore();
+        }
+
+        let mut conn = self.client.clone();
+        let _: () = pipe
+            .query_async(&mut conn)
+            .await
+            .context("failed to store device owners in Redis")?;
+        Ok(())
+    }
 }
@@ -49,6 +49,23 @@ impl SqlxClient {
         Ok(rows)
     }
 
+    /// Get every `(device_id, user_id)` ownership binding.
+    pub async fn get_device_owners(&self) -> anyhow::Result<Vec<(Uuid, Uuid)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT device_id, user_id
+            FROM user_devices
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.device_id, row.user_id))
+            .collect())
+    }
+
     /// Bind a device to a user. Fails if device does not exist.
     pub async fn bind_device(&self, user_id: Uuid, device_id: Uuid) -> anyhow::Result<()> {
         sqlx::query!(
@@ -84,5 +84,5 @@ fn version_string() -> &'static str {
     })
 }
 
-static TUNNEL_CLIENT_VERSION: &str = env!("TUNNEL_CLIENT_VERSION");
+pub static TUNNEL_CLIENT_VERSION: &str = env!("TUNNEL_CLIENT_VERSION");
 static TUNNEL_CLIENT_RUSTC_VERSION: &str = env!("TUNNEL_CLIENT_RUSTC_VERSION");
@@ -1,5 +1,6 @@
 use nexus_utils::logger::LoggerConfig;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -19,14 +20,48 @@ pub struct TunnelConfig {
     /// Unique device identifier — sent in the `device_id` query param.
     pub device_id: String,
 
+    /// Device token issued by `tunnel-server device-token` — sent as a Bearer token.
+    pub device_token: String,
+
+    /// PEM CA certificate to trust for a `wss://` server, in addition to the system roots.
+    pub ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate presented to a `wss://` server that verifies devices.
+    /// Needs `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// PKCS#8 PEM private key of `client_cert`.
+    pub client_key: Option<PathBuf>,
+
     /// Base URL of the local HTTP service to proxy requests to.
     /// Example: `http://localhost:80`
     pub local_url: String,
 
+    /// Key printed by `tunnel-server identity-key`. When set, proxied requests must
+    /// carry valid identity headers signed with it, or they are refused with `403`.
+    pub identity_key: Option<String>,
+
+    /// Maximum age of an identity signature, allowing for clock skew.
+    #[serde(with = "humantime_serde")]
+    pub identity_max_age: Duration,
+
+    /// Local TCP targets (`host:port`) the server may open raw TCP streams to.
+    /// Empty disables TCP forwarding.
+    /// Example: `["127.0.0.1:22", "localhost:502"]`
+    pub tcp_allowlist: Vec<String>,
+
+    /// Maximum time to wait for a local TCP connection to be established.
+    #[serde(with = "humantime_serde")]
+    pub tcp_connect_timeout: Duration,
+
     /// Seconds between reconnect attempts
     #[serde(with = "humantime_serde")]
     pub reconnect_timeout: Duration,
 
+    /// Maximum time to wait for the server `Hello` frame after connect.
+    #[serde(with = "humantime_serde")]
+    pub handshake_timeout: Duration,
+
     /// Maximum number of concurrent proxied streams on one device connection.
     pub max_concurrent_streams: usize,
 
@@ -39,8 +74,17 @@ impl Default for TunnelConfig {
         Self {
             server_url: "ws://localhost:8001".to_owned(),
             device_id: "device-1".to_owned(),
+            device_token: String::new(),
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
             local_url: "http://localhost:80".to_owned(),
+            identity_key: None,
+            identity_max_age: Duration::from_secs(300),
+            tcp_allowlist: vec![],
+            tcp_connect_timeout: Duration::from_secs(10),
             reconnect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
             max_concurrent_streams: 64,
             frame_channel_capacity: 64,
         }
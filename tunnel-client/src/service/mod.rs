@@ -3,24 +3,52 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context as _, Result, anyhow};
 use bytes::Bytes;
 use dashmap::DashMap;
 use futures_util::{SinkExt, Stream, StreamExt};
-use nexus_utils::tunnel::{Frame, Headers, decode_frame, encode_frame};
+use nexus_utils::identity::verify_identity;
+use nexus_utils::trace::{REQUEST_ID_HEADER, TRACEPARENT_HEADER, TraceContext};
+use nexus_utils::tunnel::{
+    Capabilities, Frame, Handshake, Headers, INITIAL_WINDOW_SIZE, SendWindow, decode_frame,
+    encode_frame, is_upgrade_request,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
-use tokio_tungstenite::connect_async;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::{Connector, connect_async_tls_with_config};
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use uuid::Uuid;
 
+use crate::cli::TUNNEL_CLIENT_VERSION;
 use crate::config::{AppConfig, TunnelConfig};
 
 const WS_PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
+/// Read buffer size for bytes coming from an upgraded or TCP local connection.
+const UPGRADE_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Maximum data carried by a single response body frame.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Request body buffer for servers without flow control.
+const REQUEST_CHANNEL_CAPACITY: usize = 16;
+
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
 pub async fn tunnel_service(config: AppConfig, token: CancellationToken) -> Result<()> {
     let cfg = config.tunnel;
     let http = reqwest::Client::builder().build()?;
+    let connector = tls_connector(&cfg)?;
 
     loop {
         if token.is_cancelled() {
@@ -33,14 +61,36 @@ pub async fn tunnel_service(config: AppConfig, token: CancellationToken) -> Resu
             cfg.device_id
         );
 
+        let mut request = url.into_client_request()?;
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", cfg.device_token))?,
+        );
+
         tracing::info!(server_url = %cfg.server_url, "tunnel-server connecting");
 
-        match connect_async(&url).await {
+        match connect_async_tls_with_config(request, None, false, connector.clone()).await {
             Ok((ws, _)) => {
                 tracing::info!("tunnel-server connected");
 
-                if let Err(err) = run_session(ws, cfg.clone(), http.clone(), token.clone()).await {
-                    tracing::error!("tunnel-server connection ended with error: {err:#}");
+                let going_away = CancellationToken::new();
+                let mut session = tokio::spawn(run_session(
+                    ws,
+                    cfg.clone(),
+                    http.clone(),
+                    token.clone(),
+                    going_away.clone(),
+                ));
+
+                tokio::select! {
+                    result = &mut session => log_session_result(result),
+                    _ = going_away.cancelled() => {
+                        // The old connection keeps serving its in-flight streams until the
+                        // server closes it; new streams arrive on the next connection.
+                        tracing::info!("tunnel-server is draining, reconnecting");
+                        tokio::spawn(async move { log_session_result(session.await) });
+                        continue;
+                    }
                 }
             }
             Err(err) => {
@@ -63,24 +113,94 @@ pub async fn tunnel_service(config: AppConfig, token: CancellationToken) -> Resu
     Ok(())
 }
 
+/// TLS settings for `wss://` servers; `None` uses the defaults.
+fn tls_connector(cfg: &TunnelConfig) -> Result<Option<Connector>> {
+    if cfg.ca_cert.is_none() && cfg.client_cert.is_none() {
+        return Ok(None);
+    }
+
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca_cert) = &cfg.ca_cert {
+        let ca = std::fs::read(ca_cert)
+            .with_context(|| format!("failed to read CA cert: {}", ca_cert.display()))?;
+        builder.add_root_certificate(
+            native_tls::Certificate::from_pem(&ca).context("failed to parse CA cert")?,
+        );
+    }
+    if let Some(client_cert) = &cfg.client_cert {
+        let client_key = cfg
+            .client_key
+            .as_ref()
+            .context("client_cert is set without client_key")?;
+        let cert = std::fs::read(client_cert)
+            .with_context(|| format!("failed to read client cert: {}", client_cert.display()))?;
+        let key = std::fs::read(client_key)
+            .with_context(|| format!("failed to read client key: {}", client_key.display()))?;
+        builder.identity(
+            native_tls::Identity::from_pkcs8(&cert, &key)
+                .context("failed to parse client identity")?,
+        );
+    }
+
+    let connector = builder.build().context("failed to build TLS connector")?;
+    Ok(Some(Connector::NativeTls(connector)))
+}
+
+fn log_session_result(result: Result<Result<()>, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!("tunnel-server connection ended with error: {err:#}"),
+        Err(err) if err.is_cancelled() => {}
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
 async fn run_session(
-    ws: tokio_tungstenite::WebSocketStream<
-        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-    >,
+    ws: WsStream,
     cfg: TunnelConfig,
     http: reqwest::Client,
     token: CancellationToken,
+    going_away: CancellationToken,
 ) -> Result<()> {
     let (mut sink, mut stream) = ws.split();
 
+    let payload = encode_frame(&Frame::hello(TUNNEL_CLIENT_VERSION))?;
+    sink.send(Message::Binary(payload.into())).await?;
+
+    let handshake = match tokio::time::timeout(cfg.handshake_timeout, server_handshake(&mut stream))
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("handshake timed out")))
+    {
+        Ok(handshake) => handshake,
+        Err(err) => {
+            let close = CloseFrame {
+                code: CloseCode::Protocol,
+                reason: err.to_string().into(),
+            };
+            let _ = sink.send(Message::Close(Some(close))).await;
+            return Err(err.context("tunnel handshake failed"));
+        }
+    };
+
+    tracing::info!(
+        protocol_version = handshake.protocol_version,
+        server_version = %handshake.peer_version,
+        capabilities = handshake.capabilities.bits(),
+        "tunnel handshake complete"
+    );
+
     let (frame_tx, mut frame_rx) = mpsc::channel::<Frame>(cfg.frame_channel_capacity);
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel::<Frame>();
 
     let max_concurrent_streams = cfg.max_concurrent_streams;
 
     let session = Arc::new(ClientSession {
         cfg,
+        handshake,
         http,
         frame_tx,
+        control_tx,
+        going_away,
         streams: DashMap::new(),
         permits: Arc::new(Semaphore::new(max_concurrent_streams)),
     });
@@ -92,25 +212,24 @@ async fn run_session(
         async move {
             let mut ping = tokio::time::interval(WS_PING_INTERVAL);
             loop {
+                // Control frames go first so window updates never queue behind data.
+                let message = tokio::select! {
+                    biased;
+                    _ = shutdown.cancelled() => break,
+                    _ = ping.tick() => Message::Ping(Bytes::new()),
+                    frame = control_rx.recv() => match frame {
+                        Some(frame) => Message::Binary(encode_frame(&frame)?.into()),
+                        None => break,
+                    },
+                    frame = frame_rx.recv() => match frame {
+                        Some(frame) => Message::Binary(encode_frame(&frame)?.into()),
+                        None => break,
+                    },
+                };
+
                 tokio::select! {
                     _ = shutdown.cancelled() => break,
-                    _ = ping.tick() => {
-                        tokio::select! {
-                            _ = shutdown.cancelled() => break,
-                            result = sink.send(Message::Ping(Bytes::new())) => { result?; }
-                        }
-                    }
-                    frame = frame_rx.recv() => {
-                        let Some(frame) = frame else {
-                            break;
-                        };
-
-                        let payload = encode_frame(&frame)?;
-                        tokio::select! {
-                            _ = shutdown.cancelled() => break,
-                            result = sink.send(Message::Binary(payload.into())) => { result?; }
-                        }
-                    }
+                    result = sink.send(message) => { result?; }
                 }
             }
 
@@ -137,27 +256,55 @@ async fn run_session(
     Ok(())
 }
 
+async fn server_handshake(
+    stream: &mut futures_util::stream::SplitStream<WsStream>,
+) -> Result<Handshake> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Binary(payload))) => {
+                return Handshake::negotiate(decode_frame(&payload)?);
+            }
+            Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+            Some(Ok(Message::Close(Some(close)))) => {
+                return Err(anyhow!("server rejected connection: {}", close.reason));
+            }
+            Some(Ok(Message::Close(None))) | None => return Err(anyhow!("closed before hello")),
+            Some(Ok(Message::Text(_))) => return Err(anyhow!("unexpected text frame")),
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+}
+
 struct ClientSession {
     cfg: TunnelConfig,
+    handshake: Handshake,
     http: reqwest::Client,
     frame_tx: mpsc::Sender<Frame>,
+    /// Unbounded queue for small control frames (window updates) that must not
+    /// wait behind data frames.
+    control_tx: mpsc::UnboundedSender<Frame>,
+    /// Cancelled when the server sends `GoAway`.
+    going_away: CancellationToken,
     streams: DashMap<Uuid, StreamState>,
     permits: Arc<Semaphore>,
 }
 
 struct StreamState {
     request_tx: Option<mpsc::Sender<Result<Bytes, io::Error>>>,
+    send_window: SendWindow,
     cancel: CancellationToken,
 }
 
+impl Drop for StreamState {
+    fn drop(&mut self) {
+        self.send_window.close();
+    }
+}
+
 impl ClientSession {
     async fn reader_loop(
         self: &Arc<Self>,
-        stream: &mut futures_util::stream::SplitStream<
-            tokio_tungstenite::WebSocketStream<
-                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-            >,
-        >,
+        stream: &mut futures_util::stream::SplitStream<WsStream>,
         token: &CancellationToken,
     ) -> Result<()> {
         loop {
@@ -171,7 +318,11 @@ impl ClientSession {
                     Some(Ok(Message::Ping(_))) => {}
                     Some(Ok(Message::Pong(_))) => {}
                     Some(Ok(Message::Frame(_))) => {}
-                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(Message::Close(Some(close)))) => {
+                        tracing::info!(code = %close.code, reason = %close.reason, "tunnel-server closed connection");
+                        return Ok(());
+                    }
+                    Some(Ok(Message::Close(None))) | None => return Ok(()),
                     Some(Ok(Message::Text(_))) => return Err(anyhow!("unexpected text frame")),
                     Some(Err(err)) => return Err(err.into()),
                 }
@@ -180,18 +331,50 @@ impl ClientSession {
     }
 
     async fn handle_frame(self: &Arc<Self>, frame: Frame) -> Result<()> {
+        if !self.handshake.allows(&frame) {
+            return Err(anyhow!(
+                "server sent a frame outside negotiated capabilities"
+            ));
+        }
+
         match frame {
             Frame::OpenStream {
                 stream_id,
                 method,
                 path_and_query,
                 headers,
+                request_id,
+                traceparent,
                 ..
             } => {
-                self.open_stream(stream_id, method, path_and_query, headers)
-                    .await
+                // The local request is a child of the server span.
+                let trace = traceparent
+                    .as_deref()
+                    .and_then(TraceContext::parse)
+                    .map(|parent| parent.child());
+                let span = tracing::info_span!(
+                    "stream",
+                    %stream_id,
+                    request_id,
+                    trace_id = trace.map(|trace| trace.trace_id()),
+                );
+                self.open_stream(
+                    stream_id,
+                    method,
+                    path_and_query,
+                    headers,
+                    request_id,
+                    trace,
+                )
+                .instrument(span)
+                .await
             }
-            Frame::RequestBodyChunk { stream_id, data } => {
+            Frame::OpenTcpStream {
+                stream_id,
+                host,
+                port,
+            } => self.open_tcp_stream(stream_id, host, port).await,
+            Frame::RequestBodyChunk { stream_id, data } | Frame::StreamData { stream_id, data } => {
                 let sender: mpsc::Sender<Result<Bytes, io::Error>> = match self
                     .streams
                     .get(&stream_id)
@@ -204,20 +387,40 @@ impl ClientSession {
                     }
                 };
 
-                if sender.send(Ok(data)).await.is_err() {
+                if let Err(err) = self.push_request(&sender, data).await {
+                    tracing::warn!(%stream_id, "dropping stream: {err:#}");
                     self.cancel_stream(stream_id).await?;
                 }
 
                 Ok(())
             }
-            Frame::RequestBodyEnd { stream_id } => {
+            Frame::RequestBodyEnd { stream_id } | Frame::StreamEnd { stream_id } => {
                 if let Some(mut entry) = self.streams.get_mut(&stream_id) {
                     entry.request_tx.take();
                 }
                 Ok(())
             }
             Frame::CancelStream { stream_id } => self.cancel_stream(stream_id).await,
-            Frame::ResponseHead { .. }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                if let Some(entry) = self.streams.get(&stream_id) {
+                    entry.send_window.grant(increment);
+                }
+                Ok(())
+            }
+            Frame::GoAway { message } => {
+                tracing::info!(%message, "tunnel-server sent go away");
+                self.going_away.cancel();
+                Ok(())
+            }
+            Frame::Unknown { tag } => {
+                tracing::debug!(tag, "ignoring unknown frame");
+                Ok(())
+            }
+            Frame::Hello { .. }
+            | Frame::ResponseHead { .. }
             | Frame::ResponseBodyChunk { .. }
             | Frame::ResponseBodyEnd { .. }
             | Frame::ErrorStream { .. } => Err(anyhow!("unexpected frame from server")),
@@ -230,7 +433,24 @@ impl ClientSession {
         method: reqwest::Method,
         path_and_query: http::uri::PathAndQuery,
         headers: Headers,
+        request_id: Option<String>,
+        trace: Option<TraceContext>,
     ) -> Result<()> {
+        if let Some(identity_key) = &self.cfg.identity_key
+            && let Err(err) = verify_identity(
+                &headers,
+                identity_key,
+                &method,
+                path_and_query.as_str(),
+                self.cfg.identity_max_age,
+            )
+        {
+            tracing::warn!(%stream_id, "refusing request: {err:#}");
+            self.send_error(stream_id, 403, "invalid identity headers")
+                .await?;
+            return Ok(());
+        }
+
         let permit = match self.permits.clone().try_acquire_owned() {
             Ok(permit) => permit,
             Err(_) => {
@@ -240,21 +460,15 @@ impl ClientSession {
             }
         };
 
-        let (request_tx, request_rx) = mpsc::channel::<Result<Bytes, io::Error>>(16);
-        let cancel = CancellationToken::new();
-        self.streams.insert(
-            stream_id,
-            StreamState {
-                request_tx: Some(request_tx),
-                cancel: cancel.clone(),
-            },
-        );
+        let (request_rx, cancel) = self.insert_stream(stream_id);
 
         let local_url = format!(
             "{}{}",
             self.cfg.local_url.trim_end_matches('/'),
             path_and_query.as_str()
         );
+        let upgrade = is_upgrade_request(&headers);
+
         let mut builder = self.http.request(method, &local_url);
         for (name, value) in &headers {
             if is_hop_by_hop(name.as_str()) {
@@ -262,24 +476,174 @@ impl ClientSession {
             }
             builder = builder.header(name, value);
         }
+        if let Some(request_id) = request_id {
+            builder = builder.header(REQUEST_ID_HEADER, request_id);
+        }
+        if let Some(trace) = trace {
+            builder = builder.header(TRACEPARENT_HEADER, trace.to_string());
+        }
 
-        let body = reqwest::Body::wrap_stream(RequestBodyStream { rx: request_rx });
-        let builder = builder.body(body);
+        // Upgrade requests carry no body; `StreamData` frames are written to the
+        // local connection once it has switched protocols.
+        let upgrade_rx = if upgrade {
+            for name in [http::header::CONNECTION, http::header::UPGRADE] {
+                if let Some(value) = headers.get(&name) {
+                    builder = builder.header(name, value);
+                }
+            }
+            Some(request_rx)
+        } else {
+            let body = reqwest::Body::wrap_stream(RequestBodyStream {
+                rx: request_rx,
+                session: self.clone(),
+                stream_id,
+            });
+            builder = builder.body(body);
+            None
+        };
+
+        let session = self.clone();
+        tokio::spawn(
+            async move {
+                session
+                    .run_local_request(stream_id, builder, upgrade_rx, cancel, permit)
+                    .await;
+            }
+            .in_current_span(),
+        );
+
+        Ok(())
+    }
+
+    async fn open_tcp_stream(
+        self: &Arc<Self>,
+        stream_id: Uuid,
+        host: String,
+        port: u16,
+    ) -> Result<()> {
+        // Both the allowlist and the connect below expect IPv6 literals bare.
+        let host = unbracket_host(&host).to_string();
+        if !tcp_target_allowed(&self.cfg.tcp_allowlist, &host, port) {
+            tracing::warn!(%stream_id, %host, port, "tcp target not in allowlist");
+            self.send_error(stream_id, 403, "tcp target not allowed")
+                .await?;
+            return Ok(());
+        }
+
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.send_error(stream_id, 503, "too many active streams")
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let (request_rx, cancel) = self.insert_stream(stream_id);
 
         let session = self.clone();
         tokio::spawn(async move {
             session
-                .run_local_request(stream_id, builder, cancel, permit)
+                .run_tcp_stream(stream_id, host, port, request_rx, cancel, permit)
                 .await;
         });
 
         Ok(())
     }
 
+    /// Track a new stream and return the receiver for data the server sends on it.
+    fn insert_stream(
+        &self,
+        stream_id: Uuid,
+    ) -> (mpsc::Receiver<Result<Bytes, io::Error>>, CancellationToken) {
+        // With flow control the server never has more than one window of data in
+        // flight, so the request buffer is bounded by the window rather than by awaiting.
+        let (request_capacity, send_window) = if self.flow_control() {
+            (INITIAL_WINDOW_SIZE as usize, SendWindow::new())
+        } else {
+            (REQUEST_CHANNEL_CAPACITY, SendWindow::unlimited())
+        };
+
+        let (request_tx, request_rx) = mpsc::channel::<Result<Bytes, io::Error>>(request_capacity);
+        let cancel = CancellationToken::new();
+        self.streams.insert(
+            stream_id,
+            StreamState {
+                request_tx: Some(request_tx),
+                send_window,
+                cancel: cancel.clone(),
+            },
+        );
+
+        (request_rx, cancel)
+    }
+
+    async fn run_tcp_stream(
+        self: Arc<Self>,
+        stream_id: Uuid,
+        host: String,
+        port: u16,
+        request_rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+        cancel: CancellationToken,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let _permit = permit;
+
+        let connect = tokio::time::timeout(
+            self.cfg.tcp_connect_timeout,
+            TcpStream::connect((host.as_str(), port)),
+        );
+        let tcp = tokio::select! {
+            _ = cancel.cancelled() => {
+                self.streams.remove(&stream_id);
+                return;
+            }
+            result = connect => match result {
+                Ok(Ok(tcp)) => tcp,
+                Ok(Err(err)) => {
+                    self.streams.remove(&stream_id);
+                    let _ = self.send_error(stream_id, 502, &format!("tcp connect failed: {err}")).await;
+                    return;
+                }
+                Err(_) => {
+                    self.streams.remove(&stream_id);
+                    let _ = self.send_error(stream_id, 504, "tcp connect timed out").await;
+                    return;
+                }
+            },
+        };
+
+        if self
+            .send_frame(Frame::ResponseHead {
+                stream_id,
+                status: 200,
+                headers: Headers::new(),
+            })
+            .await
+            .is_err()
+        {
+            self.streams.remove(&stream_id);
+            return;
+        }
+
+        tracing::debug!(%stream_id, %host, port, "tcp stream connected");
+
+        tokio::select! {
+            _ = cancel.cancelled() => {}
+            result = self.pump_stream(stream_id, tcp, request_rx) => {
+                if let Err(err) = result {
+                    let _ = self.send_error(stream_id, 502, &format!("local tcp stream failed: {err}")).await;
+                }
+            }
+        }
+        self.streams.remove(&stream_id);
+    }
+
     async fn run_local_request(
         self: Arc<Self>,
         stream_id: Uuid,
         builder: reqwest::RequestBuilder,
+        upgrade_rx: Option<mpsc::Receiver<Result<Bytes, io::Error>>>,
         cancel: CancellationToken,
         permit: OwnedSemaphorePermit,
     ) {
@@ -304,7 +668,8 @@ impl ClientSession {
             }
         };
 
-        let headers = response_headers(response.headers());
+        let switching_protocols = response.status() == reqwest::StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response_headers(response.headers(), switching_protocols);
         if self
             .send_frame(Frame::ResponseHead {
                 stream_id,
@@ -318,6 +683,21 @@ impl ClientSession {
             return;
         }
 
+        if let Some(upgrade_rx) = upgrade_rx
+            && switching_protocols
+        {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                result = self.pump_upgraded(stream_id, response, upgrade_rx) => {
+                    if let Err(err) = result {
+                        let _ = self.send_error(stream_id, 502, &format!("local upgraded stream failed: {err}")).await;
+                    }
+                }
+            }
+            self.streams.remove(&stream_id);
+            return;
+        }
+
         let mut body = response.bytes_stream();
         loop {
             tokio::select! {
@@ -328,10 +708,7 @@ impl ClientSession {
                 chunk = body.next() => {
                     match chunk {
                         Some(Ok(chunk)) => {
-                            if self.send_frame(Frame::ResponseBodyChunk {
-                                stream_id,
-                                data: chunk,
-                            }).await.is_err() {
+                            if self.send_body_chunk(stream_id, chunk).await.is_err() {
                                 self.streams.remove(&stream_id);
                                 return;
                             }
@@ -352,6 +729,120 @@ impl ClientSession {
         }
     }
 
+    async fn pump_upgraded(
+        &self,
+        stream_id: Uuid,
+        response: reqwest::Response,
+        upgrade_rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+    ) -> Result<()> {
+        let upgraded = response.upgrade().await?;
+        self.pump_stream(stream_id, upgraded, upgrade_rx).await
+    }
+
+    /// Copy bytes between a local connection and the stream's `StreamData` frames.
+    async fn pump_stream<T>(
+        &self,
+        stream_id: Uuid,
+        io: T,
+        mut data_rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+    ) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let (mut reader, mut writer) = tokio::io::split(io);
+
+        let upstream = async {
+            let mut buf = vec![0u8; UPGRADE_READ_BUFFER_SIZE];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    return self.send_frame(Frame::StreamEnd { stream_id }).await;
+                }
+                self.reserve_send(stream_id, n).await?;
+                self.send_frame(Frame::StreamData {
+                    stream_id,
+                    data: Bytes::copy_from_slice(&buf[..n]),
+                })
+                .await?;
+            }
+        };
+
+        let downstream = async {
+            while let Some(chunk) = data_rx.recv().await {
+                let chunk = chunk?;
+                writer.write_all(&chunk).await?;
+                self.release_received(stream_id, chunk.len());
+            }
+            writer.shutdown().await?;
+            Ok::<_, anyhow::Error>(())
+        };
+
+        tokio::try_join!(upstream, downstream)?;
+
+        Ok(())
+    }
+
+    fn flow_control(&self) -> bool {
+        self.handshake
+            .capabilities
+            .contains(Capabilities::FLOW_CONTROL)
+    }
+
+    /// Send a local response body chunk, split to fit the stream window.
+    async fn send_body_chunk(&self, stream_id: Uuid, mut chunk: Bytes) -> Result<()> {
+        while !chunk.is_empty() {
+            let data = chunk.split_to(chunk.len().min(MAX_CHUNK_SIZE));
+            self.reserve_send(stream_id, data.len()).await?;
+            self.send_frame(Frame::ResponseBodyChunk { stream_id, data })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the server to grant `len` bytes of send credit on the stream.
+    async fn reserve_send(&self, stream_id: Uuid, len: usize) -> Result<()> {
+        let send_window = self
+            .streams
+            .get(&stream_id)
+            .map(|entry| entry.send_window.clone())
+            .ok_or_else(|| anyhow!("stream closed: {stream_id}"))?;
+
+        send_window.reserve(len).await
+    }
+
+    /// Grant the server credit for `len` bytes the local service has consumed.
+    fn release_received(&self, stream_id: Uuid, len: usize) {
+        if !self.flow_control() || len == 0 {
+            return;
+        }
+
+        let _ = self.control_tx.send(Frame::WindowUpdate {
+            stream_id,
+            increment: len as u32,
+        });
+    }
+
+    /// Queue request data for the local service without stalling the reader loop:
+    /// with flow control a full buffer means the server overran its window.
+    async fn push_request(
+        &self,
+        request_tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+        data: Bytes,
+    ) -> Result<()> {
+        if !self.flow_control() {
+            return request_tx
+                .send(Ok(data))
+                .await
+                .map_err(|_| anyhow!("local request dropped"));
+        }
+
+        match request_tx.try_send(Ok(data)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(anyhow!("server exceeded stream window")),
+            Err(TrySendError::Closed(_)) => Err(anyhow!("local request dropped")),
+        }
+    }
+
     async fn cancel_stream(self: &Arc<Self>, stream_id: Uuid) -> Result<()> {
         if let Some((_, entry)) = self.streams.remove(&stream_id) {
             entry.cancel.cancel();
@@ -360,6 +851,12 @@ impl ClientSession {
     }
 
     async fn send_frame(&self, frame: Frame) -> Result<()> {
+        if !self.handshake.allows(&frame) {
+            return Err(anyhow!(
+                "server did not negotiate the capabilities for this frame"
+            ));
+        }
+
         self.frame_tx
             .send(frame)
             .await
@@ -387,17 +884,23 @@ impl ClientSession {
 
 struct RequestBodyStream {
     rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+    session: Arc<ClientSession>,
+    stream_id: Uuid,
 }
 
 impl Stream for RequestBodyStream {
     type Item = Result<Bytes, io::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.rx).poll_recv(cx)
+        let poll = Pin::new(&mut self.rx).poll_recv(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.session.release_received(self.stream_id, chunk.len());
+        }
+        poll
     }
 }
 
-fn response_headers(headers: &reqwest::header::HeaderMap) -> Headers {
+fn response_headers(headers: &reqwest::header::HeaderMap, switching_protocols: bool) -> Headers {
     let mut filtered = Headers::new();
     for (name, value) in headers {
         if is_hop_by_hop(name.as_str()) {
@@ -405,9 +908,38 @@ fn response_headers(headers: &reqwest::header::HeaderMap) -> Headers {
         }
         filtered.append(name.clone(), value.clone());
     }
+
+    // A `101` must carry its upgrade headers back to the browser.
+    if switching_protocols {
+        for name in [http::header::CONNECTION, http::header::UPGRADE] {
+            if let Some(value) = headers.get(&name) {
+                filtered.insert(name, value.clone());
+            }
+        }
+    }
+
     filtered
 }
 
+/// Whether `host:port` matches an entry of the configured TCP allowlist.
+fn tcp_target_allowed(allowlist: &[String], host: &str, port: u16) -> bool {
+    allowlist.iter().any(|entry| {
+        entry
+            .rsplit_once(':')
+            .is_some_and(|(allowed_host, allowed_port)| {
+                unbracket_host(allowed_host).eq_ignore_ascii_case(host)
+                    && allowed_port.parse() == Ok(port)
+            })
+    })
+}
+
+/// Strip the brackets around an IPv6 literal such as `[::1]`.
+fn unbracket_host(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
 fn is_hop_by_hop(name: &str) -> bool {
     matches!(
         name.to_ascii_lowercase().as_str(),
@@ -422,3 +954,72 @@ fn is_hop_by_hop(name: &str) -> bool {
             | "host"
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn tcp_targets_must_match_host_and_port() {
+        let allowlist = allowlist(&["127.0.0.1:22", "Localhost:502"]);
+
+        assert!(tcp_target_allowed(&allowlist, "127.0.0.1", 22));
+        assert!(tcp_target_allowed(&allowlist, "localhost", 502));
+        assert!(tcp_target_allowed(&allowlist, "LOCALHOST", 502));
+
+        assert!(!tcp_target_allowed(&allowlist, "127.0.0.1", 23));
+        assert!(!tcp_target_allowed(&allowlist, "127.0.0.1", 502));
+        assert!(!tcp_target_allowed(&allowlist, "localhost", 22));
+        assert!(!tcp_target_allowed(&allowlist, "127.0.0.2", 22));
+        assert!(!tcp_target_allowed(&allowlist, "127.0.0.1:22", 22));
+        assert!(!tcp_target_allowed(&allowlist, "", 22));
+    }
+
+    #[test]
+    fn tcp_targets_match_ipv6_entries_with_or_without_brackets() {
+        let allowlist = allowlist(&["[::1]:22"]);
+
+        assert!(tcp_target_allowed(&allowlist, "::1", 22));
+        assert!(tcp_target_allowed(&allowlist, unbracket_host("[::1]"), 22));
+        assert!(!tcp_target_allowed(&allowlist, "[::1]", 22));
+        assert!(!tcp_target_allowed(&allowlist, "::1", 2222));
+        assert!(!tcp_target_allowed(&allowlist, "::2", 22));
+
+        let bare = self::allowlist(&["::1:22"]);
+        assert!(tcp_target_allowed(&bare, "::1", 22));
+    }
+
+    #[test]
+    fn bracketed_ipv6_targets_resolve_once_unbracketed() {
+        use std::net::ToSocketAddrs;
+
+        let host = unbracket_host("[::1]");
+        assert_eq!(host, "::1");
+        assert!((host, 22).to_socket_addrs().is_ok());
+        assert_eq!(unbracket_host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(unbracket_host("[::1"), "[::1");
+    }
+
+    #[test]
+    fn malformed_tcp_allowlist_entries_match_nothing() {
+        let allowlist = allowlist(&[
+            "127.0.0.1",
+            "127.0.0.1:",
+            "127.0.0.1:ssh",
+            "127.0.0.1:70000",
+        ]);
+
+        for port in [0, 22, 4464] {
+            assert!(!tcp_target_allowed(&allowlist, "127.0.0.1", port));
+        }
+    }
+
+    #[test]
+    fn empty_tcp_allowlist_allows_nothing() {
+        assert!(!tcp_target_allowed(&[], "127.0.0.1", 22));
+    }
+}
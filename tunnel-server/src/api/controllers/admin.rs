@@ -0,0 +1,153 @@
+use axum::Json;
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::api::controllers::auth::AuthAdmin;
+use crate::api::controllers::forward::forward_to_owner;
+use crate::state::TunnelState;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceListResponse {
+    /// Cluster node the listing was taken from, in cluster mode.
+    pub node_id: Option<String>,
+    pub devices: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub device_id: Uuid,
+    pub remote_addr: String,
+    /// Unix timestamp, in seconds.
+    pub connected_at: u64,
+    /// Unix timestamp of the last message from the device, in seconds.
+    pub last_seen_at: u64,
+    /// Round trip time of the last answered ping, in milliseconds.
+    pub rtt_ms: Option<f64>,
+    pub active_streams: usize,
+    pub protocol_version: u16,
+    pub client_version: String,
+    pub draining: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamListResponse {
+    pub device_id: Uuid,
+    pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamInfo {
+    pub stream_id: Uuid,
+    pub method: String,
+    pub path: String,
+    pub age_secs: u64,
+    pub bytes_to_device: u64,
+    pub bytes_from_device: u64,
+}
+
+/// List the devices connected to this node.
+pub async fn list_devices(_: AuthAdmin, State(state): State<TunnelState>) -> Response {
+    let mut devices: Vec<DeviceInfo> = state
+        .registry()
+        .sessions()
+        .into_iter()
+        .map(|(device_id, session)| DeviceInfo {
+            device_id,
+            remote_addr: session.remote_addr().to_owned(),
+            connected_at: session.connected_at(),
+            last_seen_at: session.last_seen_at(),
+            rtt_ms: session.rtt().map(|rtt| rtt.as_secs_f64() * 1000.0),
+            active_streams: session.active_streams(),
+            protocol_version: session.handshake().protocol_version,
+            client_version: session.handshake().peer_version.clone(),
+            draining: session.is_draining(),
+        })
+        .collect();
+    devices.sort_by_key(|device| device.connected_at);
+
+    Json(DeviceListResponse {
+        node_id: state.cluster().map(|cluster| cluster.node_id().to_owned()),
+        devices,
+    })
+    .into_response()
+}
+
+/// List the in-flight streams of a device.
+pub async fn list_streams(
+    _: AuthAdmin,
+    Path(device_id): Path<Uuid>,
+    State(state): State<TunnelState>,
+    req: Request,
+) -> Response {
+    let Some(session) = state.registry().get(device_id) else {
+        return not_connected(&state, device_id, req).await;
+    };
+
+    let mut streams: Vec<StreamInfo> = session
+        .streams()
+        .into_iter()
+        .map(|stream| StreamInfo {
+            stream_id: stream.stream_id,
+            method: stream.method,
+            path: stream.path,
+            age_secs: stream.age.as_secs(),
+            bytes_to_device: stream.bytes_to_device,
+            bytes_from_device: stream.bytes_from_device,
+        })
+        .collect();
+    streams.sort_by_key(|stream| std::cmp::Reverse(stream.age_secs));
+
+    Json(StreamListResponse { device_id, streams }).into_response()
+}
+
+/// Force-disconnect a device session. The device is free to reconnect.
+pub async fn disconnect_device(
+    _: AuthAdmin,
+    Path(device_id): Path<Uuid>,
+    State(state): State<TunnelState>,
+    req: Request,
+) -> Response {
+    let Some(session) = state.registry().get(device_id) else {
+        return not_connected(&state, device_id, req).await;
+    };
+
+    tracing::warn!(%device_id, "device session disconnected by admin");
+    session.shutdown();
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Cancel a single in-flight stream of a device.
+pub async fn cancel_stream(
+    _: AuthAdmin,
+    Path((device_id, stream_id)): Path<(Uuid, Uuid)>,
+    State(state): State<TunnelState>,
+    req: Request,
+) -> Response {
+    let Some(session) = state.registry().get(device_id) else {
+        return not_connected(&state, device_id, req).await;
+    };
+
+    if !session
+        .abort_stream(stream_id, "stream cancelled by admin")
+        .await
+    {
+        return (StatusCode::NOT_FOUND, "stream not found").into_response();
+    }
+
+    tracing::warn!(%device_id, %stream_id, "stream cancelled by admin");
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// In cluster mode the device may be connected to another node, which gets the request.
+async fn not_connected(state: &TunnelState, device_id: Uuid, req: Request) -> Response {
+    if state.cluster().is_some() {
+        return forward_to_owner(state, device_id, req).await;
+    }
+
+    (StatusCode::NOT_FOUND, "device not connected").into_response()
+}
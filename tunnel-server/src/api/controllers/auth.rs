@@ -4,7 +4,7 @@ use axum::http::header::AUTHORIZATION;
 use axum::http::request::Parts;
 use axum::response::{IntoResponse, Response};
 
-use crate::state::{Claims, TunnelState};
+use crate::state::{Claims, DeviceClaims, TunnelState};
 
 /// Authenticated user extracted from a Bearer JWT token.
 pub struct AuthUser(pub Claims);
@@ -36,3 +36,66 @@ where
         Ok(AuthUser(claims))
     }
 }
+
+/// Authenticated device extracted from a Bearer device token.
+pub struct AuthDevice(pub DeviceClaims);
+
+impl<S> FromRequestParts<S> for AuthDevice
+where
+    TunnelState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| {
+                tracing::warn!(uri = %parts.uri, "device connect rejected: missing device token");
+                (StatusCode::UNAUTHORIZED, "missing Authorization header").into_response()
+            })?;
+
+        let tunnel_state = TunnelState::from_ref(state);
+
+        let claims = tunnel_state.decode_device_token(token).map_err(|e| {
+            tracing::warn!(uri = %parts.uri, "device connect rejected: invalid device token: {e}");
+            (StatusCode::UNAUTHORIZED, "invalid device token").into_response()
+        })?;
+
+        Ok(AuthDevice(claims))
+    }
+}
+
+/// Operator authenticated with the `ADMIN_API_TOKEN` Bearer token.
+pub struct AuthAdmin;
+
+impl<S> FromRequestParts<S> for AuthAdmin
+where
+    TunnelState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| {
+                (StatusCode::UNAUTHORIZED, "missing Authorization header").into_response()
+            })?;
+
+        let tunnel_state = TunnelState::from_ref(state);
+
+        if !tunnel_state.verify_admin_token(token) {
+            tracing::warn!(uri = %parts.uri, "admin request rejected: invalid admin token");
+            return Err((StatusCode::UNAUTHORIZED, "invalid admin token").into_response());
+        }
+
+        Ok(AuthAdmin)
+    }
+}
@@ -1,16 +1,30 @@
-use anyhow::Result;
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{Query, State, WebSocketUpgrade};
-use axum::response::Response;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use axum::Extension;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use axum::extract::{ConnectInfo, Query, State, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use futures_util::{SinkExt, StreamExt};
-use nexus_utils::tunnel::{Frame, decode_frame, encode_frame};
+use nexus_utils::tunnel::{Frame, Handshake, decode_frame, encode_frame};
 use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
-use crate::registry::DeviceSession;
+use crate::api::controllers::auth::AuthDevice;
+use crate::cli::TUNNEL_SERVER_VERSION;
+use crate::registry::{DeviceSession, SessionChannels};
 use crate::state::TunnelState;
+use crate::telemetry::DEVICE_PONG_TIMEOUTS_TOTAL;
+use crate::tls::VerifiedClientCert;
+
+/// How often the reader checks whether the device still answers pings.
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum time to spend closing the socket; the peer may be gone.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Deserialize)]
 pub struct ConnectQuery {
@@ -19,25 +33,110 @@ pub struct ConnectQuery {
 
 pub async fn connect(
     ws: WebSocketUpgrade,
+    AuthDevice(claims): AuthDevice,
     Query(query): Query<ConnectQuery>,
     State(state): State<TunnelState>,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+    client_cert: Option<Extension<VerifiedClientCert>>,
+    headers: HeaderMap,
 ) -> Response {
-    ws.on_upgrade(move |socket| handle_device_socket(socket, query.device_id, state))
+    if claims.sub != query.device_id {
+        tracing::warn!(
+            device_id = %query.device_id,
+            token_device_id = %claims.sub,
+            "device connect rejected: token issued for another device"
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            "device token does not match device_id",
+        )
+            .into_response();
+    }
+
+    // The device ID equals the token subject by now, so the certificate names both.
+    if state.tls().is_some_and(|tls| tls.verifies_devices()) {
+        let Some(Extension(client_cert)) = client_cert else {
+            tracing::warn!(device_id = %query.device_id, "device connect rejected: no client certificate");
+            return (StatusCode::FORBIDDEN, "client certificate required").into_response();
+        };
+        if !client_cert.is_issued_for(&query.device_id.to_string()) {
+            tracing::warn!(
+                device_id = %query.device_id,
+                "device connect rejected: client certificate issued for another device"
+            );
+            return (
+                StatusCode::FORBIDDEN,
+                "client certificate does not match device_id",
+            )
+                .into_response();
+        }
+    }
+
+    if state.registry().is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "server is draining").into_response();
+    }
+
+    let remote_addr = remote_addr(&headers, peer_addr);
+    ws.on_upgrade(move |socket| handle_device_socket(socket, query.device_id, remote_addr, state))
+}
+
+/// The device address as seen by the first proxy in front of us, if any.
+fn remote_addr(headers: &HeaderMap, peer_addr: SocketAddr) -> String {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| peer_addr.to_string())
 }
 
-async fn handle_device_socket(socket: WebSocket, device_id: Uuid, state: TunnelState) {
+async fn handle_device_socket(
+    socket: WebSocket,
+    device_id: Uuid,
+    remote_addr: String,
+    state: TunnelState,
+) {
     let (mut sink, mut stream) = socket.split();
 
+    let handshake_timeout = Duration::from_secs(state.api_config().handshake_timeout_secs);
+    let handshake = match tokio::time::timeout(handshake_timeout, device_handshake(&mut stream))
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("handshake timed out")))
+    {
+        Ok(handshake) => handshake,
+        Err(err) => {
+            tracing::warn!(%device_id, "device handshake rejected: {err:#}");
+            let close = CloseFrame {
+                code: close_code::PROTOCOL,
+                reason: err.to_string().into(),
+            };
+            let _ = sink.send(Message::Close(Some(close))).await;
+            return;
+        }
+    };
+
+    if let Err(err) = send_hello(&mut sink, &handshake).await {
+        tracing::warn!(%device_id, "failed to send hello: {err:#}");
+        return;
+    }
+
     let (frame_tx, mut frame_rx) =
         mpsc::channel::<Frame>(state.api_config().stream_channel_capacity);
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel::<Frame>();
 
     let shutdown = state.shutdown_token().child_token();
 
     let (session, previous) = state.registry().register(
         device_id,
+        handshake.clone(),
+        remote_addr.clone(),
         state.api_config().max_concurrent_streams_per_device,
-        frame_tx,
-        shutdown.clone(),
+        SessionChannels {
+            frame_tx,
+            control_tx,
+            shutdown: shutdown.clone(),
+        },
     );
 
     if let Some(previous) = previous {
@@ -45,38 +144,78 @@ async fn handle_device_socket(socket: WebSocket, device_id: Uuid, state: TunnelS
         previous.shutdown();
     }
 
-    tracing::info!(%device_id, "device connected");
+    if let Some(cluster) = state.cluster()
+        && let Err(err) = cluster.claim_device(device_id).await
+    {
+        tracing::warn!(%device_id, "failed to claim device ownership: {err:#}");
+    }
+
+    tracing::info!(
+        %device_id,
+        %remote_addr,
+        protocol_version = handshake.protocol_version,
+        client_version = %handshake.peer_version,
+        capabilities = handshake.capabilities.bits(),
+        "device connected"
+    );
+
+    let ping_interval = Duration::from_secs(state.api_config().ping_interval_secs.max(1));
+    let pong_timeout = Duration::from_secs(state.api_config().pong_timeout_secs.max(1));
 
     let handle = tokio::spawn({
         let shutdown = shutdown.clone();
+        let session = session.clone();
 
         async move {
+            let mut ping = tokio::time::interval(ping_interval);
+            ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
             loop {
-                tokio::select! {
+                // Control frames go first so window updates never queue behind data,
+                // and pings go before data so a busy device still gets them.
+                let frame = tokio::select! {
+                    biased;
                     _ = shutdown.cancelled() => break,
-                    frame = frame_rx.recv() => {
-                        let Some(frame) = frame else {
-                            break;
+                    frame = control_rx.recv() => frame,
+                    _ = ping.tick() => {
+                        let Some(payload) = session.next_ping() else {
+                            continue;
                         };
-
-                        let payload = encode_frame(&frame)?;
                         tokio::select! {
                             _ = shutdown.cancelled() => break,
-                            result = sink.send(Message::Binary(payload.into())) => { result?; }
+                            result = sink.send(Message::Ping(payload)) => { result?; }
                         }
+                        continue;
                     }
+                    frame = frame_rx.recv() => frame,
+                };
+                let Some(frame) = frame else {
+                    break;
+                };
+
+                let payload = encode_frame(&frame)?;
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    result = sink.send(Message::Binary(payload.into())) => { result?; }
                 }
             }
 
-            sink.close().await?;
+            tokio::time::timeout(CLOSE_TIMEOUT, sink.close())
+                .await
+                .map_err(|_| anyhow!("timed out closing the socket"))??;
 
             Ok::<_, anyhow::Error>(())
         }
     });
 
-    if let Err(err) = device_reader_loop(&session, &mut stream).await {
-        tracing::error!(%device_id, "device session ended: {err:#}");
-    }
+    let reason = match device_reader_loop(&session, &mut stream, pong_timeout).await {
+        Ok(()) if shutdown.is_cancelled() => "closed by server".to_owned(),
+        Ok(()) => "closed by device".to_owned(),
+        Err(err) => {
+            tracing::error!(%device_id, "device session ended: {err:#}");
+            format!("{err:#}")
+        }
+    };
 
     shutdown.cancel();
 
@@ -89,30 +228,80 @@ async fn handle_device_socket(socket: WebSocket, device_id: Uuid, state: TunnelS
 
     session.close_all("device disconnected").await;
 
-    state.registry().unregister(device_id, &session);
+    if state.registry().unregister(device_id, &session, &reason)
+        && let Some(cluster) = state.cluster()
+        && let Err(err) = cluster.release_device(device_id).await
+    {
+        tracing::warn!(%device_id, "failed to release device ownership: {err:#}");
+    }
 
     tracing::info!(%device_id, "device disconnected");
 }
 
+async fn device_handshake(
+    stream: &mut futures_util::stream::SplitStream<WebSocket>,
+) -> Result<Handshake> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Binary(payload))) => {
+                return Handshake::negotiate(decode_frame(&payload)?);
+            }
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+            Some(Ok(Message::Close(_))) | None => return Err(anyhow!("closed before hello")),
+            Some(Ok(Message::Text(_))) => return Err(anyhow!("unexpected text frame")),
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+}
+
+async fn send_hello(
+    sink: &mut futures_util::stream::SplitSink<WebSocket, Message>,
+    handshake: &Handshake,
+) -> Result<()> {
+    let hello = Frame::Hello {
+        protocol_version: handshake.protocol_version,
+        version: TUNNEL_SERVER_VERSION.to_owned(),
+        capabilities: handshake.capabilities,
+    };
+    sink.send(Message::Binary(encode_frame(&hello)?.into()))
+        .await?;
+    Ok(())
+}
+
 async fn device_reader_loop(
     session: &Arc<DeviceSession>,
     stream: &mut futures_util::stream::SplitStream<WebSocket>,
+    pong_timeout: Duration,
 ) -> Result<()> {
     let shutdown = session.shutdown_token();
+    let mut liveness = tokio::time::interval(LIVENESS_CHECK_INTERVAL);
 
     loop {
         let msg = tokio::select! {
             _ = shutdown.cancelled() => return Ok(()),
+            _ = liveness.tick() => {
+                // A half-open connection never errors, it just goes quiet.
+                if session.is_unresponsive(pong_timeout) {
+                    metrics::counter!(DEVICE_PONG_TIMEOUTS_TOTAL).increment(1);
+                    return Err(anyhow!("no pong within {}s", pong_timeout.as_secs()));
+                }
+                continue;
+            }
             msg = stream.next() => msg,
         };
 
+        if let Some(Ok(_)) = msg {
+            session.record_seen();
+        }
+
         match msg {
             Some(Ok(Message::Binary(payload))) => {
                 let frame = decode_frame(&payload)?;
                 session.deliver_frame(frame).await?;
             }
             Some(Ok(Message::Close(_))) | None => break,
-            Some(Ok(Message::Pong(_) | Message::Ping(_))) => {}
+            Some(Ok(Message::Pong(payload))) => session.record_pong(&payload),
+            Some(Ok(Message::Ping(_))) => {}
             Some(Err(err)) => return Err(err.into()),
             Some(Ok(Message::Text(_))) => return Err(anyhow::anyhow!("unexpected text frame")),
         }
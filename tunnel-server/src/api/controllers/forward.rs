@@ -0,0 +1,112 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::header::{CONNECTION, HOST, UPGRADE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use nexus_utils::tunnel::is_upgrade_request;
+use uuid::Uuid;
+
+use crate::api::controllers::tunnel::{is_hop_by_hop, sanitized_headers};
+use crate::cluster::{Cluster, FORWARDED_BY_HEADER};
+use crate::error_page::tunnel_error;
+use crate::state::TunnelState;
+
+/// Handle a request for a device that has no usable session on this node by
+/// forwarding it to the node holding the device connection.
+pub async fn forward_to_owner(state: &TunnelState, device_id: Uuid, req: Request) -> Response {
+    let Some(cluster) = state.cluster() else {
+        return tunnel_error(StatusCode::SERVICE_UNAVAILABLE, "device not connected");
+    };
+
+    // Forwarded once already: the owner record is stale, don't bounce between nodes.
+    if req.headers().contains_key(&FORWARDED_BY_HEADER) {
+        return tunnel_error(StatusCode::SERVICE_UNAVAILABLE, "device not connected");
+    }
+
+    match cluster.owner_url(device_id).await {
+        Ok(Some(url)) => forward(cluster, &url, device_id, req).await,
+        Ok(None) => tunnel_error(StatusCode::SERVICE_UNAVAILABLE, "device not connected"),
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            tunnel_error(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+        }
+    }
+}
+
+async fn forward(cluster: &Cluster, base_url: &str, device_id: Uuid, mut req: Request) -> Response {
+    let upgrade = is_upgrade_request(req.headers());
+    let on_upgrade = if upgrade {
+        req.extensions_mut().remove::<OnUpgrade>()
+    } else {
+        None
+    };
+
+    let (parts, body) = req.into_parts();
+    let url = format!(
+        "{base_url}{}",
+        parts
+            .uri
+            .path_and_query()
+            .map(|value| value.as_str())
+            .unwrap_or("/")
+    );
+
+    // The owner routes session requests by `Host`, so it must survive the hop.
+    let mut headers = sanitized_headers(parts.headers.clone(), upgrade);
+    if let Some(host) = parts.headers.get(HOST) {
+        headers.insert(HOST, host.clone());
+    }
+    headers.insert(FORWARDED_BY_HEADER.clone(), cluster.forwarded_by().clone());
+
+    let mut builder = cluster.http().request(parts.method, &url).headers(headers);
+    if !upgrade {
+        builder = builder.body(reqwest::Body::wrap_stream(body.into_data_stream()));
+    }
+
+    let response = match builder.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            tracing::warn!(%device_id, %url, "cluster forward failed: {err}");
+            return tunnel_error(StatusCode::BAD_GATEWAY, "owning node unreachable");
+        }
+    };
+
+    let status = response.status();
+    let mut headers = HeaderMap::new();
+    for (name, value) in response.headers() {
+        if is_hop_by_hop(name.as_str()) {
+            continue;
+        }
+        headers.append(name.clone(), value.clone());
+    }
+
+    if status == StatusCode::SWITCHING_PROTOCOLS
+        && let Some(on_upgrade) = on_upgrade
+    {
+        for name in [CONNECTION, UPGRADE] {
+            if let Some(value) = response.headers().get(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+
+        tokio::spawn(async move {
+            let result = async {
+                let mut upstream = response.upgrade().await?;
+                let mut downstream = TokioIo::new(on_upgrade.await?);
+                tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+
+            if let Err(err) = result {
+                tracing::debug!(%device_id, "forwarded upgrade ended: {err:#}");
+            }
+        });
+
+        return (status, headers).into_response();
+    }
+
+    (status, headers, Body::from_stream(response.bytes_stream())).into_response()
+}
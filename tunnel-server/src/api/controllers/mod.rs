@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod auth;
 pub mod device;
+pub mod forward;
+pub mod tcp;
 pub mod tunnel;
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use axum::extract::{FromRequestParts, Path, Query, Request, State, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures_util::{SinkExt, StreamExt};
+use nexus_utils::tunnel::{Capabilities, Frame};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::api::controllers::auth::AuthUser;
+use crate::api::controllers::forward::forward_to_owner;
+use crate::api::controllers::tunnel::{authorize_device, max_chunk_size};
+use crate::audit::{AuditOutcome, StreamAudit};
+use crate::bandwidth::StreamLimiter;
+use crate::registry::{DeviceSession, StreamRegistration};
+use crate::state::TunnelState;
+
+#[derive(Debug, Deserialize)]
+pub struct TcpQuery {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Bridge a WebSocket to a raw TCP connection opened by the device to `host:port`.
+///
+/// Binary messages carry the TCP byte stream in both directions. Failures after the
+/// upgrade are reported as a WebSocket close frame with the reason.
+pub async fn connect(
+    AuthUser(claims): AuthUser,
+    Path(device_id): Path<Uuid>,
+    Query(query): Query<TcpQuery>,
+    State(state): State<TunnelState>,
+    req: Request,
+) -> Response {
+    if let Err(response) = authorize_device(&state, &claims, device_id).await {
+        return response;
+    }
+
+    let session = match state.registry().get(device_id) {
+        Some(session) if !session.is_draining() => session,
+        _ => return forward_to_owner(&state, device_id, req).await,
+    };
+
+    let (mut parts, _) = req.into_parts();
+    let ws = match WebSocketUpgrade::from_request_parts(&mut parts, &state).await {
+        Ok(ws) => ws,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    if !session.capabilities().contains(Capabilities::TCP) {
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            "device does not support tcp streams",
+        )
+            .into_response();
+    }
+
+    ws.on_upgrade(move |socket| {
+        handle_tcp_socket(socket, state, session, device_id, claims.sub, query)
+    })
+}
+
+async fn handle_tcp_socket(
+    mut socket: WebSocket,
+    state: TunnelState,
+    session: Arc<DeviceSession>,
+    device_id: Uuid,
+    user_id: String,
+    query: TcpQuery,
+) {
+    let stream_id = Uuid::new_v4();
+    let target = format!("{}:{}", query.host, query.port);
+    let mut audit = state.audit_stream(stream_id, &user_id, device_id, "TCP", &target);
+
+    let limiter = match state.stream_limiter(device_id, &user_id).await {
+        Ok(limiter) => limiter,
+        Err(err) => {
+            tracing::warn!(%device_id, %stream_id, "tcp stream refused: {err:#}");
+            audit.finish(AuditOutcome::Rejected, Some(err.to_string()));
+            let close = CloseFrame {
+                code: close_code::POLICY,
+                reason: err.to_string().into(),
+            };
+            let _ = socket.send(Message::Close(Some(close))).await;
+            return;
+        }
+    };
+
+    let body_rx = match open_tcp_stream(
+        &state,
+        &session,
+        stream_id,
+        &query,
+        limiter.clone(),
+        &mut audit,
+    )
+    .await
+    {
+        Ok(body_rx) => body_rx,
+        Err((code, reason)) => {
+            tracing::warn!(%device_id, %stream_id, host = %query.host, port = query.port, "tcp stream rejected: {reason}");
+            let outcome = if code == close_code::AGAIN {
+                AuditOutcome::Rejected
+            } else {
+                AuditOutcome::Failed
+            };
+            audit.finish(outcome, Some(reason.clone()));
+            let close = CloseFrame {
+                code,
+                reason: reason.into(),
+            };
+            let _ = socket.send(Message::Close(Some(close))).await;
+            return;
+        }
+    };
+
+    tracing::info!(%device_id, %stream_id, host = %query.host, port = query.port, "tcp stream opened");
+
+    let max_chunk_size = max_chunk_size(&state);
+    if let Err(err) = bridge(
+        socket,
+        &session,
+        stream_id,
+        body_rx,
+        max_chunk_size,
+        &limiter,
+    )
+    .await
+    {
+        tracing::debug!(%device_id, %stream_id, "tcp stream ended: {err:#}");
+        audit.finish(AuditOutcome::Failed, Some(format!("{err:#}")));
+        session.cancel_stream(stream_id).await;
+    }
+
+    tracing::info!(%device_id, %stream_id, "tcp stream closed");
+}
+
+/// Ask the device to connect and wait for its answer.
+async fn open_tcp_stream(
+    state: &TunnelState,
+    session: &DeviceSession,
+    stream_id: Uuid,
+    query: &TcpQuery,
+    limiter: StreamLimiter,
+    audit: &mut StreamAudit,
+) -> Result<mpsc::Receiver<Result<bytes::Bytes, std::io::Error>>, (u16, String)> {
+    let StreamRegistration {
+        head_rx,
+        mut body_rx,
+        stats,
+        ..
+    } = session
+        .register_stream(
+            stream_id,
+            "TCP",
+            &format!("{}:{}", query.host, query.port),
+            None,
+            state.api_config().stream_channel_capacity,
+            limiter,
+        )
+        .map_err(|err| (close_code::AGAIN, err.to_string()))?;
+    audit.set_stats(stats);
+
+    let open_frame = Frame::OpenTcpStream {
+        stream_id,
+        host: query.host.clone(),
+        port: query.port,
+    };
+
+    if session.send_frame(open_frame).await.is_err() {
+        session.cancel_stream(stream_id).await;
+        return Err((close_code::AWAY, "device not connected".to_owned()));
+    }
+
+    let head = match tokio::time::timeout(
+        Duration::from_secs(state.api_config().response_head_timeout_secs),
+        head_rx,
+    )
+    .await
+    {
+        Ok(Ok(head)) => head,
+        Ok(Err(_)) => return Err((close_code::ERROR, "device closed stream".to_owned())),
+        Err(_) => {
+            session.cancel_stream(stream_id).await;
+            return Err((close_code::ERROR, "device connect timeout".to_owned()));
+        }
+    };
+    audit.set_status(head.status);
+
+    if head.status != StatusCode::OK.as_u16() {
+        let code = if head.status == StatusCode::FORBIDDEN.as_u16() {
+            close_code::POLICY
+        } else {
+            close_code::ERROR
+        };
+        let reason = match body_rx.try_recv() {
+            Ok(Err(err)) => err.to_string(),
+            _ => format!("device refused tcp stream with status {}", head.status),
+        };
+        return Err((code, reason));
+    }
+
+    Ok(body_rx)
+}
+
+async fn bridge(
+    socket: WebSocket,
+    session: &DeviceSession,
+    stream_id: Uuid,
+    mut body_rx: mpsc::Receiver<Result<bytes::Bytes, std::io::Error>>,
+    max_chunk_size: usize,
+    limiter: &StreamLimiter,
+) -> Result<()> {
+    let (mut sink, mut stream) = socket.split();
+
+    let upstream = async {
+        while let Some(msg) = stream.next().await {
+            match msg? {
+                Message::Binary(mut data) => {
+                    while !data.is_empty() {
+                        let chunk = data.split_to(data.len().min(max_chunk_size));
+                        session.reserve_send(stream_id, chunk.len()).await?;
+                        session
+                            .send_frame(Frame::StreamData {
+                                stream_id,
+                                data: chunk,
+                            })
+                            .await?;
+                    }
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+                Message::Close(_) => break,
+                Message::Text(_) => bail!("text frames are not supported on tcp streams"),
+            }
+        }
+        session.send_frame(Frame::StreamEnd { stream_id }).await
+    };
+
+    let downstream = async {
+        while let Some(chunk) = body_rx.recv().await {
+            let chunk = chunk.map_err(|err| anyhow!("device stream failed: {err}"))?;
+            let len = chunk.len();
+            limiter.acquire(len).await?;
+            sink.send(Message::Binary(chunk)).await?;
+            session.release_received(stream_id, len);
+        }
+        sink.close().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+
+    tokio::try_join!(upstream, downstream)?;
+
+    Ok(())
+}
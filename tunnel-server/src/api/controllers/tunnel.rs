@@ -1,46 +1,197 @@
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use axum::Json;
 use axum::body::Body;
 use axum::extract::{Path, Request, State};
-use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::{ACCEPT, CONNECTION, LOCATION, UPGRADE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, Uri};
 use axum::response::{IntoResponse, Response};
 use bytes::Bytes;
 use futures_util::StreamExt;
-use nexus_utils::tunnel::{Frame, Headers};
-use serde::Serialize;
-use tokio_stream::wrappers::ReceiverStream;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use nexus_utils::identity::strip_identity;
+use nexus_utils::time::now_sec;
+use nexus_utils::trace::{REQUEST_ID_HEADER, TRACEPARENT_HEADER, TraceContext, request_id};
+use nexus_utils::tunnel::{Capabilities, Frame, Headers, INITIAL_WINDOW_SIZE, is_upgrade_request};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
 use uuid::Uuid;
 
 use crate::api::controllers::auth::AuthUser;
+use crate::api::controllers::forward::forward_to_owner;
+use crate::audit::{AuditOutcome, StreamAudit};
+use crate::bandwidth::StreamLimiter;
+use crate::cluster::FORWARDED_BY_HEADER;
+use crate::config::{ApiConfig, TunnelRouting};
+use crate::error_page::tunnel_error;
+use crate::limits::{LimitExceeded, RequestLimits, StreamTimers};
 use crate::registry::{DeviceSession, ResponseHead, StreamRegistration};
-use crate::state::TunnelState;
+use crate::rewrite::{ResponseRewriter, SESSION_PATH_PREFIX, rewrite_body, session_prefix};
+use crate::session::{SessionRecord, SharePolicy};
+use crate::state::{Claims, TunnelState};
+use crate::telemetry::{REDIS_DURATION_SECONDS, RESPONSE_HEAD_TIMEOUTS_TOTAL};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CreateSessionRequest {
+    /// Mint a restricted share link instead of a full owner session.
+    pub share: Option<ShareRequest>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ShareRequest {
+    /// Lifetime of the link, at most `session_ttl`. Defaults to `session_ttl`.
+    pub ttl_secs: Option<u64>,
+    #[serde(flatten)]
+    pub policy: SharePolicy,
+}
 
 #[derive(Debug, Serialize)]
 pub struct SessionResponse {
     pub url: String,
+    /// Unix timestamp, in seconds, the session ends at however active it is.
+    pub expires_at: u64,
+    /// Seconds without requests after which the session ends early.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionListResponse {
+    pub sessions: Vec<SessionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub token: String,
+    pub url: String,
+    pub device_id: Uuid,
+    /// Unix timestamp, in seconds.
+    pub expires_at: u64,
+    /// Restrictions, for share links.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share: Option<SharePolicy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionStatusResponse {
+    pub online: bool,
 }
 
 pub async fn create_session(
-    AuthUser(_claims): AuthUser,
+    AuthUser(claims): AuthUser,
     Path(device_id): Path<Uuid>,
     State(state): State<TunnelState>,
+    body: Option<Json<CreateSessionRequest>>,
 ) -> Response {
-    if !state.registry().is_online(device_id) {
-        return (StatusCode::SERVICE_UNAVAILABLE, "device not connected").into_response();
+    if let Err(response) = authorize_device(&state, &claims, device_id).await {
+        return response;
     }
 
-    let session_token = Uuid::new_v4().to_string();
-    let ttl = state.api_config().session_ttl;
-    if let Err(err) = state
-        .redis()
-        .store_session(&session_token, &device_id.to_string(), ttl)
-        .await
+    let max_ttl = state.api_config().session_ttl;
+    let idle_timeout = state.api_config().session_idle_timeout_secs;
+    let (ttl, share) = match body.and_then(|Json(request)| request.share) {
+        Some(share) => {
+            let ttl = share.ttl_secs.unwrap_or(max_ttl);
+            if ttl == 0 || ttl > max_ttl {
+                let message = format!("share ttl_secs must be between 1 and {max_ttl}");
+                return (StatusCode::BAD_REQUEST, message).into_response();
+            }
+            match share.policy.normalized() {
+                Ok(policy) => (ttl, Some(policy)),
+                Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            }
+        }
+        None => (max_ttl, None),
+    };
+
+    match state.is_device_online(device_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, "device not connected").into_response();
+        }
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
+        }
+    }
+
+    // Share links are always kept in Redis, which holds their policy and use count.
+    let (session_token, exp) = match (state.session_tokens(), share) {
+        (Some(session_tokens), None) => {
+            let (session_token, session) = session_tokens.issue(device_id, ttl);
+            // Signed tokens have no room for the email.
+            if let Some(identity) = state.identity() {
+                identity
+                    .remember_email(&claims.sub, &claims.email, ttl)
+                    .await;
+            }
+            let started = session_tokens.start(state.redis(), &session, &claims.sub);
+            if let Err(err) = observe_redis("store_session_owner", started).await {
+                tracing::error!("failed to store session: {err:#}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to create session",
+                )
+                    .into_response();
+            }
+            (session_token, session.exp)
+        }
+        (_, share) => {
+            let session_token = Uuid::new_v4().to_string();
+            let track_uses = share.as_ref().is_some_and(|share| share.max_uses.is_some());
+            let exp = now_sec() + ttl;
+            let record = SessionRecord {
+                device_id,
+                user_id: claims.sub.clone(),
+                email: state.identity().map(|_| claims.email.clone()),
+                expires_at: Some(exp),
+                share,
+            };
+            // The key lives for the idle timeout and every request extends it;
+            // `expires_at` caps the total lifetime.
+            let key_ttl = idle_timeout.map_or(ttl, |idle_timeout| idle_timeout.clamp(1, ttl));
+            let stored = async {
+                state
+                    .redis()
+                    .store_session(&session_token, &record.encode(), key_ttl)
+                    .await?;
+                if track_uses {
+                    state
+                        .redis()
+                        .store_session_uses(&session_token, ttl)
+                        .await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            };
+            if let Err(err) = observe_redis("store_session", stored).await {
+                tracing::error!("failed to store session: {err}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to create session",
+                )
+                    .into_response();
+            }
+            (session_token, exp)
+        }
+    };
+
+    if let Err(err) = observe_redis(
+        "index_session",
+        state
+            .redis()
+            .index_session(&claims.sub, device_id, &session_token, exp, max_ttl),
+    )
+    .await
     {
-        tracing::error!("failed to store session: {err}");
+        tracing::error!("failed to index session: {err:#}");
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             "failed to create session",
@@ -48,129 +199,880 @@ pub async fn create_session(
             .into_response();
     }
 
-    let scheme = &state.api_config().tunnel_scheme;
-    let domain = &state.api_config().tunnel_domain;
-    let url = format!("{scheme}://{session_token}.{domain}/");
+    let url = session_url(state.api_config(), &session_token);
 
-    Json(SessionResponse { url }).into_response()
+    Json(SessionResponse {
+        url,
+        expires_at: exp,
+        idle_timeout_secs: idle_timeout,
+    })
+    .into_response()
 }
 
-pub async fn proxy(State(state): State<TunnelState>, req: Request) -> Response {
-    let token = match extract_token(req.headers(), &state.api_config().tunnel_domain) {
-        Some(token) => token,
-        None => return (StatusCode::BAD_REQUEST, "missing or invalid Host header").into_response(),
-    };
+/// Revoke a session token so it stops working before its expiry. Allowed for the
+/// user who created the session and for the owner of its device.
+pub async fn revoke_session(
+    AuthUser(claims): AuthUser,
+    Path(token): Path<String>,
+    State(state): State<TunnelState>,
+) -> Response {
+    let signed = state.session_tokens().filter(|_| !is_opaque_token(&token));
 
-    let device_id: Uuid = match state.redis().get_session(&token).await {
-        Ok(Some(id)) => match id.parse() {
-            Ok(uuid) => uuid,
-            Err(_) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, "invalid session data").into_response();
+    let (creator, device_id, signed) = if let Some(session_tokens) = signed {
+        let Ok(session) = session_tokens.verify(&token) else {
+            return (StatusCode::NOT_FOUND, "session not found").into_response();
+        };
+        // `None` once the owner record expired; the device owner may still revoke.
+        match session_tokens.owner(state.redis(), &session).await {
+            Ok(creator) => (creator, session.device_id, Some((session_tokens, session))),
+            Err(err) => {
+                tracing::error!("redis error: {err:#}");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
             }
-        },
-        Ok(None) => return (StatusCode::NOT_FOUND, "session not found").into_response(),
+        }
+    } else {
+        match resolve_session(&state, &token, false).await {
+            Ok(record) => (Some(record.user_id), record.device_id, None),
+            Err(response) => return response,
+        }
+    };
+
+    let device_owner = observe_redis(
+        "get_device_owner",
+        state.redis().get_device_owner(&device_id.to_string()),
+    )
+    .await;
+    let device_owner = match device_owner {
+        Ok(device_owner) => device_owner,
         Err(err) => {
             tracing::error!("redis error: {err}");
             return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
         }
     };
+    if !may_revoke(&claims.sub, creator.as_deref(), device_owner.as_deref()) {
+        tracing::warn!(%device_id, sub = %claims.sub, "session revocation rejected");
+        return (StatusCode::FORBIDDEN, "session access denied").into_response();
+    }
 
-    let Some(session) = state.registry().get(device_id) else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "device not connected").into_response();
+    let revoked = match &signed {
+        Some((session_tokens, session)) => session_tokens.revoke(state.redis(), session).await,
+        None => state.redis().delete_session(&token).await,
     };
+    if let Err(err) = revoked {
+        tracing::error!("failed to revoke session: {err:#}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
+    }
+    let creator = creator.as_deref().unwrap_or(&claims.sub);
+    finish_revocation(&state, creator, device_id, &token).await;
 
-    let stream_id = Uuid::new_v4();
-    let registration =
-        match session.register_stream(stream_id, state.api_config().stream_channel_capacity) {
-            Ok(registration) => registration,
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Whether `sub` may revoke a session created by `creator` on a device owned by
+/// `device_owner`. Either is unknown once its Redis record is gone.
+fn may_revoke(sub: &str, creator: Option<&str>, device_owner: Option<&str>) -> bool {
+    creator == Some(sub) || device_owner == Some(sub)
+}
+
+/// List the caller's active sessions and share links.
+pub async fn list_sessions(
+    AuthUser(claims): AuthUser,
+    State(state): State<TunnelState>,
+) -> Response {
+    let indexed = match observe_redis(
+        "get_user_sessions",
+        state.redis().get_user_sessions(&claims.sub),
+    )
+    .await
+    {
+        Ok(indexed) => indexed,
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
+        }
+    };
+
+    let mut sessions = Vec::with_capacity(indexed.len());
+    let mut stale = Vec::new();
+    for (token, expires_at) in indexed {
+        match lookup_session(&state, &token).await {
+            Ok(Some(record)) => sessions.push(SessionInfo {
+                url: session_url(state.api_config(), &token),
+                token,
+                device_id: record.device_id,
+                expires_at,
+                share: record.share,
+            }),
+            Ok(None) => stale.push(token),
+            Err(err) => {
+                tracing::error!("failed to look up session: {err:#}");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
+            }
+        }
+    }
+
+    // Sessions revoked through their device stay in the user index until listed.
+    if let Err(err) = state
+        .redis()
+        .unindex_user_sessions(&claims.sub, &stale)
+        .await
+    {
+        tracing::warn!("failed to unindex sessions: {err:#}");
+    }
+
+    sessions.sort_by_key(|session| session.expires_at);
+    Json(SessionListResponse { sessions }).into_response()
+}
+
+/// Revoke every session and share link of a device.
+pub async fn revoke_device_sessions(
+    AuthUser(claims): AuthUser,
+    Path(device_id): Path<Uuid>,
+    State(state): State<TunnelState>,
+) -> Response {
+    if let Err(response) = authorize_device(&state, &claims, device_id).await {
+        return response;
+    }
+
+    let indexed = match state.redis().get_device_sessions(device_id).await {
+        Ok(indexed) => indexed,
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
+        }
+    };
+
+    for (token, _) in &indexed {
+        if let Err(err) = revoke_token(&state, token).await {
+            tracing::error!(%device_id, "failed to revoke session: {err:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response();
+        }
+        if let Err(err) = state.close_session_streams(device_id, token).await {
+            tracing::warn!(%device_id, "failed to cancel session streams: {err:#}");
+        }
+    }
+
+    if let Err(err) = state.redis().delete_device_sessions(device_id).await {
+        tracing::warn!(%device_id, "failed to delete session index: {err:#}");
+    }
+    tracing::info!(%device_id, sessions = indexed.len(), "device sessions revoked");
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Make a token stop working: signed tokens go on the revocation list, opaque
+/// tokens are deleted.
+async fn revoke_token(state: &TunnelState, token: &str) -> anyhow::Result<()> {
+    if let Some(session_tokens) = state.session_tokens()
+        && !is_opaque_token(token)
+    {
+        // Expired and already revoked tokens have nothing left to revoke.
+        if let Ok(session) = session_tokens.verify(token) {
+            session_tokens.revoke(state.redis(), &session).await?;
+        }
+        return Ok(());
+    }
+
+    state.redis().delete_session(token).await
+}
+
+/// Drop a revoked session from the indexes and cancel its in-flight streams.
+/// The token is already dead, so failures here are only logged.
+async fn finish_revocation(state: &TunnelState, user_id: &str, device_id: Uuid, token: &str) {
+    if let Err(err) = state
+        .redis()
+        .unindex_session(user_id, device_id, token)
+        .await
+    {
+        tracing::warn!("failed to unindex session: {err:#}");
+    }
+    if let Err(err) = state.close_session_streams(device_id, token).await {
+        tracing::warn!(%device_id, "failed to cancel session streams: {err:#}");
+    }
+}
+
+/// Like `resolve_session`, but `None` for sessions that expired or were revoked.
+async fn lookup_session(state: &TunnelState, token: &str) -> anyhow::Result<Option<SessionRecord>> {
+    if let Some(session_tokens) = state.session_tokens()
+        && !is_opaque_token(token)
+    {
+        let Ok(session) = session_tokens.verify(token) else {
+            return Ok(None);
+        };
+        if !session_tokens.is_active(state.redis(), &session).await? {
+            return Ok(None);
+        }
+        let owner = session_tokens.owner(state.redis(), &session).await?;
+        return Ok(owner.map(|owner| SessionRecord::signed(&session, owner)));
+    }
+
+    match state.redis().get_session(token).await? {
+        Some(record) => {
+            let record = SessionRecord::decode(&record)?;
+            Ok((!record.is_expired()).then_some(record))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Resolve a session token to its device and user: signed tokens are verified in
+/// memory, opaque tokens and share links are looked up in Redis. With `touch`,
+/// the request counts as activity and extends the session's idle timeout.
+async fn resolve_session(
+    state: &TunnelState,
+    token: &str,
+    touch: bool,
+) -> Result<SessionRecord, Response> {
+    let idle_timeout = state.api_config().session_idle_timeout_secs;
+
+    if let Some(session_tokens) = state.session_tokens()
+        && !is_opaque_token(token)
+    {
+        let session = session_tokens.verify(token).map_err(|err| {
+            tracing::debug!("session token rejected: {err:#}");
+            tunnel_error(StatusCode::NOT_FOUND, "session not found")
+        })?;
+        if idle_timeout.is_some() {
+            let active = if touch {
+                observe_redis(
+                    "touch_session",
+                    session_tokens.touch(state.redis(), &session),
+                )
+                .await
+            } else {
+                observe_redis(
+                    "get_session",
+                    session_tokens.is_active(state.redis(), &session),
+                )
+                .await
+            };
+            match active {
+                Ok(true) => {}
+                Ok(false) => return Err(tunnel_error(StatusCode::NOT_FOUND, "session not found")),
+                Err(err) => {
+                    tracing::error!("redis error: {err:#}");
+                    return Err(tunnel_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal error",
+                    ));
+                }
+            }
+        }
+        return match observe_redis(
+            "get_session_owner",
+            session_tokens.owner(state.redis(), &session),
+        )
+        .await
+        {
+            Ok(Some(owner)) => Ok(SessionRecord::signed(&session, owner)),
+            Ok(None) => Err(tunnel_error(StatusCode::NOT_FOUND, "session not found")),
             Err(err) => {
-                tracing::warn!(%device_id, "failed to register stream: {err:#}");
-                return (StatusCode::SERVICE_UNAVAILABLE, "device is overloaded").into_response();
+                tracing::error!("redis error: {err:#}");
+                Err(tunnel_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal error",
+                ))
             }
         };
+    }
+
+    let record = match idle_timeout.filter(|_| touch) {
+        Some(idle_timeout) => {
+            observe_redis(
+                "touch_session",
+                state.redis().touch_session(token, idle_timeout.max(1)),
+            )
+            .await
+        }
+        None => observe_redis("get_session", state.redis().get_session(token)).await,
+    };
+
+    match record {
+        Ok(Some(record)) => match SessionRecord::decode(&record) {
+            Ok(record) if record.is_expired() => {
+                Err(tunnel_error(StatusCode::NOT_FOUND, "session not found"))
+            }
+            Ok(record) => Ok(record),
+            Err(_) => Err(tunnel_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "invalid session data",
+            )),
+        },
+        Ok(None) => Err(tunnel_error(StatusCode::NOT_FOUND, "session not found")),
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            Err(tunnel_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal error",
+            ))
+        }
+    }
+}
+
+/// Opaque tokens are UUIDs, which signed tokens never parse as.
+fn is_opaque_token(token: &str) -> bool {
+    Uuid::parse_str(token).is_ok()
+}
+
+/// Enforce a share link's policy on a request and count it as a use.
+async fn authorize_share(
+    state: &TunnelState,
+    token: &str,
+    share: &SharePolicy,
+    method: &str,
+    path: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    if !share.allows_method(method) {
+        return Err((StatusCode::FORBIDDEN, "method not allowed by share link"));
+    }
+    if !share.allows_path(path) {
+        return Err((StatusCode::FORBIDDEN, "path not allowed by share link"));
+    }
+
+    if let Some(max_uses) = share.max_uses {
+        match observe_redis("incr_session_uses", state.redis().incr_session_uses(token)).await {
+            Ok(uses) if uses <= max_uses => {}
+            Ok(_) => return Err((StatusCode::FORBIDDEN, "share link use limit reached")),
+            Err(err) => {
+                tracing::error!("redis error: {err}");
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "internal error"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensure the authenticated user owns the device.
+pub(crate) async fn authorize_device(
+    state: &TunnelState,
+    claims: &Claims,
+    device_id: Uuid,
+) -> Result<(), Response> {
+    let device_id_str = device_id.to_string();
+    let device_owner = state.redis().get_device_owner(&device_id_str);
+    match observe_redis("get_device_owner", device_owner).await {
+        Ok(Some(owner)) if owner == claims.sub => Ok(()),
+        Ok(_) => {
+            tracing::warn!(%device_id, sub = %claims.sub, "access rejected: device not owned by user");
+            Err((StatusCode::FORBIDDEN, "device access denied").into_response())
+        }
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response())
+        }
+    }
+}
+
+/// Whether the device behind a session is reachable; polled by error pages
+/// waiting for the device to come back.
+pub async fn session_status(
+    Path(token): Path<String>,
+    State(state): State<TunnelState>,
+) -> Response {
+    let record = match resolve_session(&state, &token, false).await {
+        Ok(record) => record,
+        Err(response) => return response,
+    };
+
+    match state.is_device_online(record.device_id).await {
+        Ok(online) => Json(SessionStatusResponse { online }).into_response(),
+        Err(err) => {
+            tracing::error!("redis error: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response()
+        }
+    }
+}
+
+pub async fn proxy(State(state): State<TunnelState>, mut req: Request) -> Response {
+    // Set once on the node the browser reached and kept when forwarded to the owner,
+    // so every hop logs the same request ID and continues the same trace.
+    let request_id = request_id(req.headers());
+    let trace = TraceContext::from_headers(req.headers())
+        .map_or_else(TraceContext::new, |parent| parent.child());
+    let request_id_value = HeaderValue::from_str(&request_id).expect("validated request id");
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id_value.clone());
+    req.headers_mut()
+        .insert(TRACEPARENT_HEADER, trace.to_header_value());
+
+    let span = tracing::info_span!("proxy", %request_id, trace_id = %trace.trace_id());
+    let mut response = proxy_request(state, req).instrument(span).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id_value);
+    response
+}
+
+async fn proxy_request(state: TunnelState, req: Request) -> Response {
+    let accept = req.headers().get(ACCEPT).cloned();
+    let routing = state.api_config().tunnel_routing;
+    let token = match routing {
+        TunnelRouting::Subdomain => extract_token(req.headers(), &state.api_config().tunnel_domain)
+            .ok_or_else(|| tunnel_error(StatusCode::BAD_REQUEST, "missing or invalid Host header")),
+        TunnelRouting::Path => extract_path_token(req.uri().path())
+            .ok_or_else(|| tunnel_error(StatusCode::NOT_FOUND, "not found")),
+    };
+    let token = match token {
+        Ok(token) => token,
+        Err(response) => return state.error_pages().render(accept.as_ref(), None, response),
+    };
+
+    // Relative links only resolve under the session once the prefix ends in a slash.
+    if routing == TunnelRouting::Path && req.uri().path() == session_prefix(&token) {
+        let query = req
+            .uri()
+            .query()
+            .map(|query| format!("?{query}"))
+            .unwrap_or_default();
+        let location = format!("{}/{query}", session_prefix(&token));
+        return (StatusCode::PERMANENT_REDIRECT, [(LOCATION, location)]).into_response();
+    }
+
+    let response = proxy_session(state.clone(), &token, req).await;
+    let status_url = format!("/tunnel/session/{token}/status");
+    state
+        .error_pages()
+        .render(accept.as_ref(), Some(&status_url), response)
+}
+
+async fn proxy_session(state: TunnelState, token: &str, mut req: Request) -> Response {
+    let routing = state.api_config().tunnel_routing;
+    let record = match resolve_session(&state, token, true).await {
+        Ok(record) => record,
+        Err(response) => return response,
+    };
+    let device_id = record.device_id;
+
+    let session = match state.registry().get(device_id) {
+        Some(session) if !session.is_draining() => session,
+        // A draining session's device may already be connected to another node.
+        _ => return forward_to_owner(&state, device_id, req).await,
+    };
+
+    // The owner strips the prefix of forwarded requests itself.
+    if routing == TunnelRouting::Path {
+        match strip_session_prefix(req.uri(), token) {
+            Some(uri) => *req.uri_mut() = uri,
+            None => return tunnel_error(StatusCode::BAD_REQUEST, "invalid request path"),
+        }
+    }
+    let rewriter = state.response_rewriter(token);
+
+    // Forwarded requests are audited here, on the node holding the device connection.
+    let stream_id = Uuid::new_v4();
+    let mut audit = state.audit_stream(
+        stream_id,
+        &record.user_id,
+        device_id,
+        req.method().as_str(),
+        req.uri()
+            .path_and_query()
+            .map(|value| value.as_str())
+            .unwrap_or("/"),
+    );
+
+    let limits = RequestLimits::new(&state.api_config().request_limits);
+    if let Err(violation) = limits.check_headers(req.headers()).and_then(|()| {
+        limits.check_content_length(
+            req.headers()
+                .get(axum::http::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
+        )
+    }) {
+        return audit.reject(violation.status(), violation.message());
+    }
+
+    if let Some(share) = &record.share
+        && let Err((status, message)) = authorize_share(
+            &state,
+            token,
+            share,
+            req.method().as_str(),
+            req.uri().path(),
+        )
+        .await
+    {
+        return audit.reject(status, message);
+    }
+
+    let on_upgrade = if is_upgrade_request(req.headers()) {
+        if !session.capabilities().contains(Capabilities::UPGRADE) {
+            return audit.reject(
+                StatusCode::NOT_IMPLEMENTED,
+                "device does not support upgrades",
+            );
+        }
+        match req.extensions_mut().remove::<OnUpgrade>() {
+            Some(on_upgrade) => Some(on_upgrade),
+            None => {
+                return audit.reject(StatusCode::BAD_REQUEST, "connection cannot be upgraded");
+            }
+        }
+    } else {
+        None
+    };
+
+    let limiter = match state.stream_limiter(device_id, &record.user_id).await {
+        Ok(limiter) => limiter,
+        Err(err) => {
+            tracing::warn!(%device_id, "stream refused: {err:#}");
+            return audit.reject(StatusCode::TOO_MANY_REQUESTS, "monthly data cap reached");
+        }
+    };
+
+    let registration = match session.register_stream(
+        stream_id,
+        req.method().as_str(),
+        req.uri().path(),
+        Some(token),
+        state.api_config().stream_channel_capacity,
+        limiter,
+    ) {
+        Ok(registration) => registration,
+        Err(err) => {
+            tracing::warn!(%device_id, "failed to register stream: {err:#}");
+            return audit.reject(StatusCode::SERVICE_UNAVAILABLE, "device is overloaded");
+        }
+    };
+    audit.set_stats(registration.stats.clone());
 
     let (parts, body) = req.into_parts();
     let content_length = parts
         .headers
         .get(axum::http::header::CONTENT_LENGTH)
         .and_then(|value| value.to_str().ok()?.parse().ok());
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| "/".parse().expect("root path_and_query"));
+
+    let mut headers = sanitized_headers(parts.headers, on_upgrade.is_some());
+    // Devices that understand it get the trace context as part of the frame;
+    // others pass the headers on as they are.
+    let (request_id, traceparent) = if session.capabilities().contains(Capabilities::TRACE_CONTEXT)
+    {
+        let mut take = |name| {
+            headers
+                .remove(name)
+                .and_then(|value| value.to_str().ok().map(str::to_owned))
+        };
+        (take(REQUEST_ID_HEADER), take(TRACEPARENT_HEADER))
+    } else {
+        (None, None)
+    };
+    if let Some(identity_headers) = state.identity() {
+        let identity = identity_headers.identity(&record, token).await;
+        identity_headers.sign(
+            &mut headers,
+            device_id,
+            &identity,
+            &parts.method,
+            path_and_query.as_str(),
+        );
+    }
 
     let open_frame = Frame::OpenStream {
         stream_id,
         method: parts.method,
-        path_and_query: parts
-            .uri
-            .path_and_query()
-            .cloned()
-            .unwrap_or_else(|| "/".parse().expect("root path_and_query")),
-        headers: sanitized_headers(parts.headers),
+        path_and_query,
+        headers,
         content_length,
+        request_id,
+        traceparent,
     };
 
     if let Err(err) = session.send_frame(open_frame).await {
         tracing::warn!(%device_id, %stream_id, "failed to send stream open: {err:#}");
         session.cancel_stream(stream_id).await;
-        return (StatusCode::SERVICE_UNAVAILABLE, "device not connected").into_response();
+        return audit.fail(StatusCode::SERVICE_UNAVAILABLE, "device not connected");
     }
 
-    let session_for_body = session.clone();
-    let max_chunk_size = state.api_config().max_chunk_size_bytes;
-    tokio::spawn(async move {
-        if let Err(err) =
-            forward_request_body(&session_for_body, stream_id, body, max_chunk_size).await
-        {
-            tracing::warn!(%stream_id, "request body forwarding failed: {err:#}");
-            session_for_body.cancel_stream(stream_id).await;
-        }
-    });
+    // Upgraded connections may stay open and quiet for as long as they are used.
+    let timers = match on_upgrade {
+        Some(_) => StreamTimers::unlimited(),
+        None => limits.start(),
+    };
+    let (violation_tx, request_violation) = oneshot::channel();
+    let max_chunk_size = max_chunk_size(&state);
+
+    // Upgrade requests carry no body; their bytes flow as `StreamData` after the `101`.
+    if on_upgrade.is_none() {
+        let session_for_body = session.clone();
+        tokio::spawn(
+            async move {
+                let result = forward_request_body(
+                    &session_for_body,
+                    stream_id,
+                    body,
+                    max_chunk_size,
+                    &limits,
+                    timers,
+                )
+                .await;
+                let Err(err) = result else {
+                    return;
+                };
+
+                // Report the violation before the abort fails the response head.
+                if let Some(&violation) = err.downcast_ref::<LimitExceeded>() {
+                    tracing::debug!(%stream_id, "request body cancelled: {violation}");
+                    let _ = violation_tx.send(violation);
+                    session_for_body
+                        .abort_stream(stream_id, violation.message())
+                        .await;
+                } else {
+                    tracing::warn!(%stream_id, "request body forwarding failed: {err:#}");
+                    session_for_body.cancel_stream(stream_id).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
 
-    build_streaming_response(state, session, stream_id, registration).await
+    let limits = StreamLimits {
+        head_timeout: Duration::from_secs(state.api_config().response_head_timeout_secs),
+        timers,
+        max_chunk_size,
+        request_violation,
+    };
+    build_streaming_response(
+        session,
+        stream_id,
+        registration,
+        on_upgrade,
+        rewriter,
+        limits,
+        audit,
+    )
+    .await
+}
+
+/// Limits on one stream while it waits for and relays the device response.
+struct StreamLimits {
+    head_timeout: Duration,
+    timers: StreamTimers,
+    max_chunk_size: usize,
+    /// Limit broken by the request body, reported by the task forwarding it.
+    request_violation: oneshot::Receiver<LimitExceeded>,
 }
 
 async fn build_streaming_response(
-    state: TunnelState,
     session: Arc<DeviceSession>,
     stream_id: Uuid,
-    registration: StreamRegistration,
+    mut registration: StreamRegistration,
+    on_upgrade: Option<OnUpgrade>,
+    rewriter: ResponseRewriter,
+    limits: StreamLimits,
+    mut audit: StreamAudit,
 ) -> Response {
-    let head = match tokio::time::timeout(
-        Duration::from_secs(state.api_config().response_head_timeout_secs),
-        registration.head_rx,
-    )
-    .await
-    {
-        Ok(Ok(head)) => head,
-        Ok(Err(_)) => {
+    // The body task drops its sender without a violation once the body is sent.
+    let request_violation = async {
+        match limits.request_violation.await {
+            Ok(violation) => violation,
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    let head = tokio::select! {
+        biased;
+        // The body task has already cancelled the stream.
+        violation = request_violation => {
+            return audit.fail(violation.status(), violation.message());
+        }
+        head = &mut registration.head_rx => match head {
+            Ok(head) => head,
+            Err(_) => {
+                session.cancel_stream(stream_id).await;
+                return audit.fail(StatusCode::BAD_GATEWAY, "device closed stream");
+            }
+        },
+        _ = limits.timers.expired() => {
+            let violation = LimitExceeded::Duration.record();
             session.cancel_stream(stream_id).await;
-            return (StatusCode::BAD_GATEWAY, "device closed stream").into_response();
+            return audit.fail(violation.status(), violation.message());
         }
-        Err(_) => {
+        _ = tokio::time::sleep(limits.head_timeout) => {
             session.cancel_stream(stream_id).await;
-            return (StatusCode::GATEWAY_TIMEOUT, "device response timeout").into_response();
+            metrics::counter!(RESPONSE_HEAD_TIMEOUTS_TOTAL).increment(1);
+            return audit.fail(StatusCode::GATEWAY_TIMEOUT, "device response timeout");
+        }
+    };
+    audit.set_status(head.status);
+
+    match on_upgrade {
+        Some(on_upgrade) if head.status == StatusCode::SWITCHING_PROTOCOLS.as_u16() => {
+            upgraded_response(
+                head,
+                registration,
+                session,
+                stream_id,
+                on_upgrade,
+                limits.max_chunk_size,
+                audit,
+            )
+        }
+        _ => response_from_stream(
+            head,
+            registration,
+            session,
+            stream_id,
+            rewriter,
+            limits.timers,
+            audit,
+        ),
+    }
+}
+
+fn upgraded_response(
+    head: ResponseHead,
+    registration: StreamRegistration,
+    session: Arc<DeviceSession>,
+    stream_id: Uuid,
+    on_upgrade: OnUpgrade,
+    max_chunk_size: usize,
+    mut audit: StreamAudit,
+) -> Response {
+    let StreamRegistration {
+        mut body_rx,
+        limiter,
+        ..
+    } = registration;
+
+    tokio::spawn(
+        async move {
+            let result = async {
+                let upgraded = TokioIo::new(on_upgrade.await?);
+                pump_upgraded(
+                    upgraded,
+                    &mut body_rx,
+                    &session,
+                    stream_id,
+                    max_chunk_size,
+                    &limiter,
+                )
+                .await
+            }
+            .await;
+
+            if let Err(err) = result {
+                tracing::debug!(%stream_id, "upgraded stream ended: {err:#}");
+                audit.finish(AuditOutcome::Failed, Some(format!("{err:#}")));
+                session.cancel_stream(stream_id).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.extend(head.headers);
+
+    (StatusCode::SWITCHING_PROTOCOLS, headers).into_response()
+}
+
+async fn pump_upgraded<T>(
+    io: T,
+    body_rx: &mut mpsc::Receiver<Result<Bytes, std::io::Error>>,
+    session: &DeviceSession,
+    stream_id: Uuid,
+    max_chunk_size: usize,
+    limiter: &StreamLimiter,
+) -> anyhow::Result<()>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+
+    let upstream = async {
+        let mut buf = vec![0u8; max_chunk_size];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return session.send_frame(Frame::StreamEnd { stream_id }).await;
+            }
+            session.reserve_send(stream_id, n).await?;
+            session
+                .send_frame(Frame::StreamData {
+                    stream_id,
+                    data: Bytes::copy_from_slice(&buf[..n]),
+                })
+                .await?;
+        }
+    };
+
+    let downstream = async {
+        while let Some(chunk) = body_rx.recv().await {
+            let chunk = chunk?;
+            limiter.acquire(chunk.len()).await?;
+            writer.write_all(&chunk).await?;
+            session.release_received(stream_id, chunk.len());
         }
+        writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
     };
 
-    response_from_stream(head, registration.body_rx, session, stream_id)
+    tokio::try_join!(upstream, downstream)?;
+
+    Ok(())
 }
 
 fn response_from_stream(
     head: ResponseHead,
-    body_rx: tokio::sync::mpsc::Receiver<Result<Bytes, std::io::Error>>,
+    registration: StreamRegistration,
     session: Arc<DeviceSession>,
     stream_id: Uuid,
+    rewriter: ResponseRewriter,
+    timers: StreamTimers,
+    audit: StreamAudit,
 ) -> Response {
     let status = StatusCode::from_u16(head.status).unwrap_or(StatusCode::BAD_GATEWAY);
     let mut headers = HeaderMap::new();
     headers.extend(head.headers);
+    let html_rewriter = rewriter.rewrite_headers(&mut headers);
+
+    // A stalled or overlong body ends in an error; dropping it cancels the stream.
+    let body = futures_util::stream::unfold(registration.body_rx, move |mut body_rx| async move {
+        match timers
+            .idle(body_rx.recv(), LimitExceeded::ResponseIdle)
+            .await
+        {
+            Ok(chunk) => chunk.map(|chunk| (chunk, body_rx)),
+            Err(violation) => Some((Err(std::io::Error::other(violation)), body_rx)),
+        }
+    });
+
+    // Throttle before the chunk is released, so a limited stream holds back the
+    // device through its window instead of buffering here.
+    let limiter = registration.limiter;
+    let body = body
+        .then(move |chunk| {
+            let limiter = limiter.clone();
+            async move {
+                let chunk = chunk?;
+                limiter
+                    .acquire(chunk.len())
+                    .await
+                    .map_err(std::io::Error::other)?;
+                Ok(chunk)
+            }
+        })
+        .boxed();
 
     let body_stream = CancelOnDropStream {
-        inner: ReceiverStream::new(body_rx),
+        inner: body,
         finished: false,
         session,
         stream_id,
+        audit,
+    };
+
+    let body = match html_rewriter {
+        Some(html_rewriter) => Body::from_stream(rewrite_body(body_stream, html_rewriter)),
+        None => Body::from_stream(body_stream),
     };
 
-    (status, headers, Body::from_stream(body_stream)).into_response()
+    (status, headers, body).into_response()
 }
 
 async fn forward_request_body(
@@ -178,12 +1080,21 @@ async fn forward_request_body(
     stream_id: Uuid,
     body: Body,
     max_chunk_size: usize,
+    limits: &RequestLimits,
+    timers: StreamTimers,
 ) -> anyhow::Result<()> {
     let mut stream = body.into_data_stream();
+    let mut received = 0u64;
 
-    while let Some(chunk) = stream.next().await {
+    while let Some(chunk) = timers
+        .idle(stream.next(), LimitExceeded::RequestIdle)
+        .await?
+    {
         let chunk = chunk?;
+        received += chunk.len() as u64;
+        limits.check_body(received)?;
         for slice in chunk.chunks(max_chunk_size) {
+            session.reserve_send(stream_id, slice.len()).await?;
             session
                 .send_frame(Frame::RequestBodyChunk {
                     stream_id,
@@ -200,13 +1111,61 @@ async fn forward_request_body(
     Ok(())
 }
 
+/// Time a Redis call on the request path.
+async fn observe_redis<T>(op: &'static str, fut: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let result = fut.await;
+    metrics::histogram!(REDIS_DURATION_SECONDS, "op" => op).record(started.elapsed().as_secs_f64());
+    result
+}
+
+/// Body chunks must fit in a single stream window.
+pub(crate) fn max_chunk_size(state: &TunnelState) -> usize {
+    state
+        .api_config()
+        .max_chunk_size_bytes
+        .min(INITIAL_WINDOW_SIZE as usize)
+}
+
+fn session_url(config: &ApiConfig, token: &str) -> String {
+    let scheme = &config.tunnel_scheme;
+    let domain = &config.tunnel_domain;
+    match config.tunnel_routing {
+        TunnelRouting::Subdomain => format!("{scheme}://{token}.{domain}/"),
+        TunnelRouting::Path => format!("{scheme}://{domain}{}/", session_prefix(token)),
+    }
+}
+
+/// Whether a request is addressed to a session subdomain rather than the tunnel domain.
+pub(crate) fn is_session_host(config: &ApiConfig, headers: &HeaderMap) -> bool {
+    config.tunnel_routing == TunnelRouting::Subdomain
+        && extract_token(headers, &config.tunnel_domain).is_some()
+}
+
 fn extract_token(headers: &HeaderMap, tunnel_domain: &str) -> Option<String> {
     let host = headers.get("host")?.to_str().ok()?;
     let suffix = format!(".{tunnel_domain}");
     host.strip_suffix(&suffix).map(|value| value.to_owned())
 }
 
-fn is_hop_by_hop(name: &str) -> bool {
+/// Token from a `/t/{token}/...` path.
+fn extract_path_token(path: &str) -> Option<String> {
+    let rest = path.strip_prefix(SESSION_PATH_PREFIX)?;
+    let token = rest.split('/').next()?;
+    (!token.is_empty()).then(|| token.to_owned())
+}
+
+/// The URI the device sees: the request path below the session prefix.
+fn strip_session_prefix(uri: &Uri, token: &str) -> Option<Uri> {
+    let path_and_query = uri.path_and_query()?.as_str();
+    path_and_query
+        .strip_prefix(&session_prefix(token))
+        .filter(|rest| rest.starts_with('/'))?
+        .parse()
+        .ok()
+}
+
+pub(crate) fn is_hop_by_hop(name: &str) -> bool {
     matches!(
         name.to_ascii_lowercase().as_str(),
         "connection"
@@ -221,14 +1180,24 @@ fn is_hop_by_hop(name: &str) -> bool {
     )
 }
 
-fn sanitized_headers(headers: HeaderMap) -> Headers {
+/// Headers to pass on. Identity headers are dropped too: only the node holding
+/// the device sets them.
+pub(crate) fn sanitized_headers(headers: HeaderMap, upgrade: bool) -> Headers {
     let mut sanitized = HeaderMap::new();
     for (name, value) in &headers {
-        if is_hop_by_hop(name.as_str()) {
+        if is_hop_by_hop(name.as_str()) || name == FORWARDED_BY_HEADER {
             continue;
         }
         sanitized.append(name.clone(), value.clone());
     }
+    strip_identity(&mut sanitized);
+
+    // Upgrade negotiation is end-to-end through the tunnel, so keep its headers.
+    if upgrade && let Some(protocol) = headers.get(UPGRADE) {
+        sanitized.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        sanitized.insert(UPGRADE, protocol.clone());
+    }
+
     sanitized
 }
 
@@ -237,6 +1206,7 @@ struct CancelOnDropStream<S> {
     finished: bool,
     session: Arc<DeviceSession>,
     stream_id: Uuid,
+    audit: StreamAudit,
 }
 
 impl<S> futures_util::Stream for CancelOnDropStream<S>
@@ -247,6 +1217,15 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.session.release_received(self.stream_id, chunk.len());
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.audit
+                    .finish(AuditOutcome::Failed, Some(err.to_string()));
+                Poll::Ready(Some(Err(err)))
+            }
             Poll::Ready(None) => {
                 self.finished = true;
                 Poll::Ready(None)
@@ -262,6 +1241,11 @@ impl<S> Drop for CancelOnDropStream<S> {
             return;
         }
 
+        self.audit.finish(
+            AuditOutcome::Cancelled,
+            Some("client disconnected".to_owned()),
+        );
+
         let stream_id = self.stream_id;
         let session = self.session.clone();
         tokio::spawn(async move {
@@ -269,3 +1253,80 @@ impl<S> Drop for CancelOnDropStream<S> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nexus_utils::tunnel::{Handshake, PROTOCOL_VERSION};
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::config::RequestLimitsConfig;
+    use crate::registry::{DeviceRegistry, SessionChannels};
+
+    #[tokio::test]
+    async fn upload_chunks_count_once_against_the_monthly_cap() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(16);
+        let (control_tx, _control_rx) = mpsc::unbounded_channel();
+        let handshake = Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            peer_version: String::new(),
+            capabilities: Capabilities::SUPPORTED,
+        };
+        let (session, _) = DeviceRegistry::new(None).register(
+            Uuid::new_v4(),
+            handshake,
+            "127.0.0.1:0".into(),
+            8,
+            SessionChannels {
+                frame_tx,
+                control_tx,
+                shutdown: CancellationToken::new(),
+            },
+        );
+
+        let stream_id = Uuid::new_v4();
+        let limiter = StreamLimiter::with_monthly_cap(u64::MAX);
+        let _registration = session
+            .register_stream(stream_id, "POST", "/", None, 8, limiter.clone())
+            .unwrap();
+
+        let chunk = vec![7u8; 1000];
+        forward_request_body(
+            &session,
+            stream_id,
+            Body::from(chunk.clone()),
+            64 * 1024,
+            &RequestLimits::new(&RequestLimitsConfig::default()),
+            StreamTimers::unlimited(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(limiter.monthly_usage(), chunk.len() as u64);
+        assert!(matches!(
+            frame_rx.recv().await,
+            Some(Frame::RequestBodyChunk { data, .. }) if data.len() == chunk.len()
+        ));
+    }
+
+    #[test]
+    fn opaque_sessions_are_revoked_by_creator_or_device_owner() {
+        // Opaque session records always name their creator.
+        assert!(may_revoke("alice", Some("alice"), Some("bob")));
+        assert!(may_revoke("bob", Some("alice"), Some("bob")));
+        assert!(!may_revoke("carol", Some("alice"), Some("bob")));
+        assert!(!may_revoke("carol", Some("alice"), None));
+    }
+
+    #[test]
+    fn signed_sessions_are_revoked_by_creator_or_device_owner() {
+        assert!(may_revoke("alice", Some("alice"), Some("bob")));
+        assert!(may_revoke("bob", Some("alice"), Some("bob")));
+        assert!(!may_revoke("carol", Some("alice"), Some("bob")));
+
+        // The creator of a signed session is gone once its owner record expires.
+        assert!(may_revoke("bob", None, Some("bob")));
+        assert!(!may_revoke("alice", None, Some("bob")));
+        assert!(!may_revoke("alice", None, None));
+    }
+}
@@ -1,24 +1,45 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
-use tokio::net::TcpListener;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::{TcpListener, TcpStream};
 use tokio_util::sync::CancellationToken;
-use tower::ServiceBuilder;
+use tokio_util::task::TaskTracker;
+use tower::{ServiceBuilder, ServiceExt, service_fn};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use crate::api::controllers;
 use crate::state::TunnelState;
+use crate::telemetry;
+use crate::tls::{TlsTerminator, VerifiedClientCert};
+
+/// Pause after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 
 // ── Builder ───────────────────────────────────────────────────────────────
 
 pub struct TunnelEndpointBuilder {
     healthcheck_route: Option<String>,
+    readiness_route: Option<String>,
+    metrics_route: Option<String>,
 }
 
 impl Default for TunnelEndpointBuilder {
     fn default() -> Self {
         Self {
             healthcheck_route: Some("/health".to_owned()),
+            readiness_route: Some("/ready".to_owned()),
+            metrics_route: Some("/metrics".to_owned()),
         }
     }
 }
@@ -27,12 +48,42 @@ impl TunnelEndpointBuilder {
     pub async fn bind(self, state: TunnelState) -> Result<TunnelEndpoint> {
         let listener = state.bind_socket().await?;
         let origins = state.api_config().clone().cors_origins;
-        Ok(TunnelEndpoint::from_parts(
+        let tls = state.tls().cloned();
+        Ok(
+            TunnelEndpoint::from_parts(listener, self.build_router(), state, &origins)
+                .with_tls(tls),
+        )
+    }
+
+    /// Plain HTTP endpoint on `internal_listen_addr` for metrics, health checks and
+    /// the admin API. In cluster mode it also takes the requests other nodes
+    /// forward here, so they need no TLS between nodes.
+    pub async fn bind_internal(self, state: TunnelState) -> Result<TunnelEndpoint> {
+        let listener = state.bind_internal_socket().await?;
+        let internal = self.build_internal_router().with_state(state.clone());
+
+        let router = if state.cluster().is_some() {
+            let public = self.build_router().with_state(state.clone());
+            let internal = internal.fallback_service(public.clone());
+            // Session hosts carry device paths, which internal routes must not shadow.
+            let config = state.api_config().clone();
+            axum::Router::new().fallback_service(service_fn(move |req: Request<Body>| {
+                let router = if controllers::tunnel::is_session_host(&config, req.headers()) {
+                    public.clone()
+                } else {
+                    internal.clone()
+                };
+                router.oneshot(req)
+            }))
+        } else {
+            internal
+        };
+
+        Ok(TunnelEndpoint {
             listener,
-            self.build_router(),
-            state,
-            &origins,
-        ))
+            router,
+            tls: None,
+        })
     }
 
     fn build_router<S>(&self) -> axum::Router<S>
@@ -51,9 +102,60 @@ impl TunnelEndpointBuilder {
                 "/tunnel/{device_id}/session",
                 post(controllers::tunnel::create_session),
             )
+            .route("/tunnel/sessions", get(controllers::tunnel::list_sessions))
+            .route(
+                "/tunnel/session/{token}",
+                delete(controllers::tunnel::revoke_session),
+            )
+            .route(
+                "/tunnel/session/{token}/status",
+                get(controllers::tunnel::session_status),
+            )
+            .route(
+                "/tunnel/{device_id}/sessions",
+                delete(controllers::tunnel::revoke_device_sessions),
+            )
+            .route("/tunnel/{device_id}/tcp", get(controllers::tcp::connect))
             .route("/device/connect", get(controllers::device::connect))
             .fallback(controllers::tunnel::proxy)
     }
+
+    /// Operational routes, kept off the public listener so they neither leak
+    /// nor shadow device paths.
+    fn build_internal_router<S>(&self) -> axum::Router<S>
+    where
+        TunnelState: axum::extract::FromRef<S>,
+        S: Clone + Send + Sync + 'static,
+    {
+        let mut router = axum::Router::new();
+
+        if let Some(route) = &self.healthcheck_route {
+            router = router.route(route, get(health_check));
+        }
+
+        if let Some(route) = &self.readiness_route {
+            router = router.route(route, get(readiness_check));
+        }
+
+        if let Some(route) = &self.metrics_route {
+            router = router.route(route, get(metrics));
+        }
+
+        router
+            .route("/admin/devices", get(controllers::admin::list_devices))
+            .route(
+                "/admin/devices/{device_id}",
+                delete(controllers::admin::disconnect_device),
+            )
+            .route(
+                "/admin/devices/{device_id}/streams",
+                get(controllers::admin::list_streams),
+            )
+            .route(
+                "/admin/devices/{device_id}/streams/{stream_id}",
+                delete(controllers::admin::cancel_stream),
+            )
+    }
 }
 
 // ── Endpoint ──────────────────────────────────────────────────────────────
@@ -61,6 +163,7 @@ impl TunnelEndpointBuilder {
 pub struct TunnelEndpoint {
     listener: TcpListener,
     router: axum::Router<()>,
+    tls: Option<Arc<TlsTerminator>>,
 }
 
 impl TunnelEndpoint {
@@ -81,13 +184,123 @@ impl TunnelEndpoint {
 
         let router = router.layer(service).with_state(state);
 
-        Self { listener, router }
+        Self {
+            listener,
+            router,
+            tls: None,
+        }
+    }
+
+    /// Terminate TLS on the listener instead of serving plain HTTP.
+    pub fn with_tls(mut self, tls: Option<Arc<TlsTerminator>>) -> Self {
+        self.tls = tls;
+        self
     }
 
     pub async fn serve(self, token: CancellationToken) -> std::io::Result<()> {
-        axum::serve(self.listener, self.router)
-            .with_graceful_shutdown(async move { token.cancelled().await })
-            .await
+        if let Some(tls) = self.tls {
+            serve_tls(self.listener, self.router, tls, token).await;
+            return Ok(());
+        }
+
+        axum::serve(
+            self.listener,
+            self.router
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move { token.cancelled().await })
+        .await
+    }
+}
+
+/// Accept connections until `token` is cancelled, then wait for open ones to
+/// finish their requests.
+async fn serve_tls(
+    listener: TcpListener,
+    router: axum::Router<()>,
+    tls: Arc<TlsTerminator>,
+    token: CancellationToken,
+) {
+    let connections = TaskTracker::new();
+
+    loop {
+        let (tcp, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!("failed to accept connection: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = token.cancelled() => break,
+        };
+
+        connections.spawn(serve_tls_connection(
+            tcp,
+            remote_addr,
+            router.clone(),
+            tls.clone(),
+            token.clone(),
+        ));
+    }
+
+    connections.close();
+    connections.wait().await;
+}
+
+async fn serve_tls_connection(
+    tcp: TcpStream,
+    remote_addr: SocketAddr,
+    router: axum::Router<()>,
+    tls: Arc<TlsTerminator>,
+    token: CancellationToken,
+) {
+    let stream =
+        match tokio::time::timeout(tls.handshake_timeout(), tls.acceptor().accept(tcp)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                tracing::debug!(%remote_addr, "TLS handshake failed: {err}");
+                return;
+            }
+            Err(_) => {
+                tracing::debug!(%remote_addr, "TLS handshake timed out");
+                return;
+            }
+        };
+
+    // Presented client certificates were verified during the handshake.
+    let client_cert = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(VerifiedClientCert::from_der);
+
+    // What `into_make_service_with_connect_info` provides on plain connections.
+    let service = router.map_request(move |req: Request<Incoming>| {
+        let mut req = req.map(Body::new);
+        req.extensions_mut().insert(ConnectInfo(remote_addr));
+        if let Some(client_cert) = &client_cert {
+            req.extensions_mut().insert(client_cert.clone());
+        }
+        req
+    });
+
+    let connection = hyper::server::conn::http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service))
+        .with_upgrades();
+    let mut connection = std::pin::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = token.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        tracing::debug!(%remote_addr, "connection error: {err}");
     }
 }
 
@@ -99,6 +312,22 @@ async fn health_check() -> impl IntoResponse {
         .to_string()
 }
 
+/// Fails while draining so the load balancer stops sending new traffic.
+async fn readiness_check(State(state): State<TunnelState>) -> Response {
+    if state.registry().is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response();
+    }
+
+    (StatusCode::OK, "ready").into_response()
+}
+
+async fn metrics(State(state): State<TunnelState>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        telemetry::render(state.registry()),
+    )
+}
+
 fn build_cors(origins: &[String]) -> CorsLayer {
     if origins.is_empty() {
         return CorsLayer::permissive();
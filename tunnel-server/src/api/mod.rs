@@ -1,13 +1,19 @@
+use std::time::Duration;
+
 use anyhow::{Context, Result};
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
 use crate::config::AppConfig;
 use crate::redis::RedisClient;
 use crate::state::TunnelState;
+use crate::telemetry;
 
 pub mod controllers;
 pub mod endpoint;
 
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 pub async fn http_service(config: AppConfig, token: CancellationToken) -> Result<()> {
     tracing::info!("connecting to Redis...");
     let redis_client = RedisClient::new(&config.redis.url)
@@ -15,19 +21,120 @@ pub async fn http_service(config: AppConfig, token: CancellationToken) -> Result
         .context("failed to connect to Redis")?;
     tracing::info!("Redis connected");
 
-    tracing::info!(listen_addr = %config.api.listen_addr, "tunnel-server starting...");
+    tracing::info!(
+        listen_addr = %config.api.listen_addr,
+        internal_listen_addr = %config.api.internal_listen_addr,
+        tls = config.api.tls.enabled,
+        "tunnel-server starting..."
+    );
+
+    telemetry::install()?;
+
+    // `token` only starts the drain; `stop` tears down the listener and device sessions.
+    let stop = CancellationToken::new();
 
     let state = TunnelState::builder()
         .with_config(config)
-        .with_shutdown(token.clone())
+        .with_shutdown(stop.clone())
         .with_redis_client(redis_client)
         .build()?;
 
     let endpoint = state.bind_endpoint().await?;
+    let internal_endpoint = state.bind_internal_endpoint().await?;
+
+    if let Some(session_tokens) = state.session_tokens() {
+        tokio::spawn(
+            session_tokens
+                .clone()
+                .run_revocation_sync(state.redis().clone(), stop.clone()),
+        );
+    }
+
+    // Runs on `stop`, after the drain, so records of drained streams are written.
+    let audit = state
+        .auditor()
+        .map(|auditor| tokio::spawn(auditor.clone().run(stop.clone())));
+
+    // Runs on `stop` too, and waits for the devices it closes to be recorded offline.
+    let presence = state
+        .presence()
+        .map(|presence| tokio::spawn(presence.clone().run(state.registry().clone(), stop.clone())));
+
+    if let Some(tls) = state.tls() {
+        tokio::spawn(tls.clone().run_reload(stop.clone()));
+    }
+
+    let bandwidth = state
+        .bandwidth()
+        .map(|bandwidth| tokio::spawn(bandwidth.clone().run_sync(stop.clone())));
+
+    if let Some(cluster) = state.cluster() {
+        tracing::info!(node_id = %cluster.node_id(), "cluster mode enabled");
+        tokio::spawn(
+            cluster
+                .clone()
+                .run_heartbeat(state.registry().clone(), stop.clone()),
+        );
+        tokio::spawn(
+            cluster
+                .clone()
+                .run_closed_sessions(state.registry().clone(), stop.clone()),
+        );
+    }
 
-    endpoint.serve(token).await?;
+    let mut serve = tokio::spawn(endpoint.serve(stop.clone()));
+    let internal = tokio::spawn(internal_endpoint.serve(stop.clone()));
+
+    tokio::select! {
+        result = &mut serve => return Ok(result??),
+        _ = token.cancelled() => {}
+    }
+
+    drain(&state).await;
+
+    stop.cancel();
+    serve.await??;
+    internal.await??;
+
+    if let Some(audit) = audit {
+        audit.await?;
+    }
+    if let Some(presence) = presence {
+        presence.await?;
+    }
+    if let Some(bandwidth) = bandwidth {
+        bandwidth.await?;
+    }
 
     tracing::info!("tunnel-server stopped");
 
     Ok(())
 }
+
+/// Send `GoAway` to every device and wait for in-flight streams to finish,
+/// up to `drain_timeout_secs`.
+async fn drain(state: &TunnelState) {
+    let registry = state.registry();
+    registry.start_drain();
+
+    tracing::info!(
+        devices = registry.device_count(),
+        streams = registry.active_streams(),
+        "tunnel-server draining..."
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(state.api_config().drain_timeout_secs);
+    while registry.active_streams() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    let remaining = registry.active_streams();
+    if remaining > 0 {
+        tracing::warn!(
+            streams = remaining,
+            "drain timeout reached, closing streams"
+        );
+    } else {
+        tracing::info!("drain complete");
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::{AuditRecord, AuditSink};
+
+/// Appends one JSON object per line to a local file.
+pub struct FileSink {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl FileSink {
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open audit log {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(tokio::fs::File::from_std(file)),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+
+            self.file
+                .lock()
+                .await
+                .write_all(&line)
+                .await
+                .with_context(|| format!("failed to append to {}", self.path.display()))
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+
+use super::{AuditRecord, AuditSink};
+use crate::telemetry::AUDIT_RECORDS_DROPPED_TOTAL;
+
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Produces JSON records keyed by device ID, so one device's records stay ordered.
+pub struct KafkaSink {
+    topic: String,
+    producer: FutureProducer,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str, message_timeout_ms: Option<u32>) -> Result<Self> {
+        let mut client_config = rdkafka::config::ClientConfig::new();
+        client_config.set("bootstrap.servers", brokers);
+
+        if let Some(message_timeout_ms) = message_timeout_ms {
+            client_config.set("message.timeout.ms", message_timeout_ms.to_string());
+        }
+
+        Ok(Self {
+            topic: topic.to_owned(),
+            producer: client_config.create()?,
+        })
+    }
+}
+
+impl AuditSink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    /// Hands the record to the producer queue; delivery is awaited in the background.
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let key = record.device_id.to_string();
+            let payload = serde_json::to_string(record)?;
+
+            let delivery = self
+                .producer
+                .send_result(FutureRecord::to(&self.topic).key(&key).payload(&payload))
+                .map_err(|(err, _)| err)?;
+
+            let stream_id = record.stream_id;
+            tokio::spawn(async move {
+                if let Ok(Err((err, _))) = delivery.await {
+                    metrics::counter!(AUDIT_RECORDS_DROPPED_TOTAL, "reason" => "sink_error")
+                        .increment(1);
+                    tracing::warn!(%stream_id, "kafka audit delivery failed: {err}");
+                }
+            });
+
+            Ok(())
+        })
+    }
+}
+
+impl Drop for KafkaSink {
+    fn drop(&mut self) {
+        tracing::info!("flushing kafka audit producer");
+        self.producer.flush(FLUSH_TIMEOUT).ok();
+    }
+}
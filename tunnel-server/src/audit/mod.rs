@@ -0,0 +1,294 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use axum::response::Response;
+use futures_util::future::BoxFuture;
+use nexus_utils::time::now_sec;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+pub use self::file::FileSink;
+pub use self::kafka::KafkaSink;
+
+use crate::config::{AuditConfig, AuditSinkConfig};
+use crate::error_page::tunnel_error;
+use crate::registry::StreamStats;
+use crate::telemetry::AUDIT_RECORDS_DROPPED_TOTAL;
+
+mod file;
+mod kafka;
+
+const REDACTED: &str = "REDACTED";
+
+/// Who accessed which device, what they did and how it ended — one per stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// Unix timestamp of the stream start, in seconds.
+    pub timestamp: u64,
+    pub stream_id: Uuid,
+    pub user_id: String,
+    pub device_id: Uuid,
+    pub method: String,
+    pub path: String,
+    /// Status returned to the client, if the stream got that far.
+    pub status: Option<u16>,
+    pub bytes_to_device: u64,
+    pub bytes_from_device: u64,
+    pub duration_ms: u64,
+    pub outcome: AuditOutcome,
+    /// Why the stream was rejected, cancelled or failed.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    /// The response was delivered in full.
+    Completed,
+    /// The server refused the stream before the device answered.
+    Rejected,
+    /// The client went away or the stream was cancelled on the server.
+    Cancelled,
+    /// The device failed, timed out or disconnected.
+    Failed,
+}
+
+/// Destination for audit records.
+pub trait AuditSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn write<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<()>>;
+}
+
+// ── Auditor ───────────────────────────────────────────────────────────────
+
+/// Queues audit records from the request path and writes them to every sink
+/// from a background task, so a slow sink never stalls a stream.
+pub struct Auditor {
+    tx: mpsc::Sender<AuditRecord>,
+    rx: Mutex<Option<mpsc::Receiver<AuditRecord>>>,
+    sinks: Vec<Box<dyn AuditSink>>,
+    redact_query: bool,
+}
+
+impl Auditor {
+    /// Build the configured sinks. Returns `None` when auditing is disabled.
+    pub fn new(config: &AuditConfig) -> Result<Option<Self>> {
+        if config.sinks.is_empty() {
+            return Ok(None);
+        }
+
+        let mut sinks: Vec<Box<dyn AuditSink>> = Vec::with_capacity(config.sinks.len());
+        for sink in &config.sinks {
+            match sink {
+                AuditSinkConfig::File { path } => sinks.push(Box::new(FileSink::new(path)?)),
+                AuditSinkConfig::Kafka {
+                    brokers,
+                    topic,
+                    message_timeout_ms,
+                } => sinks.push(Box::new(KafkaSink::new(
+                    brokers,
+                    topic,
+                    *message_timeout_ms,
+                )?)),
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        Ok(Some(Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            sinks,
+            redact_query: config.redact_query,
+        }))
+    }
+
+    /// Start auditing a stream; the record is emitted when the returned guard drops.
+    pub fn start(
+        self: &Arc<Self>,
+        stream_id: Uuid,
+        user_id: &str,
+        device_id: Uuid,
+        method: &str,
+        path_and_query: &str,
+    ) -> StreamAudit {
+        let path = if self.redact_query {
+            redact_query(path_and_query)
+        } else {
+            path_and_query.to_owned()
+        };
+
+        StreamAudit {
+            inner: Some((
+                self.clone(),
+                AuditRecord {
+                    timestamp: now_sec(),
+                    stream_id,
+                    user_id: user_id.to_owned(),
+                    device_id,
+                    method: method.to_owned(),
+                    path,
+                    status: None,
+                    bytes_to_device: 0,
+                    bytes_from_device: 0,
+                    duration_ms: 0,
+                    outcome: AuditOutcome::Completed,
+                    reason: None,
+                },
+            )),
+            started: Instant::now(),
+            stats: None,
+            finished: false,
+        }
+    }
+
+    fn submit(&self, record: AuditRecord) {
+        match self.tx.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(record)) => {
+                metrics::counter!(AUDIT_RECORDS_DROPPED_TOTAL, "reason" => "queue_full")
+                    .increment(1);
+                tracing::warn!(stream_id = %record.stream_id, "audit queue full, dropping record");
+            }
+            Err(TrySendError::Closed(_)) => {
+                metrics::counter!(AUDIT_RECORDS_DROPPED_TOTAL, "reason" => "stopped").increment(1);
+            }
+        }
+    }
+
+    /// Write queued records to the sinks until `token` is cancelled, then flush
+    /// whatever is still queued.
+    pub async fn run(self: Arc<Self>, token: CancellationToken) {
+        let Some(mut rx) = self.rx.lock().expect("audit receiver lock").take() else {
+            tracing::error!("audit writer already running");
+            return;
+        };
+
+        loop {
+            let record = tokio::select! {
+                _ = token.cancelled() => break,
+                record = rx.recv() => record,
+            };
+            let Some(record) = record else {
+                break;
+            };
+            self.write(&record).await;
+        }
+
+        rx.close();
+        while let Ok(record) = rx.try_recv() {
+            self.write(&record).await;
+        }
+    }
+
+    async fn write(&self, record: &AuditRecord) {
+        for sink in &self.sinks {
+            if let Err(err) = sink.write(record).await {
+                metrics::counter!(AUDIT_RECORDS_DROPPED_TOTAL, "reason" => "sink_error")
+                    .increment(1);
+                tracing::warn!(sink = sink.name(), stream_id = %record.stream_id, "failed to write audit record: {err:#}");
+            }
+        }
+    }
+}
+
+// ── Stream guard ──────────────────────────────────────────────────────────
+
+/// Collects the outcome of one stream and submits its record on drop, so every
+/// exit path of the proxy is audited.
+pub struct StreamAudit {
+    inner: Option<(Arc<Auditor>, AuditRecord)>,
+    started: Instant,
+    stats: Option<Arc<StreamStats>>,
+    finished: bool,
+}
+
+impl StreamAudit {
+    /// A guard that records nothing, used when auditing is disabled.
+    pub fn disabled() -> Self {
+        Self {
+            inner: None,
+            started: Instant::now(),
+            stats: None,
+            finished: false,
+        }
+    }
+
+    /// Read byte counts from the registered stream.
+    pub fn set_stats(&mut self, stats: Arc<StreamStats>) {
+        self.stats = Some(stats);
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        if let Some((_, record)) = &mut self.inner {
+            record.status = Some(status);
+        }
+    }
+
+    /// Record how the stream ended. Only the first call counts.
+    pub fn finish(&mut self, outcome: AuditOutcome, reason: Option<String>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        if let Some((_, record)) = &mut self.inner {
+            record.outcome = outcome;
+            record.reason = reason;
+        }
+    }
+
+    /// Record a rejection and turn it into the response sent to the client.
+    pub fn reject(mut self, status: StatusCode, message: &'static str) -> Response {
+        self.set_status(status.as_u16());
+        self.finish(AuditOutcome::Rejected, Some(message.to_owned()));
+        tunnel_error(status, message)
+    }
+
+    /// Record a device failure and turn it into the response sent to the client.
+    pub fn fail(mut self, status: StatusCode, message: &'static str) -> Response {
+        self.set_status(status.as_u16());
+        self.finish(AuditOutcome::Failed, Some(message.to_owned()));
+        tunnel_error(status, message)
+    }
+}
+
+impl Drop for StreamAudit {
+    fn drop(&mut self) {
+        let Some((auditor, mut record)) = self.inner.take() else {
+            return;
+        };
+
+        record.duration_ms = self.started.elapsed().as_millis() as u64;
+        if let Some(stats) = &self.stats {
+            record.bytes_to_device = stats.bytes_to_device();
+            record.bytes_from_device = stats.bytes_from_device();
+        }
+
+        auditor.submit(record);
+    }
+}
+
+/// Keep query parameter names but hide their values, which often carry secrets.
+fn redact_query(path_and_query: &str) -> String {
+    let Some((path, query)) = path_and_query.split_once('?') else {
+        return path_and_query.to_owned();
+    };
+
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) => format!("{name}={REDACTED}"),
+            None if pair.is_empty() => String::new(),
+            None => REDACTED.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    format!("{path}?{query}")
+}
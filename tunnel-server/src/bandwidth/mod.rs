@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, ensure};
+use dashmap::DashMap;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::config::BandwidthConfig;
+use crate::redis::RedisClient;
+
+const CAP_REACHED: &str = "monthly data cap reached";
+
+/// Bandwidth settings for a device or user stored in Redis, replacing the defaults
+/// from `BandwidthConfig`. Zero means unlimited.
+#[derive(Debug, Default, Clone, Copy)]
+struct Override {
+    bytes_per_sec: Option<u64>,
+    monthly_cap_bytes: Option<u64>,
+}
+
+impl Override {
+    fn parse(fields: &HashMap<String, String>) -> Self {
+        let field = |name: &str| fields.get(name).and_then(|value| value.parse().ok());
+        Self {
+            bytes_per_sec: field("bytes_per_sec"),
+            monthly_cap_bytes: field("monthly_cap_bytes"),
+        }
+    }
+}
+
+struct Cached<T> {
+    value: T,
+    fetched: Instant,
+}
+
+// ── Limits ────────────────────────────────────────────────────────────────
+
+/// Token buckets shared by all streams of a device and of a user, plus the
+/// monthly byte usage of each device, synced through Redis.
+pub struct Bandwidth {
+    config: BandwidthConfig,
+    redis: RedisClient,
+    device_buckets: DashMap<Uuid, Arc<TokenBucket>>,
+    user_buckets: DashMap<String, Arc<TokenBucket>>,
+    device_overrides: DashMap<Uuid, Cached<Override>>,
+    user_overrides: DashMap<String, Cached<Override>>,
+    usage: DashMap<Uuid, Arc<MonthlyUsage>>,
+}
+
+impl Bandwidth {
+    pub fn new(config: BandwidthConfig, redis: RedisClient) -> Self {
+        Self {
+            config,
+            redis,
+            device_buckets: DashMap::new(),
+            user_buckets: DashMap::new(),
+            device_overrides: DashMap::new(),
+            user_overrides: DashMap::new(),
+            usage: DashMap::new(),
+        }
+    }
+
+    /// Limiter for a new stream of `user_id` on `device_id`.
+    /// Fails when the device has used up its monthly cap.
+    pub async fn stream_limiter(&self, device_id: Uuid, user_id: &str) -> Result<StreamLimiter> {
+        let device = self.device_override(device_id).await;
+        let user = if user_id.is_empty() {
+            Override::default()
+        } else {
+            self.user_override(user_id).await
+        };
+
+        let device_rate = effective(device.bytes_per_sec, self.config.device_bytes_per_sec);
+        let user_rate = effective(user.bytes_per_sec, self.config.user_bytes_per_sec);
+        let monthly_cap = effective(
+            device.monthly_cap_bytes,
+            self.config.device_monthly_cap_bytes,
+        );
+
+        let usage = match monthly_cap {
+            Some(cap) => {
+                let usage = self.usage(device_id).await;
+                usage.cap.store(cap, Ordering::Relaxed);
+                ensure!(!usage.exhausted(), CAP_REACHED);
+                Some(usage)
+            }
+            None => None,
+        };
+
+        let burst_secs = self.config.burst_secs;
+        Ok(StreamLimiter {
+            device: device_rate
+                .map(|rate| bucket(&self.device_buckets, device_id, rate, burst_secs)),
+            user: user_rate
+                .map(|rate| bucket(&self.user_buckets, user_id.to_owned(), rate, burst_secs)),
+            usage,
+        })
+    }
+
+    async fn device_override(&self, device_id: Uuid) -> Override {
+        if let Some(cached) = self.device_overrides.get(&device_id)
+            && cached.fetched.elapsed() < self.override_ttl()
+        {
+            return cached.value;
+        }
+
+        let value = self.fetch_override(&format!("device:{device_id}")).await;
+        self.device_overrides.insert(
+            device_id,
+            Cached {
+                value,
+                fetched: Instant::now(),
+            },
+        );
+        value
+    }
+
+    async fn user_override(&self, user_id: &str) -> Override {
+        if let Some(cached) = self.user_overrides.get(user_id)
+            && cached.fetched.elapsed() < self.override_ttl()
+        {
+            return cached.value;
+        }
+
+        let value = self.fetch_override(&format!("user:{user_id}")).await;
+        self.user_overrides.insert(
+            user_id.to_owned(),
+            Cached {
+                value,
+                fetched: Instant::now(),
+            },
+        );
+        value
+    }
+
+    /// Redis errors fall back to the configured defaults rather than failing streams.
+    async fn fetch_override(&self, subject: &str) -> Override {
+        match self.redis.get_bandwidth_override(subject).await {
+            Ok(fields) => Override::parse(&fields),
+            Err(err) => {
+                tracing::warn!(%subject, "failed to load bandwidth override: {err:#}");
+                Override::default()
+            }
+        }
+    }
+
+    async fn usage(&self, device_id: Uuid) -> Arc<MonthlyUsage> {
+        let month = current_month();
+        if let Some(usage) = self.usage.get(&device_id)
+            && usage.month == month
+        {
+            return usage.clone();
+        }
+
+        let used = match self.redis.get_bandwidth_usage(device_id, &month).await {
+            Ok(used) => used,
+            Err(err) => {
+                tracing::warn!(%device_id, "failed to load monthly usage: {err:#}");
+                0
+            }
+        };
+
+        self.usage
+            .entry(device_id)
+            .and_modify(|usage| {
+                if usage.month != month {
+                    *usage = Arc::new(MonthlyUsage::new(device_id, month.clone(), used));
+                }
+            })
+            .or_insert_with(|| Arc::new(MonthlyUsage::new(device_id, month.clone(), used)))
+            .clone()
+    }
+
+    fn override_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.override_ttl_secs)
+    }
+
+    /// Push monthly usage to Redis and drop state no stream uses anymore, until
+    /// `token` is cancelled.
+    pub async fn run_sync(self: Arc<Self>, token: CancellationToken) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.usage_sync_interval_secs));
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            self.sync_usage().await;
+            self.prune();
+        }
+
+        self.sync_usage().await;
+    }
+
+    async fn sync_usage(&self) {
+        let usages: Vec<Arc<MonthlyUsage>> = self
+            .usage
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+
+        for usage in usages {
+            let pending = usage.pending.swap(0, Ordering::AcqRel);
+            if pending == 0 {
+                continue;
+            }
+
+            match self
+                .redis
+                .add_bandwidth_usage(usage.device_id, &usage.month, pending)
+                .await
+            {
+                // Other nodes count towards the cap too.
+                Ok(total) => usage.used.store(
+                    total + usage.pending.load(Ordering::Acquire),
+                    Ordering::Release,
+                ),
+                Err(err) => {
+                    usage.pending.fetch_add(pending, Ordering::AcqRel);
+                    tracing::warn!(device_id = %usage.device_id, "failed to sync monthly usage: {err:#}");
+                }
+            }
+        }
+    }
+
+    fn prune(&self) {
+        let ttl = self.override_ttl();
+        self.device_buckets
+            .retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        self.user_buckets
+            .retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        self.device_overrides
+            .retain(|_, cached| cached.fetched.elapsed() < ttl);
+        self.user_overrides
+            .retain(|_, cached| cached.fetched.elapsed() < ttl);
+        self.usage.retain(|_, usage| {
+            Arc::strong_count(usage) > 1 || usage.pending.load(Ordering::Acquire) > 0
+        });
+    }
+}
+
+fn effective(value: Option<u64>, default: Option<u64>) -> Option<u64> {
+    value.or(default).filter(|value| *value > 0)
+}
+
+fn bucket<K>(
+    buckets: &DashMap<K, Arc<TokenBucket>>,
+    key: K,
+    rate: u64,
+    burst_secs: u64,
+) -> Arc<TokenBucket>
+where
+    K: Eq + std::hash::Hash,
+{
+    let bucket = buckets
+        .entry(key)
+        .or_insert_with(|| Arc::new(TokenBucket::new(rate, burst_secs)))
+        .clone();
+    bucket.set_rate(rate, burst_secs);
+    bucket
+}
+
+/// Calendar month in UTC, e.g. "2026-03".
+fn current_month() -> String {
+    chrono::Utc::now().format("%Y-%m").to_string()
+}
+
+// ── Stream limiter ────────────────────────────────────────────────────────
+
+/// Limits that apply to one stream. Cloning shares the underlying buckets.
+#[derive(Clone, Default)]
+pub struct StreamLimiter {
+    device: Option<Arc<TokenBucket>>,
+    user: Option<Arc<TokenBucket>>,
+    usage: Option<Arc<MonthlyUsage>>,
+}
+
+impl StreamLimiter {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Limiter that only counts monthly usage against `cap`.
+    #[cfg(test)]
+    pub fn with_monthly_cap(cap: u64) -> Self {
+        let usage = MonthlyUsage::new(Uuid::nil(), String::new(), 0);
+        usage.cap.store(cap, Ordering::Relaxed);
+        Self {
+            usage: Some(Arc::new(usage)),
+            ..Self::default()
+        }
+    }
+
+    /// Bytes counted against the monthly cap so far.
+    #[cfg(test)]
+    pub fn monthly_usage(&self) -> u64 {
+        self.usage
+            .as_ref()
+            .map_or(0, |usage| usage.used.load(Ordering::Acquire))
+    }
+
+    /// Wait until `len` body bytes may pass. Fails once the monthly cap is used up.
+    pub async fn acquire(&self, len: usize) -> Result<()> {
+        if let Some(usage) = &self.usage {
+            usage.record(len as u64)?;
+        }
+        if let Some(bucket) = &self.device {
+            bucket.acquire(len).await;
+        }
+        if let Some(bucket) = &self.user {
+            bucket.acquire(len).await;
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket that lets callers go into debt and sleep it off, so concurrent
+/// streams are served roughly in arrival order and chunks larger than the
+/// burst still pass.
+struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst_secs: u64) -> Self {
+        let burst = rate.saturating_mul(burst_secs.max(1)) as f64;
+        Self {
+            state: Mutex::new(BucketState {
+                rate: rate as f64,
+                burst,
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    fn set_rate(&self, rate: u64, burst_secs: u64) {
+        let mut state = self.state.lock().expect("token bucket lock");
+        state.rate = rate as f64;
+        state.burst = rate.saturating_mul(burst_secs.max(1)) as f64;
+    }
+
+    async fn acquire(&self, len: usize) {
+        let wait = {
+            let mut state = self.state.lock().expect("token bucket lock");
+            let now = Instant::now();
+            let refill = now.duration_since(state.updated).as_secs_f64() * state.rate;
+            state.tokens = (state.tokens + refill).min(state.burst);
+            state.updated = now;
+            state.tokens -= len as f64;
+
+            if state.tokens >= 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64(-state.tokens / state.rate)
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Body bytes a device used this month: the last total read from Redis plus
+/// what this node counted since, of which `pending` is not yet in Redis.
+struct MonthlyUsage {
+    device_id: Uuid,
+    month: String,
+    cap: AtomicU64,
+    used: AtomicU64,
+    pending: AtomicU64,
+}
+
+impl MonthlyUsage {
+    fn new(device_id: Uuid, month: String, used: u64) -> Self {
+        Self {
+            device_id,
+            month,
+            cap: AtomicU64::new(u64::MAX),
+            used: AtomicU64::new(used),
+            pending: AtomicU64::new(0),
+        }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.used.load(Ordering::Acquire) >= self.cap.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, len: u64) -> Result<()> {
+        ensure!(!self.exhausted(), CAP_REACHED);
+        self.used.fetch_add(len, Ordering::AcqRel);
+        self.pending.fetch_add(len, Ordering::AcqRel);
+        Ok(())
+    }
+}
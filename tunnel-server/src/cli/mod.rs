@@ -5,9 +5,11 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use nexus_utils as utils;
 use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
 use crate::api;
-use crate::config::AppConfig;
+use crate::config::{self, AppConfig};
+use crate::state;
 
 #[derive(Parser)]
 #[clap(name = "tunnel-server")]
@@ -28,12 +30,20 @@ impl App {
 enum Cmd {
     /// Start service.
     Run(CmdRun),
+
+    /// Issue a device token for `tunnel-client`.
+    DeviceToken(CmdDeviceToken),
+
+    /// Print the key `tunnel-client` verifies identity headers with.
+    IdentityKey(CmdIdentityKey),
 }
 
 impl Cmd {
     fn run(self) -> Result<()> {
         match self {
             Cmd::Run(cmd) => cmd.run(),
+            Cmd::DeviceToken(cmd) => cmd.run(),
+            Cmd::IdentityKey(cmd) => cmd.run(),
         }
     }
 }
@@ -74,6 +84,41 @@ impl CmdRun {
     }
 }
 
+#[derive(Parser)]
+struct CmdDeviceToken {
+    /// Device ID the token is issued for.
+    #[clap(short, long)]
+    device_id: Uuid,
+
+    /// Token lifetime in seconds.
+    #[clap(short, long, default_value_t = 365 * 24 * 3600)]
+    ttl: u64,
+}
+
+impl CmdDeviceToken {
+    fn run(self) -> Result<()> {
+        let secret = config::device_token_secret_from_env()?;
+        let token = state::issue_device_token(&secret, self.device_id, self.ttl)?;
+        println!("{token}");
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct CmdIdentityKey {
+    /// Device ID the key is derived for.
+    #[clap(short, long)]
+    device_id: Uuid,
+}
+
+impl CmdIdentityKey {
+    fn run(self) -> Result<()> {
+        let secret = config::identity_header_secret_from_env()?;
+        println!("{}", utils::identity::device_key(&secret, self.device_id));
+        Ok(())
+    }
+}
+
 fn version_string() -> &'static str {
     static STRING: OnceLock<String> = OnceLock::new();
     STRING.get_or_init(|| {
@@ -81,5 +126,5 @@ fn version_string() -> &'static str {
     })
 }
 
-static TUNNEL_SERVER_VERSION: &str = env!("TUNNEL_SERVER_VERSION");
+pub static TUNNEL_SERVER_VERSION: &str = env!("TUNNEL_SERVER_VERSION");
 static TUNNEL_SERVER_RUSTC_VERSION: &str = env!("TUNNEL_SERVER_RUSTC_VERSION");
@@ -0,0 +1,220 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use axum::http::header::HeaderName;
+use futures_util::StreamExt;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::config::ClusterConfig;
+use crate::redis::RedisClient;
+use crate::registry::{DeviceRegistry, SESSION_REVOKED_MESSAGE};
+
+/// Set on requests forwarded between nodes; carries the sender's node ID.
+/// A node never forwards a request that already has it.
+pub static FORWARDED_BY_HEADER: HeaderName = HeaderName::from_static("x-nexus-forwarded-by");
+
+const SUBSCRIPTION_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Device-to-node ownership shared by all tunnel-server replicas through Redis.
+pub struct Cluster {
+    node_id: String,
+    advertise_url: String,
+    forwarded_by: HeaderValue,
+    config: ClusterConfig,
+    redis: RedisClient,
+    http: reqwest::Client,
+}
+
+impl Cluster {
+    /// Other nodes reach this one on `internal_listen_addr`, in plain HTTP even
+    /// when the public listener terminates TLS.
+    pub fn new(
+        config: &ClusterConfig,
+        internal_listen_addr: SocketAddr,
+        redis: RedisClient,
+    ) -> Result<Self> {
+        let node_id = match &config.node_id {
+            Some(node_id) => node_id.clone(),
+            None => std::env::var("HOSTNAME")
+                .context("cluster.node_id not set and HOSTNAME unavailable")?,
+        };
+
+        let advertise_url = match &config.advertise_url {
+            Some(url) => url.trim_end_matches('/').to_owned(),
+            None => {
+                let pod_ip = std::env::var("POD_IP")
+                    .context("cluster.advertise_url not set and POD_IP unavailable")?;
+                format!("http://{pod_ip}:{}", internal_listen_addr.port())
+            }
+        };
+
+        let forwarded_by = HeaderValue::from_str(&node_id).context("invalid cluster node ID")?;
+
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .no_proxy()
+            .build()
+            .context("failed to build cluster HTTP client")?;
+
+        Ok(Self {
+            node_id,
+            advertise_url,
+            forwarded_by,
+            config: config.clone(),
+            redis,
+            http,
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn forwarded_by(&self) -> &HeaderValue {
+        &self.forwarded_by
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Record this node as the holder of a freshly connected device.
+    pub async fn claim_device(&self, device_id: Uuid) -> Result<()> {
+        self.redis
+            .claim_device_node(
+                &device_id.to_string(),
+                &self.node_id,
+                self.config.ownership_ttl_secs,
+            )
+            .await
+    }
+
+    /// Drop the ownership record unless another node has already taken the device over.
+    pub async fn release_device(&self, device_id: Uuid) -> Result<()> {
+        self.redis
+            .release_device_node(&device_id.to_string(), &self.node_id)
+            .await
+    }
+
+    /// Whether any node holds a connection for the device.
+    pub async fn is_online(&self, device_id: Uuid) -> Result<bool> {
+        let node_id = self.redis.get_device_node(&device_id.to_string()).await?;
+        Ok(node_id.is_some())
+    }
+
+    /// Internal URL of the node holding the device, if it is another node.
+    pub async fn owner_url(&self, device_id: Uuid) -> Result<Option<String>> {
+        let Some(node_id) = self.redis.get_device_node(&device_id.to_string()).await? else {
+            return Ok(None);
+        };
+
+        if node_id == self.node_id {
+            return Ok(None);
+        }
+
+        self.redis.get_node_url(&node_id).await
+    }
+
+    /// Refresh this node's URL and the ownership of every device connected to it
+    /// until `token` is cancelled.
+    pub async fn run_heartbeat(
+        self: Arc<Self>,
+        registry: Arc<DeviceRegistry>,
+        token: CancellationToken,
+    ) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.heartbeat_interval_secs));
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            if let Err(err) = self.heartbeat(&registry).await {
+                tracing::warn!(node_id = %self.node_id, "cluster heartbeat failed: {err:#}");
+            }
+        }
+    }
+
+    /// Cancel the streams of sessions closed on any node until `token` is cancelled.
+    pub async fn run_closed_sessions(
+        self: Arc<Self>,
+        registry: Arc<DeviceRegistry>,
+        token: CancellationToken,
+    ) {
+        loop {
+            match self.watch_closed_sessions(&registry, &token).await {
+                Ok(()) => break,
+                Err(err) => tracing::warn!("closed session subscription failed: {err:#}"),
+            }
+
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep(SUBSCRIPTION_RETRY_DELAY) => {}
+            }
+        }
+    }
+
+    async fn watch_closed_sessions(
+        &self,
+        registry: &DeviceRegistry,
+        token: &CancellationToken,
+    ) -> Result<()> {
+        let mut messages = self.redis.subscribe_closed_sessions().await?;
+
+        loop {
+            let message = tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                message = messages.next() => message.context("closed session subscription ended")?,
+            };
+
+            let payload: String = message.get_payload()?;
+            let Some((device_id, session)) = payload
+                .split_once(':')
+                .and_then(|(device_id, session)| Some((device_id.parse::<Uuid>().ok()?, session)))
+            else {
+                tracing::warn!(%payload, "invalid closed session message");
+                continue;
+            };
+
+            if let Some(device) = registry.get(device_id) {
+                let aborted = device
+                    .abort_session_streams(session, SESSION_REVOKED_MESSAGE)
+                    .await;
+                if aborted > 0 {
+                    tracing::info!(%device_id, streams = aborted, "cancelled streams of revoked session");
+                }
+            }
+        }
+    }
+
+    async fn heartbeat(&self, registry: &DeviceRegistry) -> Result<()> {
+        let ttl = self.config.ownership_ttl_secs;
+
+        self.redis
+            .store_node_url(&self.node_id, &self.advertise_url, ttl)
+            .await?;
+
+        for (device_id, session) in registry.sessions() {
+            // A draining session's device is reconnecting to another node.
+            if session.is_draining() {
+                continue;
+            }
+
+            let owned = self
+                .redis
+                .refresh_device_node(&device_id.to_string(), &self.node_id, ttl)
+                .await?;
+            if !owned {
+                tracing::warn!(%device_id, node_id = %self.node_id, "device is held by another node");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
 use anyhow::Context;
 use base64::Engine as _;
@@ -11,6 +12,9 @@ use zeroize::Zeroize;
 pub struct AppConfig {
     pub api: ApiConfig,
     pub redis: RedisConfig,
+    pub cluster: ClusterConfig,
+    pub audit: AuditConfig,
+    pub presence: PresenceConfig,
     pub logger: LoggerConfig,
 }
 
@@ -19,14 +23,37 @@ pub struct AppConfig {
 pub struct ApiConfig {
     /// TCP socket address to listen for incoming connections.
     pub listen_addr: SocketAddr,
+    /// Serve HTTPS on `listen_addr` without a reverse proxy in front.
+    pub tls: TlsConfig,
+    /// Plain HTTP address for metrics and health checks. Keep it off the public
+    /// ingress: the metrics name every connected device.
+    pub internal_listen_addr: SocketAddr,
     /// Scheme used to construct tunnel session URLs: "http" or "https".
     pub tunnel_scheme: String,
     /// Domain used to construct tunnel session URLs.
     /// Local:  "localhost:8001"  → http://{token}.localhost:8001/
     /// Prod:   "tunnel.example.com" → https://{token}.tunnel.example.com/
+    /// With `path` routing: https://tunnel.example.com/t/{token}/
     pub tunnel_domain: String,
-    /// Session token TTL in seconds.
+    /// Where the session token goes in tunnel URLs.
+    pub tunnel_routing: TunnelRouting,
+    /// With `path` routing, prefix absolute links in HTML responses with the
+    /// session path so that pages keep working without a base URL.
+    pub rewrite_html_links: bool,
+    /// Tell devices who is browsing them through signed `x-nexus-*` identity
+    /// headers. Needs `IDENTITY_HEADER_SECRET`.
+    pub forward_identity: bool,
+    /// How device response headers are adapted to the session origin.
+    pub response_rewrite: ResponseRewriteConfig,
+    /// Pages shown to browsers when the tunnel cannot reach the device.
+    pub error_pages: ErrorPagesConfig,
+    /// Maximum session lifetime in seconds, however active the session is.
     pub session_ttl: u64,
+    /// Seconds a session stays valid without proxied requests; every request
+    /// extends it, up to `session_ttl`. None = sessions last the full `session_ttl`.
+    pub session_idle_timeout_secs: Option<u64>,
+    /// How tunnel session tokens are issued and checked.
+    pub session_token_mode: SessionTokenMode,
     /// Maximum number of concurrent active streams per connected device.
     pub max_concurrent_streams_per_device: usize,
     /// Maximum frame chunk size for request and response bodies.
@@ -35,26 +62,233 @@ pub struct ApiConfig {
     pub stream_channel_capacity: usize,
     /// Maximum seconds to wait for the first response head frame.
     pub response_head_timeout_secs: u64,
+    /// Maximum seconds to wait for the device `Hello` frame after connect.
+    pub handshake_timeout_secs: u64,
+    /// Seconds between WebSocket pings sent to each connected device.
+    pub ping_interval_secs: u64,
+    /// Seconds a device may leave a ping unanswered, with no other traffic,
+    /// before its connection is considered dead and dropped.
+    pub pong_timeout_secs: u64,
+    /// Maximum seconds to let in-flight streams finish after a shutdown signal.
+    pub drain_timeout_secs: u64,
     /// CORS allowed origins. Empty = permissive (all origins allowed).
     pub cors_origins: Vec<String>,
+    /// Rate limits and monthly caps on tunnelled body bytes.
+    pub bandwidth: BandwidthConfig,
+    /// Size and time limits on individual proxied requests.
+    pub request_limits: RequestLimitsConfig,
 }
 
 impl Default for ApiConfig {
     fn default() -> Self {
         Self {
             listen_addr: (Ipv4Addr::UNSPECIFIED, 8001).into(),
+            tls: TlsConfig::default(),
+            internal_listen_addr: (Ipv4Addr::UNSPECIFIED, 8002).into(),
             tunnel_scheme: "http".to_owned(),
             tunnel_domain: "localhost:8001".to_owned(),
+            tunnel_routing: TunnelRouting::default(),
+            rewrite_html_links: false,
+            forward_identity: false,
+            response_rewrite: ResponseRewriteConfig::default(),
+            error_pages: ErrorPagesConfig::default(),
             session_ttl: 3600,
+            session_idle_timeout_secs: None,
+            session_token_mode: SessionTokenMode::default(),
             max_concurrent_streams_per_device: 64,
             max_chunk_size_bytes: 64 * 1024,
             stream_channel_capacity: 16,
             response_head_timeout_secs: 30,
+            handshake_timeout_secs: 10,
+            ping_interval_secs: 15,
+            pong_timeout_secs: 30,
+            drain_timeout_secs: 30,
             cors_origins: vec![],
+            bandwidth: BandwidthConfig::default(),
+            request_limits: RequestLimitsConfig::default(),
         }
     }
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Terminate TLS on `listen_addr`. Set `tunnel_scheme` to "https" to match.
+    pub enabled: bool,
+    /// PEM certificate chain. With `subdomain` routing it must also cover
+    /// `*.{tunnel_domain}`, e.g. a wildcard certificate.
+    pub cert_path: PathBuf,
+    /// PEM private key of the certificate (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: PathBuf,
+    /// PEM CA certificates device client certificates must chain to. When set,
+    /// `/device/connect` refuses connections without a valid client certificate
+    /// naming the device ID as a DNS or URI subject alternative name or common name.
+    pub device_client_ca_path: Option<PathBuf>,
+    /// Seconds between checks of the certificate, key and CA files; changed files
+    /// are loaded for new connections without a restart.
+    pub reload_interval_secs: u64,
+    /// Maximum seconds a client may take to complete the TLS handshake.
+    pub handshake_timeout_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: PathBuf::from("tls/tls.crt"),
+            key_path: PathBuf::from("tls/tls.key"),
+            device_client_ca_path: None,
+            reload_interval_secs: 30,
+            handshake_timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelRouting {
+    /// `{token}.{tunnel_domain}`; needs wildcard DNS and a wildcard certificate.
+    #[default]
+    Subdomain,
+    /// `{tunnel_domain}/t/{token}/` on a single host.
+    Path,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseRewriteConfig {
+    /// Origins device web servers refer to themselves by, e.g. "http://192.168.1.10".
+    /// URLs on these origins are mapped to the session origin.
+    pub device_origins: Vec<String>,
+    /// Also map URLs on loopback, private-network and `.local` hosts.
+    pub map_private_origins: bool,
+    /// Drop the `Domain` attribute of cookies so they stick to the session host.
+    pub strip_cookie_domain: bool,
+    /// Add or remove the `Secure` attribute of cookies to match `tunnel_scheme`.
+    pub match_cookie_secure: bool,
+    /// What to do with `Content-Security-Policy` headers.
+    pub content_security_policy: CspRewrite,
+    /// Response headers dropped entirely, case-insensitive.
+    pub strip_headers: Vec<String>,
+}
+
+impl Default for ResponseRewriteConfig {
+    fn default() -> Self {
+        Self {
+            device_origins: vec![],
+            map_private_origins: true,
+            strip_cookie_domain: true,
+            match_cookie_secure: true,
+            content_security_policy: CspRewrite::default(),
+            strip_headers: vec!["strict-transport-security".to_owned(), "alt-svc".to_owned()],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CspRewrite {
+    /// Pass the policy through unchanged.
+    Keep,
+    /// Replace device-local sources with the session origin.
+    #[default]
+    Rewrite,
+    /// Drop the policy.
+    Strip,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorPagesConfig {
+    /// Directory with HTML templates: `{status}.html` for one status code and
+    /// `default.html` for the rest. Unset = built-in page.
+    pub template_dir: Option<PathBuf>,
+    /// Seconds between device status checks on pages that retry on their own.
+    pub retry_interval_secs: u64,
+}
+
+impl Default for ErrorPagesConfig {
+    fn default() -> Self {
+        Self {
+            template_dir: None,
+            retry_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Enforce limits and caps. Per-device and per-user overrides are read from the
+    /// Redis hashes `bandwidth:device:{id}` and `bandwidth:user:{id}` (fields
+    /// `bytes_per_sec` and `monthly_cap_bytes`, 0 = unlimited).
+    pub enabled: bool,
+    /// Body bytes per second across all streams of a device, both directions. None = unlimited.
+    pub device_bytes_per_sec: Option<u64>,
+    /// Body bytes per second across all streams of a user, both directions. None = unlimited.
+    pub user_bytes_per_sec: Option<u64>,
+    /// Seconds of unused rate a bucket saves up for bursts.
+    pub burst_secs: u64,
+    /// Body bytes a device may transfer per calendar month (UTC). None = no cap.
+    pub device_monthly_cap_bytes: Option<u64>,
+    /// Seconds Redis overrides are cached.
+    pub override_ttl_secs: u64,
+    /// Seconds between monthly usage flushes to Redis.
+    pub usage_sync_interval_secs: u64,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            device_bytes_per_sec: None,
+            user_bytes_per_sec: None,
+            burst_secs: 1,
+            device_monthly_cap_bytes: None,
+            override_ttl_secs: 30,
+            usage_sync_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestLimitsConfig {
+    /// Largest request body passed to a device, in bytes; larger ones get 413.
+    /// None = unlimited.
+    pub max_body_bytes: Option<u64>,
+    /// Largest total size of request header lines, in bytes; larger ones get 431.
+    pub max_header_bytes: usize,
+    /// Seconds a request or response body may stall between chunks before the
+    /// stream is cancelled (408 for requests, 504 for responses). None = no limit.
+    pub body_idle_timeout_secs: Option<u64>,
+    /// Maximum seconds from opening a stream to the end of its response; longer
+    /// ones are cancelled with 504. Upgraded connections are exempt. None = no limit.
+    pub max_stream_duration_secs: Option<u64>,
+}
+
+impl Default for RequestLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: None,
+            max_header_bytes: 64 * 1024,
+            body_idle_timeout_secs: Some(60),
+            max_stream_duration_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionTokenMode {
+    /// Random token looked up in Redis on every request.
+    #[default]
+    Opaque,
+    /// HMAC-signed token carrying session ID, device ID and expiry, verified in memory.
+    /// Requires `SESSION_TOKEN_SECRET`.
+    Signed,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct RedisConfig {
@@ -69,21 +303,148 @@ impl Default for RedisConfig {
     }
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Route streams between replicas: device-to-node ownership is kept in Redis and
+    /// requests for devices connected elsewhere are forwarded to the owning node.
+    pub enabled: bool,
+    /// Unique node identifier. Defaults to `$HOSTNAME`.
+    pub node_id: Option<String>,
+    /// Base URL other nodes use to reach this one, e.g. "http://10.0.0.12:8002".
+    /// Must point at `api.internal_listen_addr`, which serves forwarded requests.
+    /// Defaults to `http://$POD_IP:{internal listen port}`.
+    pub advertise_url: Option<String>,
+    /// Seconds between ownership heartbeats.
+    pub heartbeat_interval_secs: u64,
+    /// Seconds a device-to-node record outlives the last heartbeat.
+    pub ownership_ttl_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node_id: None,
+            advertise_url: None,
+            heartbeat_interval_secs: 10,
+            ownership_ttl_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// Destinations for per-stream audit records. Empty = auditing disabled.
+    pub sinks: Vec<AuditSinkConfig>,
+    /// Replace query parameter values with `REDACTED` in recorded paths.
+    pub redact_query: bool,
+    /// Records buffered ahead of the sinks; records beyond it are dropped.
+    pub queue_capacity: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            sinks: vec![],
+            redact_query: true,
+            queue_capacity: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// Keep a `presence:{device_id}` hash in Redis and publish an event whenever a
+    /// device connects or disconnects.
+    pub enabled: bool,
+    /// Destinations for presence events, besides the hashes.
+    pub sinks: Vec<PresenceSinkConfig>,
+    /// Seconds an online entry outlives the last refresh, so entries of a node
+    /// that died without cleaning up expire.
+    pub online_ttl_secs: u64,
+    /// Seconds an offline entry is kept after the disconnect.
+    pub offline_ttl_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sinks: vec![],
+            online_ttl_secs: 90,
+            offline_ttl_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PresenceSinkConfig {
+    /// Publish JSON events to a Redis pub/sub channel.
+    Redis { channel: String },
+    /// Produce JSON events keyed by device ID to a Kafka topic.
+    Kafka {
+        brokers: String,
+        topic: String,
+        message_timeout_ms: Option<u32>,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditSinkConfig {
+    /// Append JSON lines to a local file.
+    File { path: PathBuf },
+    /// Produce JSON records keyed by device ID to a Kafka topic.
+    Kafka {
+        brokers: String,
+        topic: String,
+        message_timeout_ms: Option<u32>,
+    },
+}
+
 /// Sensitive credentials — loaded exclusively from environment variables.
 #[derive(Clone, Zeroize)]
 #[zeroize(drop)]
 pub struct AppSecrets {
     pub jwt_public_key: String,
+    pub device_token_secret: String,
+    /// HMAC secret for signed session tokens; only needed in `signed` mode.
+    pub session_token_secret: Option<String>,
+    /// Bearer token for the admin API; the API is disabled when unset.
+    pub admin_api_token: Option<String>,
+    /// Secret per-device identity header keys are derived from; only needed
+    /// with `forward_identity`.
+    pub identity_header_secret: Option<String>,
 }
 
 impl AppSecrets {
     pub fn from_env() -> anyhow::Result<Self> {
         Ok(Self {
             jwt_public_key: decode_b64_env("JWT_PUBLIC_KEY")?,
+            device_token_secret: device_token_secret_from_env()?,
+            session_token_secret: std::env::var("SESSION_TOKEN_SECRET").ok(),
+            admin_api_token: std::env::var("ADMIN_API_TOKEN").ok(),
+            identity_header_secret: std::env::var(IDENTITY_HEADER_SECRET_ENV).ok(),
         })
     }
 }
 
+const IDENTITY_HEADER_SECRET_ENV: &str = "IDENTITY_HEADER_SECRET";
+
+/// Secret per-device identity header keys are derived from.
+pub fn identity_header_secret_from_env() -> anyhow::Result<String> {
+    std::env::var(IDENTITY_HEADER_SECRET_ENV).context("IDENTITY_HEADER_SECRET not set")
+}
+
+/// HMAC secret used to sign and verify device tokens.
+pub fn device_token_secret_from_env() -> anyhow::Result<String> {
+    std::env::var("DEVICE_TOKEN_SECRET").context("DEVICE_TOKEN_SECRET not set")
+}
+
 fn decode_b64_env(var: &str) -> anyhow::Result<String> {
     let encoded = std::env::var(var).with_context(|| format!("{var} not set"))?;
     let bytes = base64::engine::general_purpose::STANDARD
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+use crate::config::ErrorPagesConfig;
+
+const DEFAULT_TEMPLATE: &str = include_str!("default.html");
+
+/// Marks a response as a failure of the tunnel itself rather than of the device,
+/// so it can be rendered in the format the client asked for.
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelError {
+    pub message: &'static str,
+}
+
+/// Plain-text tunnel failure, rendered by `ErrorPages` on the way out.
+pub fn tunnel_error(status: StatusCode, message: &'static str) -> Response {
+    let mut response = (status, message).into_response();
+    response.extensions_mut().insert(TunnelError { message });
+    response
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+    /// Seconds after which the request may succeed, for device availability errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    Json,
+    Html,
+    Text,
+}
+
+// ── Pages ─────────────────────────────────────────────────────────────────
+
+/// Renders tunnel failures as HTML pages for browsers and JSON for API clients.
+pub struct ErrorPages {
+    default_template: String,
+    templates: HashMap<u16, String>,
+    retry_interval_secs: u64,
+}
+
+impl ErrorPages {
+    /// Load operator templates, falling back to the built-in page.
+    pub fn new(config: &ErrorPagesConfig) -> Result<Self> {
+        let mut pages = Self {
+            default_template: DEFAULT_TEMPLATE.to_owned(),
+            templates: HashMap::new(),
+            retry_interval_secs: config.retry_interval_secs.max(1),
+        };
+
+        if let Some(dir) = &config.template_dir {
+            pages.load_templates(dir)?;
+        }
+
+        Ok(pages)
+    }
+
+    /// `default.html` replaces the built-in page, `{status}.html` is used for one status.
+    fn load_templates(&mut self, dir: &Path) -> Result<()> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read error page templates {}", dir.display()))?;
+
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "html") {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let template = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if stem == "default" {
+                self.default_template = template;
+            } else if let Ok(status) = stem.parse() {
+                self.templates.insert(status, template);
+            } else {
+                tracing::warn!(path = %path.display(), "ignoring error page template");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Render a tunnel failure for the client. Responses from the device pass
+    /// through untouched. `status_url` is polled by retrying pages.
+    pub fn render(
+        &self,
+        accept: Option<&HeaderValue>,
+        status_url: Option<&str>,
+        response: Response,
+    ) -> Response {
+        let Some(error) = response.extensions().get::<TunnelError>().copied() else {
+            return response;
+        };
+
+        let status = response.status();
+        let retry_after = is_retryable(status).then_some(self.retry_interval_secs);
+
+        let mut response = match negotiate(accept) {
+            Format::Json => {
+                let body = ErrorBody {
+                    status: status.as_u16(),
+                    error: error.message,
+                    retry_after_secs: retry_after,
+                };
+                (status, axum::Json(body)).into_response()
+            }
+            Format::Html => {
+                let page = self.page(
+                    status,
+                    error.message,
+                    status_url.filter(|_| retry_after.is_some()),
+                );
+                (status, [(CONTENT_TYPE, "text/html; charset=utf-8")], page).into_response()
+            }
+            Format::Text => response,
+        };
+
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+
+    fn page(&self, status: StatusCode, message: &str, status_url: Option<&str>) -> String {
+        let template = self
+            .templates
+            .get(&status.as_u16())
+            .unwrap_or(&self.default_template);
+        let (title, description) = explain(status);
+        let retry = status_url
+            .map(|status_url| self.retry_script(status_url))
+            .unwrap_or_default();
+
+        template
+            .replace("{{status}}", status.as_str())
+            .replace(
+                "{{reason}}",
+                &escape_html(status.canonical_reason().unwrap_or_default()),
+            )
+            .replace("{{title}}", &escape_html(title))
+            .replace("{{description}}", &escape_html(description))
+            .replace("{{message}}", &escape_html(message))
+            .replace("{{retry}}", &retry)
+    }
+
+    /// Poll the session status and reload once the device is back online.
+    fn retry_script(&self, status_url: &str) -> String {
+        let status_url = serde_json::to_string(status_url).unwrap_or_default();
+        let interval_ms = self.retry_interval_secs * 1000;
+        format!(
+            r#"<p>This page reloads automatically once the device is back online.</p>
+    <script>
+      setInterval(async () => {{
+        try {{
+          const response = await fetch({status_url}, {{ cache: "no-store" }});
+          if (response.ok && (await response.json()).online) location.reload();
+        }} catch (_) {{}}
+      }}, {interval_ms});
+    </script>"#
+        )
+    }
+}
+
+/// Failures that go away once the device is back or less busy.
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn explain(status: StatusCode) -> (&'static str, &'static str) {
+    match status {
+        StatusCode::SERVICE_UNAVAILABLE => (
+            "Device unavailable",
+            "The device is offline or busy right now.",
+        ),
+        StatusCode::BAD_GATEWAY => (
+            "Connection to the device lost",
+            "The device closed the connection before it finished answering.",
+        ),
+        StatusCode::GATEWAY_TIMEOUT => (
+            "Device did not respond",
+            "The device took too long to answer.",
+        ),
+        StatusCode::TOO_MANY_REQUESTS => (
+            "Data limit reached",
+            "This device has used up its data allowance for now.",
+        ),
+        StatusCode::FORBIDDEN => (
+            "Access denied",
+            "This link does not give access to this page.",
+        ),
+        StatusCode::NOT_FOUND => (
+            "Link not found",
+            "This tunnel link is invalid or has expired. Ask for a new one.",
+        ),
+        _ => ("Tunnel error", "The tunnel could not handle this request."),
+    }
+}
+
+/// Pick the format the client prefers. Clients that accept anything get JSON;
+/// browsers rank `text/html` above their catch-all.
+fn negotiate(accept: Option<&HeaderValue>) -> Format {
+    let Some(accept) = accept.and_then(|value| value.to_str().ok()) else {
+        return Format::Json;
+    };
+
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let mime = params.next()?.trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|value| value.trim().parse().ok())
+                .unwrap_or(1.0);
+            Some((mime, quality))
+        })
+        .collect();
+
+    [
+        (Format::Json, "application/json"),
+        (Format::Html, "text/html"),
+        (Format::Text, "text/plain"),
+    ]
+    .into_iter()
+    .map(|(format, mime)| (format, quality(&ranges, mime)))
+    .filter(|(_, quality)| *quality > 0.0)
+    .fold(None, |best: Option<(Format, f32)>, candidate| match best {
+        Some(best) if best.1 >= candidate.1 => Some(best),
+        _ => Some(candidate),
+    })
+    .map_or(Format::Json, |(format, _)| format)
+}
+
+/// Quality of `mime` under the most specific matching range.
+fn quality(ranges: &[(&str, f32)], mime: &str) -> f32 {
+    let type_range = mime
+        .split_once('/')
+        .map(|(kind, _)| format!("{kind}/*"))
+        .unwrap_or_default();
+
+    [mime, type_range.as_str(), "*/*"]
+        .iter()
+        .find_map(|candidate| {
+            ranges
+                .iter()
+                .find(|(range, _)| range.eq_ignore_ascii_case(candidate))
+                .map(|(_, quality)| *quality)
+        })
+        .unwrap_or(0.0)
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, Method};
+use dashmap::DashMap;
+use nexus_utils::identity::{Access, Identity, device_key, insert_identity, session_id};
+use uuid::Uuid;
+
+use crate::redis::RedisClient;
+use crate::session::SessionRecord;
+
+/// How long an email looked up in Redis is reused.
+const EMAIL_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Cached emails beyond which stale entries are dropped.
+const EMAIL_CACHE_PRUNE_LEN: usize = 4096;
+
+struct CachedEmail {
+    email: String,
+    fetched: Instant,
+}
+
+/// Signs the identity of the user behind a session onto requests forwarded to
+/// devices, with a key derived per device from `IDENTITY_HEADER_SECRET`.
+pub struct IdentityHeaders {
+    secret: String,
+    redis: RedisClient,
+    emails: DashMap<String, CachedEmail>,
+}
+
+impl IdentityHeaders {
+    pub fn new(secret: String, redis: RedisClient) -> Self {
+        Self {
+            secret,
+            redis,
+            emails: DashMap::new(),
+        }
+    }
+
+    /// Keep the email of a user who opened a session, for sessions that cannot
+    /// carry it themselves. Lives as long as the session may.
+    pub async fn remember_email(&self, user_id: &str, email: &str, ttl_secs: u64) {
+        if let Err(err) = self.redis.store_user_email(user_id, email, ttl_secs).await {
+            tracing::warn!(%user_id, "failed to store user email: {err:#}");
+        }
+    }
+
+    /// Identity of the user behind `token`. Share links carry no user.
+    pub async fn identity(&self, record: &SessionRecord, token: &str) -> Identity {
+        let session_id = session_id(token);
+        if record.share.is_some() {
+            return Identity {
+                session_id,
+                access: Access::Share,
+                ..Identity::default()
+            };
+        }
+
+        let email = match &record.email {
+            Some(email) => email.clone(),
+            None => self.email(&record.user_id).await,
+        };
+        Identity {
+            user_id: record.user_id.clone(),
+            email,
+            session_id,
+            access: Access::Owner,
+        }
+    }
+
+    /// Replace the identity headers of a request to `device_id`.
+    pub fn sign(
+        &self,
+        headers: &mut HeaderMap,
+        device_id: Uuid,
+        identity: &Identity,
+        method: &Method,
+        path_and_query: &str,
+    ) {
+        let key = device_key(&self.secret, device_id);
+        insert_identity(headers, &key, identity, method, path_and_query);
+    }
+
+    /// Redis errors leave the email out rather than failing the request.
+    async fn email(&self, user_id: &str) -> String {
+        if let Some(cached) = self.emails.get(user_id)
+            && cached.fetched.elapsed() < EMAIL_CACHE_TTL
+        {
+            return cached.email.clone();
+        }
+
+        let email = match self.redis.get_user_email(user_id).await {
+            Ok(email) => email.unwrap_or_default(),
+            Err(err) => {
+                tracing::warn!(%user_id, "failed to load user email: {err:#}");
+                return String::new();
+            }
+        };
+
+        if self.emails.len() >= EMAIL_CACHE_PRUNE_LEN {
+            self.emails
+                .retain(|_, cached| cached.fetched.elapsed() < EMAIL_CACHE_TTL);
+        }
+        self.emails.insert(
+            user_id.to_owned(),
+            CachedEmail {
+                email: email.clone(),
+                fetched: Instant::now(),
+            },
+        );
+        email
+    }
+}
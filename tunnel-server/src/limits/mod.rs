@@ -0,0 +1,172 @@
+use std::fmt;
+use std::time::Duration;
+
+use axum::http::{HeaderMap, StatusCode};
+use tokio::time::Instant;
+
+use crate::config::RequestLimitsConfig;
+use crate::telemetry::LIMIT_VIOLATIONS_TOTAL;
+
+/// A proxied stream broke one of the `request_limits`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LimitExceeded {
+    BodyTooLarge,
+    HeadersTooLarge,
+    /// The client stopped sending its request body.
+    RequestIdle,
+    /// The device stopped sending its response body.
+    ResponseIdle,
+    Duration,
+}
+
+impl LimitExceeded {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::RequestIdle => StatusCode::REQUEST_TIMEOUT,
+            Self::ResponseIdle | Self::Duration => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::BodyTooLarge => "request body too large",
+            Self::HeadersTooLarge => "request headers too large",
+            Self::RequestIdle => "request body timed out",
+            Self::ResponseIdle => "device response stalled",
+            Self::Duration => "request took too long",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::BodyTooLarge => "body_size",
+            Self::HeadersTooLarge => "header_size",
+            Self::RequestIdle => "request_idle",
+            Self::ResponseIdle => "response_idle",
+            Self::Duration => "duration",
+        }
+    }
+
+    /// Count the violation and hand it back.
+    pub fn record(self) -> Self {
+        metrics::counter!(LIMIT_VIOLATIONS_TOTAL, "limit" => self.label()).increment(1);
+        self
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+// ── Limits ────────────────────────────────────────────────────────────────
+
+/// Size and time limits on proxied requests, from `api.request_limits`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    max_body_bytes: Option<u64>,
+    max_header_bytes: usize,
+    body_idle_timeout: Option<Duration>,
+    max_duration: Option<Duration>,
+}
+
+impl RequestLimits {
+    pub fn new(config: &RequestLimitsConfig) -> Self {
+        Self {
+            max_body_bytes: config.max_body_bytes,
+            max_header_bytes: config.max_header_bytes,
+            body_idle_timeout: config.body_idle_timeout_secs.map(Duration::from_secs),
+            max_duration: config.max_stream_duration_secs.map(Duration::from_secs),
+        }
+    }
+
+    /// Header names and values plus the `: ` and CRLF of each line, as on the wire.
+    pub fn check_headers(&self, headers: &HeaderMap) -> Result<(), LimitExceeded> {
+        let size: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        if size > self.max_header_bytes {
+            return Err(LimitExceeded::HeadersTooLarge.record());
+        }
+        Ok(())
+    }
+
+    /// Reject a body that is too large before any of it is read.
+    pub fn check_content_length(&self, content_length: Option<u64>) -> Result<(), LimitExceeded> {
+        if let (Some(length), Some(max)) = (content_length, self.max_body_bytes)
+            && length > max
+        {
+            return Err(LimitExceeded::BodyTooLarge.record());
+        }
+        Ok(())
+    }
+
+    /// Fails once `received` request body bytes are over the limit.
+    pub fn check_body(&self, received: u64) -> Result<(), LimitExceeded> {
+        match self.max_body_bytes {
+            Some(max) if received > max => Err(LimitExceeded::BodyTooLarge.record()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Timers for a stream opened now.
+    pub fn start(&self) -> StreamTimers {
+        StreamTimers {
+            idle_timeout: self.body_idle_timeout,
+            deadline: self.max_duration.map(|max| Instant::now() + max),
+        }
+    }
+}
+
+/// Idle timeout and deadline of one running stream.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTimers {
+    idle_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl StreamTimers {
+    /// No limits, for upgraded connections that may stay open and quiet for long.
+    pub fn unlimited() -> Self {
+        Self {
+            idle_timeout: None,
+            deadline: None,
+        }
+    }
+
+    /// Wait for `fut`, failing with `idle` if it takes longer than the idle timeout,
+    /// or when the stream runs out of time.
+    pub async fn idle<F: Future>(
+        &self,
+        fut: F,
+        idle: LimitExceeded,
+    ) -> Result<F::Output, LimitExceeded> {
+        let idle_timeout = async {
+            match self.idle_timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+            output = fut => Ok(output),
+            _ = idle_timeout => Err(idle.record()),
+            _ = self.expired() => Err(LimitExceeded::Duration.record()),
+        }
+    }
+
+    /// Resolves when the stream runs out of time; never without a duration limit.
+    pub async fn expired(&self) {
+        match self.deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+}
@@ -5,11 +5,22 @@ use clap::Parser;
 use crate::cli::App;
 
 mod api;
+mod audit;
+mod bandwidth;
 mod cli;
+mod cluster;
 mod config;
+mod error_page;
+mod identity;
+mod limits;
+mod presence;
 mod redis;
 mod registry;
+mod rewrite;
+mod session;
 mod state;
+mod telemetry;
+mod tls;
 
 fn main() -> ExitCode {
     if std::env::var("RUST_BACKTRACE").is_err() {
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+
+use super::{PresenceEvent, PresenceSink};
+
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Produces JSON events keyed by device ID, so one device's events stay ordered.
+pub struct KafkaSink {
+    topic: String,
+    producer: FutureProducer,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str, message_timeout_ms: Option<u32>) -> Result<Self> {
+        let mut client_config = rdkafka::config::ClientConfig::new();
+        client_config.set("bootstrap.servers", brokers);
+
+        if let Some(message_timeout_ms) = message_timeout_ms {
+            client_config.set("message.timeout.ms", message_timeout_ms.to_string());
+        }
+
+        Ok(Self {
+            topic: topic.to_owned(),
+            producer: client_config.create()?,
+        })
+    }
+}
+
+impl PresenceSink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    /// Waits for delivery, so events reach the topic in the order they happened.
+    fn publish<'a>(&'a self, event: &'a PresenceEvent) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let key = event.device_id.to_string();
+            let payload = serde_json::to_string(event)?;
+
+            self.producer
+                .send(
+                    FutureRecord::to(&self.topic).key(&key).payload(&payload),
+                    FLUSH_TIMEOUT,
+                )
+                .await
+                .map_err(|(err, _)| err)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Drop for KafkaSink {
+    fn drop(&mut self) {
+        tracing::info!("flushing kafka presence producer");
+        self.producer.flush(FLUSH_TIMEOUT).ok();
+    }
+}
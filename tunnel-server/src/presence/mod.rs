@@ -0,0 +1,288 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use nexus_utils::time::now_sec;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+pub use self::kafka::KafkaSink;
+pub use self::pubsub::PubSubSink;
+
+use crate::config::{PresenceConfig, PresenceSinkConfig};
+use crate::redis::RedisClient;
+use crate::registry::{DeviceRegistry, DeviceSession};
+
+mod kafka;
+mod pubsub;
+
+/// How long to wait on shutdown for disconnect events of the last devices.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A device connected to or disconnected from this node.
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceEvent {
+    pub event: PresenceEventKind,
+    pub device_id: Uuid,
+    /// Tells connections of one device apart; a reconnect to another node may be
+    /// announced before the old connection is.
+    pub connection_id: Uuid,
+    /// Unix timestamp of the event, in seconds.
+    pub timestamp: u64,
+    /// Unix timestamp of the connect, in seconds.
+    pub connected_at: u64,
+    pub remote_addr: String,
+    pub client_version: String,
+    pub protocol_version: u16,
+    /// Node holding the connection, in cluster mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// Why the device disconnected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEventKind {
+    Connected,
+    Disconnected,
+}
+
+impl PresenceEvent {
+    fn new(
+        event: PresenceEventKind,
+        session: &DeviceSession,
+        node_id: Option<&str>,
+        reason: Option<&str>,
+    ) -> Self {
+        Self {
+            event,
+            device_id: session.device_id(),
+            connection_id: session.connection_id(),
+            timestamp: now_sec(),
+            connected_at: session.connected_at(),
+            remote_addr: session.remote_addr().to_owned(),
+            client_version: session.handshake().peer_version.clone(),
+            protocol_version: session.handshake().protocol_version,
+            node_id: node_id.map(str::to_owned),
+            reason: reason.map(str::to_owned),
+        }
+    }
+
+    /// Fields of the `presence:{device_id}` hash.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("connection_id", self.connection_id.to_string()),
+            ("connected_at", self.connected_at.to_string()),
+            ("remote_addr", self.remote_addr.clone()),
+            ("client_version", self.client_version.clone()),
+            ("protocol_version", self.protocol_version.to_string()),
+        ];
+        if let Some(node_id) = &self.node_id {
+            fields.push(("node_id", node_id.clone()));
+        }
+
+        match self.event {
+            PresenceEventKind::Connected => fields.push(("status", "online".to_owned())),
+            PresenceEventKind::Disconnected => {
+                fields.push(("status", "offline".to_owned()));
+                fields.push(("disconnected_at", self.timestamp.to_string()));
+                if let Some(reason) = &self.reason {
+                    fields.push(("reason", reason.clone()));
+                }
+            }
+        }
+        fields
+    }
+}
+
+/// Destination for presence events.
+pub trait PresenceSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn publish<'a>(&'a self, event: &'a PresenceEvent) -> BoxFuture<'a, Result<()>>;
+}
+
+// ── Presence ──────────────────────────────────────────────────────────────
+
+/// Keeps `presence:{device_id}` hashes in Redis up to date and publishes connect
+/// and disconnect events. Events are queued by the registry and handled in order
+/// by a background task.
+pub struct Presence {
+    tx: mpsc::UnboundedSender<PresenceEvent>,
+    rx: Mutex<Option<mpsc::UnboundedReceiver<PresenceEvent>>>,
+    sinks: Vec<Box<dyn PresenceSink>>,
+    redis: RedisClient,
+    node_id: Option<String>,
+    online_ttl_secs: u64,
+    offline_ttl_secs: u64,
+}
+
+impl Presence {
+    /// Build the configured sinks. Returns `None` when presence is disabled.
+    pub fn new(
+        config: &PresenceConfig,
+        redis: RedisClient,
+        node_id: Option<String>,
+    ) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let mut sinks: Vec<Box<dyn PresenceSink>> = Vec::with_capacity(config.sinks.len());
+        for sink in &config.sinks {
+            match sink {
+                PresenceSinkConfig::Redis { channel } => {
+                    sinks.push(Box::new(PubSubSink::new(redis.clone(), channel)));
+                }
+                PresenceSinkConfig::Kafka {
+                    brokers,
+                    topic,
+                    message_timeout_ms,
+                } => sinks.push(Box::new(KafkaSink::new(
+                    brokers,
+                    topic,
+                    *message_timeout_ms,
+                )?)),
+            }
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok(Some(Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            sinks,
+            redis,
+            node_id,
+            online_ttl_secs: config.online_ttl_secs.max(1),
+            offline_ttl_secs: config.offline_ttl_secs.max(1),
+        }))
+    }
+
+    pub fn connected(&self, session: &DeviceSession) {
+        self.submit(PresenceEvent::new(
+            PresenceEventKind::Connected,
+            session,
+            self.node_id.as_deref(),
+            None,
+        ));
+    }
+
+    pub fn disconnected(&self, session: &DeviceSession, reason: &str) {
+        self.submit(PresenceEvent::new(
+            PresenceEventKind::Disconnected,
+            session,
+            self.node_id.as_deref(),
+            Some(reason),
+        ));
+    }
+
+    fn submit(&self, event: PresenceEvent) {
+        if self.tx.send(event).is_err() {
+            tracing::warn!("presence writer stopped, dropping event");
+        }
+    }
+
+    /// Handle queued events and keep the online entries of `registry` from expiring
+    /// until `token` is cancelled, then wait for the last devices to disconnect.
+    pub async fn run(self: Arc<Self>, registry: Arc<DeviceRegistry>, token: CancellationToken) {
+        let Some(mut rx) = self.rx.lock().expect("presence receiver lock").take() else {
+            tracing::error!("presence writer already running");
+            return;
+        };
+
+        let mut refresh =
+            tokio::time::interval(Duration::from_secs((self.online_ttl_secs / 3).max(1)));
+        refresh.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                event = rx.recv() => match event {
+                    Some(event) => self.handle(&event).await,
+                    None => return,
+                },
+                _ = refresh.tick() => self.refresh(&registry).await,
+            }
+        }
+
+        // Device connections close on the same token; record their disconnects.
+        let flush = async {
+            loop {
+                let empty = registry.device_count() == 0;
+                if empty {
+                    tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+                }
+                while let Ok(event) = rx.try_recv() {
+                    self.handle(&event).await;
+                }
+                if empty {
+                    break;
+                }
+                tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+            }
+        };
+        if tokio::time::timeout(FLUSH_TIMEOUT, flush).await.is_err() {
+            tracing::warn!("timed out recording device disconnects");
+        }
+    }
+
+    async fn handle(&self, event: &PresenceEvent) {
+        let fields = event.fields();
+        let stored = match event.event {
+            PresenceEventKind::Connected => {
+                self.redis
+                    .store_presence(event.device_id, &fields, self.online_ttl_secs)
+                    .await
+            }
+            // A newer connection, possibly on another node, keeps its entry.
+            PresenceEventKind::Disconnected => self
+                .redis
+                .store_presence_if_owned(
+                    event.device_id,
+                    &event.connection_id.to_string(),
+                    &fields,
+                    self.offline_ttl_secs,
+                )
+                .await
+                .map(|_| ()),
+        };
+        if let Err(err) = stored {
+            tracing::warn!(device_id = %event.device_id, "failed to store presence: {err:#}");
+        }
+
+        for sink in &self.sinks {
+            if let Err(err) = sink.publish(event).await {
+                tracing::warn!(sink = sink.name(), device_id = %event.device_id, "failed to publish presence event: {err:#}");
+            }
+        }
+    }
+
+    async fn refresh(&self, registry: &DeviceRegistry) {
+        for (device_id, session) in registry.sessions() {
+            let event = PresenceEvent::new(
+                PresenceEventKind::Connected,
+                &session,
+                self.node_id.as_deref(),
+                None,
+            );
+            if let Err(err) = self
+                .redis
+                .refresh_presence(
+                    device_id,
+                    &event.connection_id.to_string(),
+                    &event.fields(),
+                    self.online_ttl_secs,
+                )
+                .await
+            {
+                tracing::warn!(%device_id, "failed to refresh presence: {err:#}");
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+
+use super::{PresenceEvent, PresenceSink};
+use crate::redis::RedisClient;
+
+/// Publishes JSON events to a Redis pub/sub channel.
+pub struct PubSubSink {
+    redis: RedisClient,
+    channel: String,
+}
+
+impl PubSubSink {
+    pub fn new(redis: RedisClient, channel: &str) -> Self {
+        Self {
+            redis,
+            channel: channel.to_owned(),
+        }
+    }
+}
+
+impl PresenceSink for PubSubSink {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn publish<'a>(&'a self, event: &'a PresenceEvent) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let payload = serde_json::to_string(event)?;
+            self.redis.publish_presence(&self.channel, &payload).await
+        })
+    }
+}
@@ -1,42 +1,622 @@
+use std::collections::HashMap;
+
 use anyhow::Context;
-use redis::AsyncCommands;
+use futures_util::Stream;
+use nexus_utils::time::now_sec;
+use redis::{AsyncCommands, Expiry, Script};
+use uuid::Uuid;
+
+use crate::session::format_revocation;
+
+/// Sorted set of revoked session IDs scored by token expiry, plus the pub/sub
+/// channel that announces new revocations.
+const REVOKED_SESSIONS_KEY: &str = "session:revoked";
+
+/// Pub/sub channel announcing closed sessions as `{device_id}:{token}`, so the node
+/// holding the device cancels their streams.
+const CLOSED_SESSIONS_CHANNEL: &str = "session:closed";
+
+/// Monthly usage counters outlive their month long enough to be inspected.
+const BANDWIDTH_USAGE_TTL_SECS: i64 = 40 * 24 * 60 * 60;
+
+/// Refresh `KEYS[1]` to `ARGV[1]` with TTL `ARGV[2]` unless another value owns it.
+const REFRESH_IF_OWNED: &str = r"
+local current = redis.call('GET', KEYS[1])
+if current == false or current == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// Delete `KEYS[1]` only while it still holds `ARGV[1]`.
+const DELETE_IF_OWNED: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Replace the hash `KEYS[1]` with the field pairs from `ARGV[3]` on and TTL `ARGV[2]`,
+/// unless its `connection_id` is set to something other than `ARGV[1]`.
+const REPLACE_PRESENCE_IF_OWNED: &str = r"
+local current = redis.call('HGET', KEYS[1], 'connection_id')
+if current ~= false and current ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('HSET', KEYS[1], unpack(ARGV, 3))
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return 1
+";
+
+/// Extend the hash `KEYS[1]` to TTL `ARGV[2]` while its `connection_id` is `ARGV[1]`,
+/// or recreate it from the field pairs from `ARGV[3]` on if it is gone.
+const REFRESH_PRESENCE: &str = r"
+local current = redis.call('HGET', KEYS[1], 'connection_id')
+if current == false then
+    redis.call('HSET', KEYS[1], unpack(ARGV, 3))
+elseif current ~= ARGV[1] then
+    return 0
+end
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return 1
+";
 
 #[derive(Clone)]
 pub struct RedisClient {
     client: redis::aio::ConnectionManager,
+    pubsub_client: redis::Client,
 }
 
 impl RedisClient {
     pub async fn new(url: &str) -> anyhow::Result<Self> {
         let client = redis::Client::open(url).context("failed to create Redis client")?;
-        let manager = redis::aio::ConnectionManager::new(client)
+        let manager = redis::aio::ConnectionManager::new(client.clone())
             .await
             .context("failed to connect to Redis")?;
-        Ok(Self { client: manager })
+        Ok(Self {
+            client: manager,
+            pubsub_client: client,
+        })
     }
 
-    /// Store `session:{token} → device_id` with a TTL.
+    /// Store `session:{token} → record` with a TTL.
     pub async fn store_session(
         &self,
         token: &str,
-        device_id: &str,
+        record: &str,
         ttl_secs: u64,
     ) -> anyhow::Result<()> {
         let mut conn = self.client.clone();
         let _: () = conn
-            .set_ex(format!("session:{token}"), device_id, ttl_secs)
+            .set_ex(format!("session:{token}"), record, ttl_secs)
             .await
             .context("failed to store session in Redis")?;
         Ok(())
     }
 
-    /// Look up the device_id associated with a session token.
+    /// Look up `session:{token}` and extend its TTL to `ttl_secs`.
+    pub async fn touch_session(
+        &self,
+        token: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<Option<String>> {
+        let mut conn = self.client.clone();
+        let record: Option<String> = conn
+            .get_ex(format!("session:{token}"), Expiry::EX(ttl_secs))
+            .await
+            .context("failed to touch session in Redis")?;
+        Ok(record)
+    }
+
+    /// Store `session:owner:{session_id} → user_id` for a signed session, with a TTL.
+    pub async fn store_session_owner(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .set_ex(format!("session:owner:{session_id}"), user_id, ttl_secs)
+            .await
+            .context("failed to store session owner in Redis")?;
+        Ok(())
+    }
+
+    /// Look up the user a signed session was issued to.
+    pub async fn get_session_owner(&self, session_id: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.client.clone();
+        let user_id: Option<String> = conn
+            .get(format!("session:owner:{session_id}"))
+            .await
+            .context("failed to get session owner from Redis")?;
+        Ok(user_id)
+    }
+
+    /// Mark a signed session active as `session:active:{session_id}` for `ttl_secs`.
+    pub async fn store_session_activity(
+        &self,
+        session_id: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .set_ex(format!("session:active:{session_id}"), 1, ttl_secs)
+            .await
+            .context("failed to store session activity in Redis")?;
+        Ok(())
+    }
+
+    /// Extend `session:active:{session_id}` to `ttl_secs`.
+    /// Returns `false` when the session has already gone idle.
+    pub async fn refresh_session_activity(
+        &self,
+        session_id: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.client.clone();
+        let refreshed: bool = conn
+            .expire(format!("session:active:{session_id}"), ttl_secs as i64)
+            .await
+            .context("failed to refresh session activity in Redis")?;
+        Ok(refreshed)
+    }
+
+    /// Whether a signed session has not gone idle yet.
+    pub async fn is_session_active(&self, session_id: &str) -> anyhow::Result<bool> {
+        let mut conn = self.client.clone();
+        let active: bool = conn
+            .exists(format!("session:active:{session_id}"))
+            .await
+            .context("failed to check session activity in Redis")?;
+        Ok(active)
+    }
+
+    /// Store `user:email:{user_id} → email` with a TTL.
+    pub async fn store_user_email(
+        &self,
+        user_id: &str,
+        email: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .set_ex(format!("user:email:{user_id}"), email, ttl_secs)
+            .await
+            .context("failed to store user email in Redis")?;
+        Ok(())
+    }
+
+    /// Look up the email stored for a user.
+    pub async fn get_user_email(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.client.clone();
+        let email: Option<String> = conn
+            .get(format!("user:email:{user_id}"))
+            .await
+            .context("failed to get user email from Redis")?;
+        Ok(email)
+    }
+
+    /// Start the use counter `session:uses:{token}` of a share link, expiring with it.
+    pub async fn store_session_uses(&self, token: &str, ttl_secs: u64) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .set_ex(format!("session:uses:{token}"), 0, ttl_secs)
+            .await
+            .context("failed to store session uses in Redis")?;
+        Ok(())
+    }
+
+    /// Count one use of a share link and return the uses so far.
+    pub async fn incr_session_uses(&self, token: &str) -> anyhow::Result<u64> {
+        let mut conn = self.client.clone();
+        let uses: u64 = conn
+            .incr(format!("session:uses:{token}"), 1)
+            .await
+            .context("failed to count session use in Redis")?;
+        Ok(uses)
+    }
+
+    /// Delete `session:{token}` and its use counter.
+    pub async fn delete_session(&self, token: &str) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .del(&[format!("session:{token}"), format!("session:uses:{token}")])
+            .await
+            .context("failed to delete session from Redis")?;
+        Ok(())
+    }
+
+    /// Add a session to the indexes `sessions:user:{user_id}` and
+    /// `sessions:device:{device_id}`, sorted sets of tokens scored by expiry, and
+    /// drop expired entries. The indexes expire `index_ttl_secs` after the last write.
+    pub async fn index_session(
+        &self,
+        user_id: &str,
+        device_id: Uuid,
+        token: &str,
+        exp: u64,
+        index_ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let mut keys = vec![format!("sessions:device:{device_id}")];
+        if !user_id.is_empty() {
+            keys.push(format!("sessions:user:{user_id}"));
+        }
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.zadd(key, token, exp)
+                .ignore()
+                .zrembyscore(key, "-inf", now_sec())
+                .ignore()
+                .expire(key, index_ttl_secs as i64)
+                .ignore();
+        }
+
+        let mut conn = self.client.clone();
+        let _: () = pipe
+            .query_async(&mut conn)
+            .await
+            .context("failed to index session in Redis")?;
+        Ok(())
+    }
+
+    /// Unexpired sessions of a user, with their expiry.
+    pub async fn get_user_sessions(&self, user_id: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut conn = self.client.clone();
+        let sessions: Vec<(String, u64)> = conn
+            .zrangebyscore_withscores(format!("sessions:user:{user_id}"), now_sec(), "+inf")
+            .await
+            .context("failed to get user sessions from Redis")?;
+        Ok(sessions)
+    }
+
+    /// Unexpired sessions of a device, with their expiry.
+    pub async fn get_device_sessions(&self, device_id: Uuid) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut conn = self.client.clone();
+        let sessions: Vec<(String, u64)> = conn
+            .zrangebyscore_withscores(format!("sessions:device:{device_id}"), now_sec(), "+inf")
+            .await
+            .context("failed to get device sessions from Redis")?;
+        Ok(sessions)
+    }
+
+    /// Remove sessions from a user's index.
+    pub async fn unindex_user_sessions(
+        &self,
+        user_id: &str,
+        tokens: &[String],
+    ) -> anyhow::Result<()> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .zrem(format!("sessions:user:{user_id}"), tokens)
+            .await
+            .context("failed to unindex sessions in Redis")?;
+        Ok(())
+    }
+
+    /// Remove a session from the user and device indexes.
+    pub async fn unindex_session(
+        &self,
+        user_id: &str,
+        device_id: Uuid,
+        token: &str,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = redis::pipe()
+            .zrem(format!("sessions:user:{user_id}"), token)
+            .ignore()
+            .zrem(format!("sessions:device:{device_id}"), token)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .context("failed to unindex session in Redis")?;
+        Ok(())
+    }
+
+    /// Drop the session index of a device.
+    pub async fn delete_device_sessions(&self, device_id: Uuid) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .del(format!("sessions:device:{device_id}"))
+            .await
+            .context("failed to delete device sessions from Redis")?;
+        Ok(())
+    }
+
+    /// Announce on `session:closed` that a session of `device_id` was closed.
+    pub async fn publish_session_closed(&self, device_id: Uuid, token: &str) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .publish(CLOSED_SESSIONS_CHANNEL, format!("{device_id}:{token}"))
+            .await
+            .context("failed to publish closed session")?;
+        Ok(())
+    }
+
+    /// Subscribe to closed session announcements on the `session:closed` channel.
+    pub async fn subscribe_closed_sessions(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = redis::Msg> + use<>> {
+        let mut pubsub = self
+            .pubsub_client
+            .get_async_pubsub()
+            .await
+            .context("failed to open Redis pub/sub connection")?;
+        pubsub
+            .subscribe(CLOSED_SESSIONS_CHANNEL)
+            .await
+            .context("failed to subscribe to closed sessions")?;
+        Ok(pubsub.into_on_message())
+    }
+
+    /// Add a signed session to `session:revoked` until `exp`, drop entries that have
+    /// expired, and announce the revocation on the `session:revoked` channel.
+    pub async fn revoke_session(&self, session_id: &str, exp: u64) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = redis::pipe()
+            .zadd(REVOKED_SESSIONS_KEY, session_id, exp)
+            .ignore()
+            .zrembyscore(REVOKED_SESSIONS_KEY, "-inf", now_sec())
+            .ignore()
+            .publish(REVOKED_SESSIONS_KEY, format_revocation(session_id, exp))
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .context("failed to revoke session in Redis")?;
+        Ok(())
+    }
+
+    /// Revoked session IDs that have not expired yet, with their expiry.
+    pub async fn get_revoked_sessions(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut conn = self.client.clone();
+        let revoked: Vec<(String, u64)> = conn
+            .zrangebyscore_withscores(REVOKED_SESSIONS_KEY, now_sec(), "+inf")
+            .await
+            .context("failed to get revoked sessions from Redis")?;
+        Ok(revoked)
+    }
+
+    /// Subscribe to revocation announcements on the `session:revoked` channel.
+    pub async fn subscribe_revocations(
+        &self,
+    ) -> anyhow::Result<impl Stream<Item = redis::Msg> + use<>> {
+        let mut pubsub = self
+            .pubsub_client
+            .get_async_pubsub()
+            .await
+            .context("failed to open Redis pub/sub connection")?;
+        pubsub
+            .subscribe(REVOKED_SESSIONS_KEY)
+            .await
+            .context("failed to subscribe to session revocations")?;
+        Ok(pubsub.into_on_message())
+    }
+
+    /// Look up the session record stored for a session token.
     pub async fn get_session(&self, token: &str) -> anyhow::Result<Option<String>> {
         let mut conn = self.client.clone();
-        let device_id: Option<String> = conn
+        let record: Option<String> = conn
             .get(format!("session:{token}"))
             .await
             .context("failed to get session from Redis")?;
-        Ok(device_id)
+        Ok(record)
+    }
+
+    /// Look up the owner of a device in the ownership cache maintained by the gateway
+    /// (`device:owner:{device_id} → user_id`).
+    pub async fn get_device_owner(&self, device_id: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.client.clone();
+        let user_id: Option<String> = conn
+            .get(format!("device:owner:{device_id}"))
+            .await
+            .context("failed to get device owner from Redis")?;
+        Ok(user_id)
+    }
+
+    /// Record `device:node:{device_id} → node_id` with a TTL, taking over from any
+    /// previous node.
+    pub async fn claim_device_node(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .set_ex(format!("device:node:{device_id}"), node_id, ttl_secs)
+            .await
+            .context("failed to store device node in Redis")?;
+        Ok(())
+    }
+
+    /// Extend `device:node:{device_id}` if it is still held by `node_id`.
+    /// Returns `false` when another node has taken the device over.
+    pub async fn refresh_device_node(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.client.clone();
+        let refreshed: i64 = Script::new(REFRESH_IF_OWNED)
+            .key(format!("device:node:{device_id}"))
+            .arg(node_id)
+            .arg(ttl_secs)
+            .invoke_async(&mut conn)
+            .await
+            .context("failed to refresh device node in Redis")?;
+        Ok(refreshed == 1)
+    }
+
+    /// Remove `device:node:{device_id}` if it is still held by `node_id`.
+    pub async fn release_device_node(&self, device_id: &str, node_id: &str) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: i64 = Script::new(DELETE_IF_OWNED)
+            .key(format!("device:node:{device_id}"))
+            .arg(node_id)
+            .invoke_async(&mut conn)
+            .await
+            .context("failed to release device node in Redis")?;
+        Ok(())
+    }
+
+    /// Look up the node holding the device connection.
+    pub async fn get_device_node(&self, device_id: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.client.clone();
+        let node_id: Option<String> = conn
+            .get(format!("device:node:{device_id}"))
+            .await
+            .context("failed to get device node from Redis")?;
+        Ok(node_id)
+    }
+
+    /// Store `node:url:{node_id} → url` with a TTL.
+    pub async fn store_node_url(
+        &self,
+        node_id: &str,
+        url: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .set_ex(format!("node:url:{node_id}"), url, ttl_secs)
+            .await
+            .context("failed to store node url in Redis")?;
+        Ok(())
+    }
+
+    /// Look up the internal URL of a node.
+    pub async fn get_node_url(&self, node_id: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.client.clone();
+        let url: Option<String> = conn
+            .get(format!("node:url:{node_id}"))
+            .await
+            .context("failed to get node url from Redis")?;
+        Ok(url)
+    }
+
+    /// Replace `presence:{device_id}` with `fields` for `ttl_secs`.
+    pub async fn store_presence(
+        &self,
+        device_id: Uuid,
+        fields: &[(&str, String)],
+        ttl_secs: u64,
+    ) -> anyhow::Result<()> {
+        let key = format!("presence:{device_id}");
+        let mut conn = self.client.clone();
+        let _: () = redis::pipe()
+            .atomic()
+            .del(&key)
+            .ignore()
+            .hset_multiple(&key, fields)
+            .ignore()
+            .expire(&key, ttl_secs as i64)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .context("failed to store presence in Redis")?;
+        Ok(())
+    }
+
+    /// Replace `presence:{device_id}` with `fields` for `ttl_secs` unless another
+    /// connection has taken it over. Returns whether it was replaced.
+    pub async fn store_presence_if_owned(
+        &self,
+        device_id: Uuid,
+        connection_id: &str,
+        fields: &[(&str, String)],
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.client.clone();
+        let stored: i64 = Script::new(REPLACE_PRESENCE_IF_OWNED)
+            .key(format!("presence:{device_id}"))
+            .arg(connection_id)
+            .arg(ttl_secs)
+            .arg(fields)
+            .invoke_async(&mut conn)
+            .await
+            .context("failed to store presence in Redis")?;
+        Ok(stored == 1)
+    }
+
+    /// Extend `presence:{device_id}` while `connection_id` owns it, recreating it
+    /// from `fields` if it expired. Returns `false` when another connection owns it.
+    pub async fn refresh_presence(
+        &self,
+        device_id: Uuid,
+        connection_id: &str,
+        fields: &[(&str, String)],
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.client.clone();
+        let refreshed: i64 = Script::new(REFRESH_PRESENCE)
+            .key(format!("presence:{device_id}"))
+            .arg(connection_id)
+            .arg(ttl_secs)
+            .arg(fields)
+            .invoke_async(&mut conn)
+            .await
+            .context("failed to refresh presence in Redis")?;
+        Ok(refreshed == 1)
+    }
+
+    /// Publish a presence event on `channel`.
+    pub async fn publish_presence(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        let mut conn = self.client.clone();
+        let _: () = conn
+            .publish(channel, payload)
+            .await
+            .context("failed to publish presence event")?;
+        Ok(())
+    }
+
+    /// Load the bandwidth override hash `bandwidth:{subject}`, where subject is
+    /// `device:{device_id}` or `user:{user_id}`.
+    pub async fn get_bandwidth_override(
+        &self,
+        subject: &str,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let mut conn = self.client.clone();
+        let fields: HashMap<String, String> = conn
+            .hgetall(format!("bandwidth:{subject}"))
+            .await
+            .context("failed to get bandwidth override from Redis")?;
+        Ok(fields)
+    }
+
+    /// Bytes counted in `bandwidth:usage:{device_id}:{month}`.
+    pub async fn get_bandwidth_usage(&self, device_id: Uuid, month: &str) -> anyhow::Result<u64> {
+        let mut conn = self.client.clone();
+        let used: Option<u64> = conn
+            .get(format!("bandwidth:usage:{device_id}:{month}"))
+            .await
+            .context("failed to get bandwidth usage from Redis")?;
+        Ok(used.unwrap_or_default())
+    }
+
+    /// Add `bytes` to `bandwidth:usage:{device_id}:{month}` and return the new total.
+    pub async fn add_bandwidth_usage(
+        &self,
+        device_id: Uuid,
+        month: &str,
+        bytes: u64,
+    ) -> anyhow::Result<u64> {
+        let key = format!("bandwidth:usage:{device_id}:{month}");
+        let mut conn = self.client.clone();
+        let (total,): (u64,) = redis::pipe()
+            .incr(&key, bytes)
+            .expire(&key, BANDWIDTH_USAGE_TTL_SECS)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .context("failed to add bandwidth usage in Redis")?;
+        Ok(total)
     }
 }
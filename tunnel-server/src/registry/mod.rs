@@ -1,53 +1,79 @@
 use std::io;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{Result, anyhow};
 use axum::http::HeaderMap;
 use bytes::Bytes;
 use dashmap::DashMap;
-use nexus_utils::tunnel::{Frame, Headers};
+use nexus_utils::time::now_sec;
+use nexus_utils::tunnel::{
+    Capabilities, Frame, Handshake, Headers, INITIAL_WINDOW_SIZE, SendWindow,
+};
 use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+use crate::bandwidth::StreamLimiter;
+use crate::presence::Presence;
+use crate::telemetry::{
+    BYTES_TOTAL, DIRECTION_FROM_DEVICE, DIRECTION_TO_DEVICE, FRAMES_TOTAL,
+    STREAM_CANCELLATIONS_TOTAL, STREAM_REJECTIONS_TOTAL,
+};
+
 #[derive(Clone)]
 pub struct DeviceRegistry {
     devices: Arc<DashMap<Uuid, Arc<DeviceSession>>>,
+    draining: Arc<AtomicBool>,
+    presence: Option<Arc<Presence>>,
 }
 
 impl DeviceRegistry {
-    pub fn new() -> Self {
+    pub fn new(presence: Option<Arc<Presence>>) -> Self {
         Self {
             devices: Arc::new(DashMap::new()),
+            draining: Arc::new(AtomicBool::new(false)),
+            presence,
         }
     }
 
     pub fn register(
         &self,
         device_id: Uuid,
+        handshake: Handshake,
+        remote_addr: String,
         max_streams: usize,
-        frame_tx: mpsc::Sender<Frame>,
-        shutdown: CancellationToken,
+        channels: SessionChannels,
     ) -> (Arc<DeviceSession>, Option<Arc<DeviceSession>>) {
         let session = Arc::new(DeviceSession::new(
             device_id,
+            handshake,
+            remote_addr,
             max_streams,
-            frame_tx,
-            shutdown,
+            channels,
         ));
+        if self.is_draining() {
+            session.go_away(GO_AWAY_MESSAGE);
+        }
         let previous = self.devices.insert(device_id, session.clone());
+        if let Some(presence) = &self.presence {
+            presence.connected(&session);
+        }
         (session, previous)
     }
 
-    pub fn unregister(&self, device_id: Uuid, session: &Arc<DeviceSession>) {
-        let is_same = self
+    /// Remove the device unless `session` has already been replaced by a newer one.
+    /// Returns whether the device was removed.
+    pub fn unregister(&self, device_id: Uuid, session: &Arc<DeviceSession>, reason: &str) -> bool {
+        let removed = self
             .devices
-            .get(&device_id)
-            .is_some_and(|entry| Arc::ptr_eq(entry.value(), session));
-
-        if is_same {
-            self.devices.remove(&device_id);
+            .remove_if(&device_id, |_, current| Arc::ptr_eq(current, session))
+            .is_some();
+        if removed && let Some(presence) = &self.presence {
+            presence.disconnected(session, reason);
         }
+        removed
     }
 
     pub fn get(&self, device_id: Uuid) -> Option<Arc<DeviceSession>> {
@@ -57,11 +83,79 @@ impl DeviceRegistry {
     pub fn is_online(&self, device_id: Uuid) -> bool {
         self.devices.contains_key(&device_id)
     }
+
+    pub fn sessions(&self) -> Vec<(Uuid, Arc<DeviceSession>)> {
+        self.devices
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Enter drain mode: every connected device gets a `GoAway` and no new streams are
+    /// accepted, while streams already in flight keep running.
+    pub fn start_drain(&self) {
+        self.draining.store(true, Ordering::Release);
+        for entry in self.devices.iter() {
+            entry.go_away(GO_AWAY_MESSAGE);
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn device_count(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Number of in-flight streams across all connected devices.
+    pub fn active_streams(&self) -> usize {
+        self.devices
+            .iter()
+            .map(|entry| entry.active_streams())
+            .sum()
+    }
+}
+
+const GO_AWAY_MESSAGE: &str = "server is shutting down";
+
+/// Error given to the streams of a revoked tunnel session.
+pub const SESSION_REVOKED_MESSAGE: &str = "tunnel session revoked";
+
+/// Handles to the writer task of a device connection.
+pub struct SessionChannels {
+    pub frame_tx: mpsc::Sender<Frame>,
+    pub control_tx: mpsc::UnboundedSender<Frame>,
+    pub shutdown: CancellationToken,
 }
 
 pub struct StreamRegistration {
     pub head_rx: oneshot::Receiver<ResponseHead>,
     pub body_rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+    pub stats: Arc<StreamStats>,
+    pub limiter: StreamLimiter,
+}
+
+/// Description and byte counters of a stream, shared with whoever opened it.
+#[derive(Debug)]
+pub struct StreamStats {
+    pub method: String,
+    pub path: String,
+    /// Tunnel session token the stream was opened with, if any.
+    pub session: Option<String>,
+    pub opened_at: Instant,
+    bytes_to_device: AtomicU64,
+    bytes_from_device: AtomicU64,
+}
+
+impl StreamStats {
+    pub fn bytes_to_device(&self) -> u64 {
+        self.bytes_to_device.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_from_device(&self) -> u64 {
+        self.bytes_from_device.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Debug)]
@@ -70,30 +164,114 @@ pub struct ResponseHead {
     pub headers: Headers,
 }
 
+/// Point-in-time view of an in-flight stream, for the admin API.
+#[derive(Debug)]
+pub struct StreamSnapshot {
+    pub stream_id: Uuid,
+    pub method: String,
+    pub path: String,
+    pub age: Duration,
+    pub bytes_to_device: u64,
+    pub bytes_from_device: u64,
+}
+
 pub struct DeviceSession {
     device_id: Uuid,
+    /// Unique per connection, unlike `device_id`.
+    connection_id: Uuid,
+    handshake: Handshake,
+    remote_addr: String,
+    /// Unix timestamp of the connect, in seconds.
+    connected_at: u64,
     max_streams: usize,
     frame_tx: mpsc::Sender<Frame>,
+    /// Unbounded queue for small control frames (window updates) that must not
+    /// wait behind data frames.
+    control_tx: mpsc::UnboundedSender<Frame>,
     shutdown: CancellationToken,
+    draining: AtomicBool,
     streams: DashMap<Uuid, StreamResponder>,
+    liveness: Liveness,
+}
+
+/// When the device was last heard from and how fast it answers pings.
+struct Liveness {
+    started: Instant,
+    /// Milliseconds after `started` of the last message from the device.
+    last_seen_ms: AtomicU64,
+    /// Round trip of the last answered ping; `u64::MAX` until one is answered.
+    rtt_micros: AtomicU64,
+    next_nonce: AtomicU64,
+    pending_ping: Mutex<Option<PendingPing>>,
+}
+
+struct PendingPing {
+    nonce: u64,
+    sent: Instant,
 }
 
 impl DeviceSession {
     fn new(
         device_id: Uuid,
+        handshake: Handshake,
+        remote_addr: String,
         max_streams: usize,
-        frame_tx: mpsc::Sender<Frame>,
-        shutdown: CancellationToken,
+        SessionChannels {
+            frame_tx,
+            control_tx,
+            shutdown,
+        }: SessionChannels,
     ) -> Self {
         Self {
             device_id,
+            connection_id: Uuid::new_v4(),
+            handshake,
+            remote_addr,
+            connected_at: now_sec(),
             max_streams,
             frame_tx,
+            control_tx,
             shutdown,
+            draining: AtomicBool::new(false),
             streams: DashMap::new(),
+            liveness: Liveness {
+                started: Instant::now(),
+                last_seen_ms: AtomicU64::new(0),
+                rtt_micros: AtomicU64::new(u64::MAX),
+                next_nonce: AtomicU64::new(0),
+                pending_ping: Mutex::new(None),
+            },
         }
     }
 
+    fn flow_control(&self) -> bool {
+        self.capabilities().contains(Capabilities::FLOW_CONTROL)
+    }
+
+    pub fn device_id(&self) -> Uuid {
+        self.device_id
+    }
+
+    pub fn connection_id(&self) -> Uuid {
+        self.connection_id
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.handshake.capabilities
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    pub fn remote_addr(&self) -> &str {
+        &self.remote_addr
+    }
+
+    pub fn connected_at(&self) -> u64 {
+        self.connected_at
+    }
+
     pub fn shutdown(&self) {
         self.shutdown.cancel();
     }
@@ -102,29 +280,218 @@ impl DeviceSession {
         &self.shutdown
     }
 
+    /// Stop accepting new streams and ask the device to reconnect elsewhere.
+    pub fn go_away(&self, message: &str) {
+        if self.draining.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        self.send_control(Frame::GoAway {
+            message: message.to_owned(),
+        });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    // ── Liveness ──────────────────────────────────────────────────────────
+
+    /// Note a message from the device.
+    pub fn record_seen(&self) {
+        let elapsed = self.liveness.started.elapsed().as_millis() as u64;
+        self.liveness
+            .last_seen_ms
+            .fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the last message from the device.
+    pub fn silence(&self) -> Duration {
+        let last_seen = Duration::from_millis(self.liveness.last_seen_ms.load(Ordering::Relaxed));
+        self.liveness.started.elapsed().saturating_sub(last_seen)
+    }
+
+    /// Unix timestamp of the last message from the device, in seconds.
+    pub fn last_seen_at(&self) -> u64 {
+        now_sec().saturating_sub(self.silence().as_secs())
+    }
+
+    /// Round trip time of the last answered ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.liveness.rtt_micros.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+
+    /// Payload for the next ping, or `None` while the previous one is unanswered.
+    pub fn next_ping(&self) -> Option<Bytes> {
+        let mut pending = self.liveness.pending_ping.lock().expect("ping lock");
+        if pending.is_some() {
+            return None;
+        }
+
+        let nonce = self.liveness.next_nonce.fetch_add(1, Ordering::Relaxed);
+        *pending = Some(PendingPing {
+            nonce,
+            sent: Instant::now(),
+        });
+        Some(Bytes::copy_from_slice(&nonce.to_be_bytes()))
+    }
+
+    /// Match a pong to the outstanding ping and record the round trip.
+    /// Unsolicited pongs are ignored.
+    pub fn record_pong(&self, payload: &[u8]) {
+        let Ok(nonce) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+
+        let mut pending = self.liveness.pending_ping.lock().expect("ping lock");
+        if let Some(ping) = pending.take_if(|ping| ping.nonce == nonce) {
+            let rtt = ping.sent.elapsed().as_micros() as u64;
+            self.liveness
+                .rtt_micros
+                .store(rtt.min(u64::MAX - 1), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether a ping has gone unanswered for `timeout` with nothing else
+    /// received from the device meanwhile.
+    pub fn is_unresponsive(&self, timeout: Duration) -> bool {
+        let pending = self.liveness.pending_ping.lock().expect("ping lock");
+        pending
+            .as_ref()
+            .is_some_and(|ping| ping.sent.elapsed() >= timeout && self.silence() >= timeout)
+    }
+
+    pub fn streams(&self) -> Vec<StreamSnapshot> {
+        self.streams
+            .iter()
+            .map(|entry| StreamSnapshot {
+                stream_id: *entry.key(),
+                method: entry.stats.method.clone(),
+                path: entry.stats.path.clone(),
+                age: entry.stats.opened_at.elapsed(),
+                bytes_to_device: entry.stats.bytes_to_device(),
+                bytes_from_device: entry.stats.bytes_from_device(),
+            })
+            .collect()
+    }
+
+    /// Register a stream; `method` and `path` only describe it for the admin API
+    /// and the audit trail, `session` lets it be cancelled with its tunnel session.
     pub fn register_stream(
         &self,
         stream_id: Uuid,
+        method: &str,
+        path: &str,
+        session: Option<&str>,
         body_capacity: usize,
+        limiter: StreamLimiter,
     ) -> Result<StreamRegistration> {
+        if self.is_draining() {
+            metrics::counter!(STREAM_REJECTIONS_TOTAL, "reason" => "draining").increment(1);
+            return Err(anyhow!("device session is draining"));
+        }
+
         if self.streams.len() >= self.max_streams {
+            metrics::counter!(STREAM_REJECTIONS_TOTAL, "reason" => "overloaded").increment(1);
             return Err(anyhow!("too many active streams"));
         }
 
+        let send_window = if self.flow_control() {
+            SendWindow::new()
+        } else {
+            SendWindow::unlimited()
+        };
+
         let (head_tx, head_rx) = oneshot::channel();
         let (body_tx, body_rx) = mpsc::channel(body_capacity);
+        let stats = Arc::new(StreamStats {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            session: session.map(str::to_owned),
+            opened_at: Instant::now(),
+            bytes_to_device: AtomicU64::new(0),
+            bytes_from_device: AtomicU64::new(0),
+        });
         self.streams.insert(
             stream_id,
             StreamResponder {
                 head_tx: Some(head_tx),
                 body_tx,
+                send_window,
+                unreleased: AtomicUsize::new(0),
+                stats: stats.clone(),
+                limiter: limiter.clone(),
             },
         );
 
-        Ok(StreamRegistration { head_rx, body_rx })
+        Ok(StreamRegistration {
+            head_rx,
+            body_rx,
+            stats,
+            limiter,
+        })
+    }
+
+    /// Wait for the device to grant `len` bytes of send credit on the stream and
+    /// for the stream's bandwidth limits to let them through.
+    pub async fn reserve_send(&self, stream_id: Uuid, len: usize) -> Result<()> {
+        let (send_window, limiter) = self
+            .streams
+            .get(&stream_id)
+            .map(|entry| (entry.send_window.clone(), entry.limiter.clone()))
+            .ok_or_else(|| anyhow!("stream closed: {stream_id}"))?;
+
+        send_window.reserve(len).await?;
+        limiter.acquire(len).await
+    }
+
+    /// Grant the device credit for `len` bytes the browser side has consumed.
+    pub fn release_received(&self, stream_id: Uuid, len: usize) {
+        if !self.flow_control() || len == 0 {
+            return;
+        }
+
+        if let Some(entry) = self.streams.get(&stream_id) {
+            entry.unreleased.fetch_sub(len, Ordering::AcqRel);
+        }
+        self.send_control(Frame::WindowUpdate {
+            stream_id,
+            increment: len as u32,
+        });
+    }
+
+    /// Queue a control frame ahead of data frames.
+    fn send_control(&self, frame: Frame) {
+        record_frame(DIRECTION_TO_DEVICE, &frame);
+        let _ = self.control_tx.send(frame);
     }
 
     pub async fn send_frame(&self, frame: Frame) -> Result<()> {
+        if !self.handshake.allows(&frame) {
+            return Err(anyhow!(
+                "device {} did not negotiate the capabilities for this frame",
+                self.device_id
+            ));
+        }
+
+        let data_len = record_frame(DIRECTION_TO_DEVICE, &frame);
+        if data_len > 0
+            && let Some(stream_id) = frame.stream_id()
+            && let Some(entry) = self.streams.get(&stream_id)
+        {
+            entry
+                .stats
+                .bytes_to_device
+                .fetch_add(data_len as u64, Ordering::Relaxed);
+        }
+
         self.frame_tx
             .send(frame)
             .await
@@ -132,6 +499,14 @@ impl DeviceSession {
     }
 
     pub async fn deliver_frame(&self, frame: Frame) -> Result<()> {
+        if !self.handshake.allows(&frame) {
+            return Err(anyhow!(
+                "device sent a frame outside negotiated capabilities"
+            ));
+        }
+
+        record_frame(DIRECTION_FROM_DEVICE, &frame);
+
         match frame {
             Frame::ResponseHead {
                 stream_id,
@@ -146,19 +521,26 @@ impl DeviceSession {
                 };
                 let _ = head_tx.send(ResponseHead { status, headers });
             }
-            Frame::ResponseBodyChunk { stream_id, data } => {
-                let Some(body_tx) = self
-                    .streams
-                    .get(&stream_id)
-                    .map(|entry| entry.body_tx.clone())
-                else {
+            Frame::ResponseBodyChunk { stream_id, data }
+            | Frame::StreamData { stream_id, data } => {
+                let Some((body_tx, within_window)) = self.streams.get(&stream_id).map(|entry| {
+                    entry
+                        .stats
+                        .bytes_from_device
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    (entry.body_tx.clone(), self.receive(&entry, data.len()))
+                }) else {
                     return Ok(());
                 };
-                if body_tx.send(Ok(data)).await.is_err() {
-                    self.streams.remove(&stream_id);
+                if !within_window {
+                    tracing::warn!(device_id = %self.device_id, %stream_id, "dropping stream: device exceeded stream window");
+                    self.cancel_stream(stream_id).await;
+                } else if let Err(err) = self.push_body(&body_tx, Ok(data)).await {
+                    tracing::warn!(device_id = %self.device_id, %stream_id, "dropping stream: {err:#}");
+                    self.cancel_stream(stream_id).await;
                 }
             }
-            Frame::ResponseBodyEnd { stream_id } => {
+            Frame::ResponseBodyEnd { stream_id } | Frame::StreamEnd { stream_id } => {
                 self.streams.remove(&stream_id);
             }
             Frame::ErrorStream {
@@ -166,20 +548,33 @@ impl DeviceSession {
                 status,
                 message,
             } => {
-                let Some((_, responder)) = self.streams.remove(&stream_id) else {
+                let Some((_, mut responder)) = self.streams.remove(&stream_id) else {
                     return Ok(());
                 };
-                if let Some(head_tx) = responder.head_tx {
+                if let Some(head_tx) = responder.head_tx.take() {
                     let _ = head_tx.send(ResponseHead {
                         status,
                         headers: HeaderMap::new(),
                     });
                 }
-                let _ = responder.body_tx.send(Err(io::Error::other(message))).await;
+                let _ = self
+                    .push_body(&responder.body_tx, Err(io::Error::other(message)))
+                    .await;
             }
             Frame::CancelStream { stream_id } => {
                 self.streams.remove(&stream_id);
             }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                if let Some(entry) = self.streams.get(&stream_id) {
+                    entry.send_window.grant(increment);
+                }
+            }
+            Frame::Unknown { tag } => {
+                tracing::debug!(device_id = %self.device_id, tag, "ignoring unknown frame");
+            }
             _ => {
                 return Err(anyhow!("unexpected frame from device"));
             }
@@ -189,30 +584,211 @@ impl DeviceSession {
     }
 
     pub async fn cancel_stream(&self, stream_id: Uuid) {
+        metrics::counter!(STREAM_CANCELLATIONS_TOTAL).increment(1);
         self.streams.remove(&stream_id);
         let _ = self.send_frame(Frame::CancelStream { stream_id }).await;
     }
 
+    /// Cancel a stream on both ends, failing the browser side with `reason`.
+    /// Returns whether the stream was open.
+    pub async fn abort_stream(&self, stream_id: Uuid, reason: &str) -> bool {
+        let Some((_, responder)) = self.streams.remove(&stream_id) else {
+            return false;
+        };
+
+        metrics::counter!(STREAM_CANCELLATIONS_TOTAL).increment(1);
+        self.fail_responder(responder, reason).await;
+        let _ = self.send_frame(Frame::CancelStream { stream_id }).await;
+        true
+    }
+
+    /// Abort every stream opened with the tunnel session `session`.
+    /// Returns how many were open.
+    pub async fn abort_session_streams(&self, session: &str, reason: &str) -> usize {
+        let stream_ids: Vec<Uuid> = self
+            .streams
+            .iter()
+            .filter(|entry| entry.stats.session.as_deref() == Some(session))
+            .map(|entry| *entry.key())
+            .collect();
+
+        let mut aborted = 0;
+        for stream_id in stream_ids {
+            if self.abort_stream(stream_id, reason).await {
+                aborted += 1;
+            }
+        }
+        aborted
+    }
+
     pub async fn close_all(&self, reason: &str) {
         let stream_ids: Vec<Uuid> = self.streams.iter().map(|entry| *entry.key()).collect();
         for stream_id in stream_ids {
             if let Some((_, responder)) = self.streams.remove(&stream_id) {
-                if let Some(head_tx) = responder.head_tx {
-                    let _ = head_tx.send(ResponseHead {
-                        status: 503,
-                        headers: HeaderMap::new(),
-                    });
-                }
-                let _ = responder
-                    .body_tx
-                    .send(Err(io::Error::other(reason.to_owned())))
-                    .await;
+                self.fail_responder(responder, reason).await;
             }
         }
     }
+
+    async fn fail_responder(&self, mut responder: StreamResponder, reason: &str) {
+        if let Some(head_tx) = responder.head_tx.take() {
+            let _ = head_tx.send(ResponseHead {
+                status: 503,
+                headers: HeaderMap::new(),
+            });
+        }
+        let _ = self
+            .push_body(&responder.body_tx, Err(io::Error::other(reason.to_owned())))
+            .await;
+    }
+
+    /// Count `len` bytes received on a stream against its window. Returns false
+    /// once the device sent more than the browser side has released.
+    fn receive(&self, responder: &StreamResponder, len: usize) -> bool {
+        if !self.flow_control() {
+            return true;
+        }
+
+        let unreleased = responder.unreleased.fetch_add(len, Ordering::AcqRel) + len;
+        unreleased <= INITIAL_WINDOW_SIZE as usize
+    }
+
+    /// Queue an item for the browser side. The window is enforced in bytes by
+    /// `receive`, so a full queue only means many small chunks and just waits.
+    async fn push_body(
+        &self,
+        body_tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+        item: Result<Bytes, io::Error>,
+    ) -> Result<()> {
+        body_tx
+            .send(item)
+            .await
+            .map_err(|_| anyhow!("stream receiver dropped"))
+    }
+}
+
+/// Count the frame and return its payload length.
+fn record_frame(direction: &'static str, frame: &Frame) -> usize {
+    metrics::counter!(FRAMES_TOTAL, "direction" => direction).increment(1);
+
+    let data_len = match frame {
+        Frame::RequestBodyChunk { data, .. }
+        | Frame::ResponseBodyChunk { data, .. }
+        | Frame::StreamData { data, .. } => data.len(),
+        _ => 0,
+    };
+    if data_len > 0 {
+        metrics::counter!(BYTES_TOTAL, "direction" => direction).increment(data_len as u64);
+    }
+
+    data_len
 }
 
 struct StreamResponder {
     head_tx: Option<oneshot::Sender<ResponseHead>>,
     body_tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    send_window: SendWindow,
+    /// Bytes received from the device that the browser side has not released.
+    unreleased: AtomicUsize,
+    stats: Arc<StreamStats>,
+    limiter: StreamLimiter,
+}
+
+impl Drop for StreamResponder {
+    fn drop(&mut self) {
+        self.send_window.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nexus_utils::tunnel::PROTOCOL_VERSION;
+
+    use super::*;
+
+    const WINDOW: usize = INITIAL_WINDOW_SIZE as usize;
+
+    fn session() -> (Arc<DeviceSession>, mpsc::Receiver<Frame>) {
+        let (frame_tx, frame_rx) = mpsc::channel(16);
+        let (control_tx, _control_rx) = mpsc::unbounded_channel();
+        let handshake = Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            peer_version: String::new(),
+            capabilities: Capabilities::SUPPORTED,
+        };
+        let (session, _) = DeviceRegistry::new(None).register(
+            Uuid::new_v4(),
+            handshake,
+            "127.0.0.1:0".into(),
+            8,
+            SessionChannels {
+                frame_tx,
+                control_tx,
+                shutdown: CancellationToken::new(),
+            },
+        );
+        (session, frame_rx)
+    }
+
+    fn chunk(stream_id: Uuid, len: usize) -> Frame {
+        Frame::ResponseBodyChunk {
+            stream_id,
+            data: Bytes::from(vec![0u8; len]),
+        }
+    }
+
+    #[tokio::test]
+    async fn small_chunks_within_the_window_wait_for_the_queue() {
+        let (session, mut frame_rx) = session();
+        let stream_id = Uuid::new_v4();
+        let mut registration = session
+            .register_stream(stream_id, "GET", "/", None, 4, StreamLimiter::unlimited())
+            .unwrap();
+
+        let reader = tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(Ok(data)) = registration.body_rx.recv().await {
+                received += data.len();
+            }
+            received
+        });
+
+        for _ in 0..64 {
+            session.deliver_frame(chunk(stream_id, 16)).await.unwrap();
+        }
+        session
+            .deliver_frame(Frame::ResponseBodyEnd { stream_id })
+            .await
+            .unwrap();
+
+        assert_eq!(reader.await.unwrap(), 64 * 16);
+        assert!(frame_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_past_the_window_are_cancelled() {
+        let (session, mut frame_rx) = session();
+        let stream_id = Uuid::new_v4();
+        let mut registration = session
+            .register_stream(stream_id, "GET", "/", None, 16, StreamLimiter::unlimited())
+            .unwrap();
+
+        session
+            .deliver_frame(chunk(stream_id, WINDOW))
+            .await
+            .unwrap();
+        let data = registration.body_rx.recv().await.unwrap().unwrap();
+        session.release_received(stream_id, data.len());
+        session
+            .deliver_frame(chunk(stream_id, WINDOW))
+            .await
+            .unwrap();
+        assert!(frame_rx.try_recv().is_err());
+
+        session.deliver_frame(chunk(stream_id, 1)).await.unwrap();
+        assert!(matches!(
+            frame_rx.try_recv(),
+            Ok(Frame::CancelStream { stream_id: cancelled }) if cancelled == stream_id
+        ));
+    }
 }
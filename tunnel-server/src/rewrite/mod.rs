@@ -0,0 +1,655 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::http::header::{
+    ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_LOCATION,
+    CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY_REPORT_ONLY, CONTENT_TYPE, LOCATION,
+    SET_COOKIE,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+
+use crate::config::{ApiConfig, CspRewrite, ResponseRewriteConfig, TunnelRouting};
+
+/// Sessions are served under `/t/{token}/` with `path` routing.
+pub const SESSION_PATH_PREFIX: &str = "/t/";
+
+/// Longest unterminated tag held back between body chunks before giving up on it.
+const MAX_TAG_BYTES: usize = 16 * 1024;
+
+/// HTML attributes whose absolute-path values get the session prefix.
+const LINK_ATTRIBUTES: [&[u8]; 5] = [b"href", b"src", b"action", b"formaction", b"poster"];
+
+/// Session path prefix for `token`, without a trailing slash.
+pub fn session_prefix(token: &str) -> String {
+    format!("{SESSION_PATH_PREFIX}{token}")
+}
+
+// ── Rules ─────────────────────────────────────────────────────────────────
+
+/// Header rewrite settings with device origins and header names parsed once.
+pub struct RewriteRules {
+    config: ResponseRewriteConfig,
+    device_origins: Vec<(String, u16)>,
+    strip_headers: Vec<HeaderName>,
+}
+
+impl RewriteRules {
+    pub fn new(config: &ResponseRewriteConfig) -> Result<Self> {
+        let device_origins = config
+            .device_origins
+            .iter()
+            .map(|origin| {
+                origin
+                    .parse::<Uri>()
+                    .ok()
+                    .as_ref()
+                    .and_then(origin_key)
+                    .with_context(|| format!("device origin {origin} is not scheme://host[:port]"))
+            })
+            .collect::<Result<_>>()?;
+
+        let strip_headers = config
+            .strip_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("invalid header name {name}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            config: config.clone(),
+            device_origins,
+            strip_headers,
+        })
+    }
+
+    fn is_device_origin(&self, uri: &Uri) -> bool {
+        let Some(origin) = origin_key(uri) else {
+            return false;
+        };
+
+        self.device_origins.contains(&origin)
+            || (self.config.map_private_origins && is_private_host(&origin.0))
+    }
+}
+
+/// Lowercase host and effective port of an absolute URL.
+fn origin_key(uri: &Uri) -> Option<(String, u16)> {
+    let scheme = uri.scheme_str()?;
+    let host = uri
+        .host()?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    let default_port = if scheme.eq_ignore_ascii_case("https") {
+        443
+    } else {
+        80
+    };
+
+    Some((host, uri.port_u16().unwrap_or(default_port)))
+}
+
+fn is_private_host(host: &str) -> bool {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => {
+            ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+        }
+        Err(_) => host == "localhost" || host.ends_with(".localhost") || host.ends_with(".local"),
+    }
+}
+
+// ── Response rewriter ─────────────────────────────────────────────────────
+
+/// Adapts device responses to the URL the browser sees the session under.
+#[derive(Clone)]
+pub struct ResponseRewriter {
+    rules: Arc<RewriteRules>,
+    /// Session origin, e.g. "https://{token}.tunnel.example.com".
+    origin: String,
+    /// Session path prefix with `path` routing.
+    prefix: Option<String>,
+    secure: bool,
+    rewrite_html: bool,
+}
+
+impl ResponseRewriter {
+    pub fn new(rules: Arc<RewriteRules>, config: &ApiConfig, token: &str) -> Self {
+        let scheme = &config.tunnel_scheme;
+        let domain = &config.tunnel_domain;
+        let (origin, prefix) = match config.tunnel_routing {
+            TunnelRouting::Subdomain => (format!("{scheme}://{token}.{domain}"), None),
+            TunnelRouting::Path => (format!("{scheme}://{domain}"), Some(session_prefix(token))),
+        };
+
+        Self {
+            rules,
+            origin,
+            rewrite_html: prefix.is_some() && config.rewrite_html_links,
+            prefix,
+            secure: scheme.eq_ignore_ascii_case("https"),
+        }
+    }
+
+    /// Rewrite response headers in place. Returns a body rewriter when the body
+    /// has to be rewritten too, in which case `Content-Length` is dropped.
+    pub fn rewrite_headers(&self, headers: &mut HeaderMap) -> Option<HtmlRewriter> {
+        for name in &self.rules.strip_headers {
+            headers.remove(name);
+        }
+
+        for name in [LOCATION, CONTENT_LOCATION] {
+            rewrite_all(headers, name, |url| self.rewrite_url(url));
+        }
+        rewrite_all(headers, ACCESS_CONTROL_ALLOW_ORIGIN, |origin| {
+            let uri = origin.parse::<Uri>().ok()?;
+            self.rules
+                .is_device_origin(&uri)
+                .then(|| self.origin.clone())
+        });
+        rewrite_all(headers, SET_COOKIE, |cookie| self.rewrite_cookie(cookie));
+
+        for name in [CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY_REPORT_ONLY] {
+            match self.rules.config.content_security_policy {
+                CspRewrite::Keep => {}
+                CspRewrite::Rewrite => {
+                    rewrite_all(headers, name, |policy| self.rewrite_policy(policy));
+                }
+                CspRewrite::Strip => {
+                    headers.remove(name);
+                }
+            }
+        }
+
+        let prefix = self.prefix.as_deref()?;
+        if !self.rewrite_html || !is_plain_html(headers) {
+            return None;
+        }
+        headers.remove(CONTENT_LENGTH);
+
+        Some(HtmlRewriter {
+            prefix: prefix.as_bytes().to_vec(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Map a URL the device sent to the one the browser has to use: absolute paths
+    /// move under the session prefix, device-local URLs onto the session origin.
+    fn rewrite_url(&self, url: &str) -> Option<String> {
+        if url.starts_with('/') && !url.starts_with("//") {
+            return self
+                .prefix
+                .as_deref()
+                .and_then(|prefix| prefix_path(url, prefix));
+        }
+
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (url, None),
+        };
+        let uri = url.parse::<Uri>().ok()?;
+        if !self.rules.is_device_origin(&uri) {
+            return None;
+        }
+
+        let mut rewritten = format!(
+            "{}{}{}",
+            self.origin,
+            self.prefix.as_deref().unwrap_or_default(),
+            uri.path_and_query()
+                .map(|value| value.as_str())
+                .unwrap_or("/")
+        );
+        if let Some(fragment) = fragment {
+            rewritten.push('#');
+            rewritten.push_str(fragment);
+        }
+        Some(rewritten)
+    }
+
+    fn rewrite_cookie(&self, cookie: &str) -> Option<String> {
+        let config = &self.rules.config;
+        let mut attributes = cookie.split(';');
+        let mut rewritten = attributes.next()?.to_owned();
+        let mut secure = false;
+
+        for attribute in attributes {
+            let (name, value) = match attribute.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (attribute.trim(), None),
+            };
+
+            if name.eq_ignore_ascii_case("domain") && config.strip_cookie_domain {
+                continue;
+            }
+            if name.eq_ignore_ascii_case("secure") {
+                secure = true;
+                if config.match_cookie_secure && !self.secure {
+                    continue;
+                }
+            }
+            if name.eq_ignore_ascii_case("path")
+                && let Some(prefix) = &self.prefix
+                && let Some(path) = value.and_then(|value| prefix_path(value, prefix))
+            {
+                rewritten.push_str("; Path=");
+                rewritten.push_str(&path);
+                continue;
+            }
+
+            rewritten.push(';');
+            rewritten.push_str(attribute);
+        }
+
+        if config.match_cookie_secure && self.secure && !secure {
+            rewritten.push_str("; Secure");
+        }
+
+        (rewritten != cookie).then_some(rewritten)
+    }
+
+    /// Replace device-local sources; keywords such as `'self'` already follow the session.
+    fn rewrite_policy(&self, policy: &str) -> Option<String> {
+        let rewritten = policy
+            .split(';')
+            .map(|directive| {
+                directive
+                    .split(' ')
+                    .map(|source| {
+                        if source.starts_with('\'') {
+                            return source.to_owned();
+                        }
+                        self.rewrite_url(source)
+                            .unwrap_or_else(|| source.to_owned())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+
+        (rewritten != policy).then_some(rewritten)
+    }
+}
+
+/// Apply `rewrite` to every value of a header, keeping values it leaves alone.
+fn rewrite_all(
+    headers: &mut HeaderMap,
+    name: HeaderName,
+    rewrite: impl Fn(&str) -> Option<String>,
+) {
+    let mut changed = false;
+    let values: Vec<HeaderValue> = headers
+        .get_all(&name)
+        .iter()
+        .map(|value| {
+            let rewritten = value
+                .to_str()
+                .ok()
+                .and_then(&rewrite)
+                .and_then(|value| HeaderValue::from_str(&value).ok());
+            changed |= rewritten.is_some();
+            rewritten.unwrap_or_else(|| value.clone())
+        })
+        .collect();
+
+    if !changed {
+        return;
+    }
+    headers.remove(&name);
+    for value in values {
+        headers.append(name.clone(), value);
+    }
+}
+
+/// Compressed bodies are passed through untouched.
+fn is_plain_html(headers: &HeaderMap) -> bool {
+    let html = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .trim_start()
+                .get(..9)
+                .is_some_and(|mime| mime.eq_ignore_ascii_case("text/html"))
+        });
+    let encoded = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| !value.trim().eq_ignore_ascii_case("identity"));
+
+    html && !encoded
+}
+
+/// Prefix an absolute path; full URLs and network-path references are left alone.
+fn prefix_path(value: &str, prefix: &str) -> Option<String> {
+    (value.starts_with('/') && !value.starts_with("//")).then(|| format!("{prefix}{value}"))
+}
+
+// ── HTML links ────────────────────────────────────────────────────────────
+
+/// Prefixes absolute-path links in an HTML body as it streams through. A tag
+/// split across chunks is held back until it is complete.
+pub struct HtmlRewriter {
+    prefix: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl HtmlRewriter {
+    /// Rewrite the next chunk, returning what can be sent so far.
+    pub fn feed(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+
+        let split = match self.pending.iter().rposition(|&byte| byte == b'<') {
+            Some(start)
+                if !self.pending[start..].contains(&b'>')
+                    && self.pending.len() - start <= MAX_TAG_BYTES =>
+            {
+                start
+            }
+            _ => self.pending.len(),
+        };
+
+        let held = self.pending.split_off(split);
+        let ready = std::mem::replace(&mut self.pending, held);
+        self.rewrite(&ready)
+    }
+
+    /// Rewrite whatever is still held back at the end of the body.
+    pub fn finish(&mut self) -> Bytes {
+        let rest = std::mem::take(&mut self.pending);
+        self.rewrite(&rest)
+    }
+
+    fn rewrite(&self, input: &[u8]) -> Bytes {
+        let mut output = Vec::with_capacity(input.len());
+        let mut copied = 0;
+
+        for index in 0..input.len() {
+            if let Some(value) = link_value(input, index) {
+                output.extend_from_slice(&input[copied..value]);
+                output.extend_from_slice(&self.prefix);
+                copied = value;
+            }
+        }
+        output.extend_from_slice(&input[copied..]);
+
+        Bytes::from(output)
+    }
+}
+
+/// If a link attribute with an absolute-path value starts after the whitespace at
+/// `index`, return the position of its value.
+fn link_value(input: &[u8], index: usize) -> Option<usize> {
+    if !input[index].is_ascii_whitespace() {
+        return None;
+    }
+
+    let name_start = index + 1;
+    LINK_ATTRIBUTES.iter().find_map(|attribute| {
+        let name_end = name_start + attribute.len();
+        let value = name_end + 2;
+        let name = input.get(name_start..name_end)?;
+
+        let matches = name.eq_ignore_ascii_case(attribute)
+            && input.get(name_end) == Some(&b'=')
+            && matches!(input.get(name_end + 1), Some(b'"' | b'\''))
+            && input.get(value) == Some(&b'/')
+            && input.get(value + 1) != Some(&b'/');
+        matches.then_some(value)
+    })
+}
+
+/// Run a response body through `rewriter`.
+pub fn rewrite_body<S>(
+    body: S,
+    rewriter: HtmlRewriter,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin,
+{
+    futures_util::stream::unfold((body, Some(rewriter)), |(mut body, rewriter)| async move {
+        let mut rewriter = rewriter?;
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let chunk = rewriter.feed(&chunk);
+                Some((Ok(chunk), (body, Some(rewriter))))
+            }
+            Some(Err(err)) => Some((Err(err), (body, None))),
+            None => Some((Ok(rewriter.finish()), (body, None))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "abc123";
+
+    fn rewriter(scheme: &str, routing: TunnelRouting) -> ResponseRewriter {
+        let config = ApiConfig {
+            tunnel_scheme: scheme.to_owned(),
+            tunnel_domain: "tunnel.example.com".to_owned(),
+            tunnel_routing: routing,
+            rewrite_html_links: true,
+            ..ApiConfig::default()
+        };
+        let rules = RewriteRules::new(&config.response_rewrite).unwrap();
+        ResponseRewriter::new(Arc::new(rules), &config, TOKEN)
+    }
+
+    fn html_rewriter() -> HtmlRewriter {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        rewriter("https", TunnelRouting::Path)
+            .rewrite_headers(&mut headers)
+            .unwrap()
+    }
+
+    fn rewrite_chunks(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut rewriter = html_rewriter();
+        let mut output = Vec::new();
+        for chunk in chunks {
+            output.extend_from_slice(&rewriter.feed(chunk));
+        }
+        output.extend_from_slice(&rewriter.finish());
+        output
+    }
+
+    fn cookies(rewriter: &ResponseRewriter, cookie: &'static str) -> Vec<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(SET_COOKIE, HeaderValue::from_static(cookie));
+        rewriter.rewrite_headers(&mut headers);
+        headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn html_links_get_the_session_prefix() {
+        let output = rewrite_chunks(&[
+            b"<a href=\"/docs\">docs</a><img SRC='/logo.png'><a href=\"//cdn.example.com/x\">",
+            b"<a href=\"https://example.com/\"><a href=\"page\"><form action=\"/login\">",
+        ]);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "<a href=\"/t/abc123/docs\">docs</a><img SRC='/t/abc123/logo.png'>\
+             <a href=\"//cdn.example.com/x\"><a href=\"https://example.com/\"><a href=\"page\">\
+             <form action=\"/t/abc123/login\">"
+        );
+    }
+
+    #[test]
+    fn html_split_at_any_byte_matches_unsplit_output() {
+        let html: &[u8] = b"<html><head><link rel=\"stylesheet\" href=\"/app.css\"></head>\
+            <body><a class=\"nav\"\nhref='/home'>home</a><video poster=\"/p.jpg\" src=\"/v.mp4\">\
+            <button formaction=\"/save\">save</button></body></html>";
+        let expected = rewrite_chunks(&[html]);
+        assert_eq!(expected.len(), html.len() + 5 * session_prefix(TOKEN).len());
+
+        for split in 0..=html.len() {
+            let (first, second) = html.split_at(split);
+            assert_eq!(
+                rewrite_chunks(&[first, second]),
+                expected,
+                "split at {split}"
+            );
+        }
+
+        let bytes: Vec<&[u8]> = html.chunks(1).collect();
+        assert_eq!(rewrite_chunks(&bytes), expected);
+    }
+
+    #[test]
+    fn html_rewriter_holds_back_only_open_tags() {
+        let mut rewriter = html_rewriter();
+        assert_eq!(&rewriter.feed(b"<p>text</p><a hr")[..], b"<p>text</p>");
+        assert_eq!(
+            &rewriter.feed(b"ef=\"/x\">link")[..],
+            b"<a href=\"/t/abc123/x\">link"
+        );
+        assert_eq!(&rewriter.finish()[..], b"");
+    }
+
+    #[test]
+    fn html_rewriter_gives_up_on_oversized_tags() {
+        let mut rewriter = html_rewriter();
+        let mut tag = b"<a title=\"".to_vec();
+        tag.resize(MAX_TAG_BYTES + 1, b'x');
+        assert_eq!(rewriter.feed(&tag).len(), tag.len());
+        assert_eq!(&rewriter.finish()[..], b"");
+    }
+
+    #[test]
+    fn html_rewriter_flushes_unterminated_tags_at_the_end() {
+        assert_eq!(
+            rewrite_chunks(&[b"<a href=\"/x\""]),
+            b"<a href=\"/t/abc123/x\""
+        );
+    }
+
+    #[test]
+    fn only_plain_html_is_rewritten() {
+        let rewriter = rewriter("https", TunnelRouting::Path);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert!(rewriter.rewrite_headers(&mut headers).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(rewriter.rewrite_headers(&mut headers).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("42"));
+        assert!(rewriter.rewrite_headers(&mut headers).is_some());
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        assert!(
+            self::rewriter("https", TunnelRouting::Subdomain)
+                .rewrite_headers(&mut headers)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn cookies_follow_the_session() {
+        let https_path = rewriter("https", TunnelRouting::Path);
+        assert_eq!(
+            cookies(
+                &https_path,
+                "sid=1; Domain=192.168.1.10; Path=/app; HttpOnly"
+            ),
+            ["sid=1; Path=/t/abc123/app; HttpOnly; Secure"]
+        );
+        assert_eq!(
+            cookies(&https_path, "sid=1; path=//other; Secure"),
+            ["sid=1; path=//other; Secure"]
+        );
+
+        let http_subdomain = rewriter("http", TunnelRouting::Subdomain);
+        assert_eq!(
+            cookies(&http_subdomain, "sid=1; Path=/app; Secure; SameSite=None"),
+            ["sid=1; Path=/app; SameSite=None"]
+        );
+        assert_eq!(cookies(&http_subdomain, "sid=1"), ["sid=1"]);
+    }
+
+    #[test]
+    fn every_cookie_is_rewritten() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("a=1; Domain=device.local"),
+        );
+        headers.append(SET_COOKIE, HeaderValue::from_static("b=2; Path=/"));
+        rewriter("http", TunnelRouting::Path).rewrite_headers(&mut headers);
+
+        let cookies: Vec<_> = headers.get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2; Path=/t/abc123/"]);
+    }
+
+    #[test]
+    fn device_urls_map_to_the_session_origin() {
+        let rewriter = rewriter("https", TunnelRouting::Subdomain);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            HeaderValue::from_static("http://192.168.1.10:8080/login?next=%2F#top"),
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("http://localhost:3000"),
+        );
+        headers.insert(
+            "strict-transport-security",
+            HeaderValue::from_static("max-age=1"),
+        );
+        rewriter.rewrite_headers(&mut headers);
+
+        assert_eq!(
+            headers[LOCATION],
+            "https://abc123.tunnel.example.com/login?next=%2F#top"
+        );
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://abc123.tunnel.example.com"
+        );
+        assert!(!headers.contains_key("strict-transport-security"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("https://example.com/"));
+        rewriter.rewrite_headers(&mut headers);
+        assert_eq!(headers[LOCATION], "https://example.com/");
+    }
+
+    #[test]
+    fn redirects_stay_in_the_session() {
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("/login?next=%2F"));
+        headers.insert(
+            CONTENT_LOCATION,
+            HeaderValue::from_static("https://example.com/"),
+        );
+        rewriter("https", TunnelRouting::Path).rewrite_headers(&mut headers);
+
+        assert_eq!(headers[LOCATION], "/t/abc123/login?next=%2F");
+        assert_eq!(headers[CONTENT_LOCATION], "https://example.com/");
+    }
+}
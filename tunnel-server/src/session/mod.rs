@@ -0,0 +1,677 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail, ensure};
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use nexus_utils::time::now_sec;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::redis::RedisClient;
+
+/// Token layout version, the first payload byte.
+const TOKEN_VERSION: u8 = 2;
+
+/// version + session id + device id + expiry in seconds.
+const PAYLOAD_LEN: usize = 1 + 8 + 16 + 4;
+
+/// Truncated HMAC-SHA256 tag length.
+const MAC_LEN: usize = 10;
+
+/// Tokens are placed in the Host header as a single DNS label, so one wildcard
+/// certificate covers them.
+const MAX_LABEL_LEN: usize = 63;
+
+/// RFC 4648 base32 alphabet, lowercase: hostnames are case-insensitive.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+const REVOCATION_RETRY_DELAY: Duration = Duration::from_secs(5);
+const REVOCATION_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Identity carried by a signed session token. The user is not part of the
+/// token, it is kept in Redis under `session:owner:{session_id}`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SessionClaims {
+    pub session_id: u64,
+    pub device_id: Uuid,
+    pub exp: u64,
+}
+
+/// Opaque session stored in Redis under `session:{token}`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub device_id: Uuid,
+    pub user_id: String,
+    /// Email of the user, forwarded to the device in identity headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Unix timestamp the session ends at, however active it is. Not set on
+    /// records written before idle timeouts, which only expire with their key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Set for share links minted by the owner for someone else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share: Option<SharePolicy>,
+}
+
+/// Restrictions of a share link.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharePolicy {
+    /// Allowed request methods, e.g. `["GET", "HEAD"]`. Empty = any method.
+    pub methods: Vec<String>,
+    /// Allowed path prefixes, matched on whole segments. Empty = any path.
+    pub path_prefixes: Vec<String>,
+    /// Maximum number of proxied requests. None = unlimited.
+    pub max_uses: Option<u64>,
+}
+
+impl SharePolicy {
+    /// Check the policy and bring methods to upper case.
+    pub fn normalized(mut self) -> Result<Self> {
+        for method in &mut self.methods {
+            *method = method.to_ascii_uppercase();
+            axum::http::Method::from_bytes(method.as_bytes())
+                .with_context(|| format!("invalid method: {method}"))?;
+        }
+        for prefix in &self.path_prefixes {
+            ensure!(
+                prefix.starts_with('/'),
+                "path prefix must start with '/': {prefix}"
+            );
+        }
+        ensure!(self.max_uses != Some(0), "max_uses must be positive");
+        Ok(self)
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|allowed| allowed == method)
+    }
+
+    pub fn allows_path(&self, path: &str) -> bool {
+        if self.path_prefixes.is_empty() {
+            return true;
+        }
+
+        // The device resolves dot segments itself, so they could step out of a prefix.
+        let escapes = path.split('/').any(|segment| {
+            let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+            segment == "."
+                || segment == ".."
+                || segment.contains('\\')
+                || segment.contains("%2f")
+                || segment.contains("%5c")
+        });
+        if escapes {
+            return false;
+        }
+
+        self.path_prefixes.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            path == prefix
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+}
+
+impl SessionRecord {
+    /// Record of a signed session owned by `user_id`.
+    pub fn signed(claims: &SessionClaims, user_id: String) -> Self {
+        Self {
+            device_id: claims.device_id,
+            user_id,
+            email: None,
+            expires_at: Some(claims.exp),
+            share: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now_sec())
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("session record is serializable")
+    }
+
+    pub fn decode(value: &str) -> Result<Self> {
+        // Records written before the user was stored hold only the device ID.
+        if let Ok(device_id) = value.parse() {
+            return Ok(Self {
+                device_id,
+                user_id: String::new(),
+                email: None,
+                expires_at: None,
+                share: None,
+            });
+        }
+        serde_json::from_str(value).context("invalid session record")
+    }
+}
+
+// ── Tokens ────────────────────────────────────────────────────────────────
+
+/// Issues and verifies stateless session tokens, checking them against a
+/// revocation list that Redis pub/sub keeps in sync across nodes. With an idle
+/// timeout, activity is tracked in Redis under `session:active:{session_id}`.
+pub struct SessionTokens {
+    mac: Hmac<Sha256>,
+    revoked: DashMap<u64, u64>,
+    idle_timeout: Option<Duration>,
+    /// When this node last extended each session's activity in Redis.
+    touched: DashMap<u64, Instant>,
+    /// Owners looked up from Redis, with the session expiry.
+    owners: DashMap<u64, (String, u64)>,
+}
+
+impl SessionTokens {
+    pub fn new(secret: &str, idle_timeout_secs: Option<u64>) -> Result<Self> {
+        let mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .context("invalid session token secret")?;
+        Ok(Self {
+            mac,
+            revoked: DashMap::new(),
+            idle_timeout: idle_timeout_secs.map(Duration::from_secs),
+            touched: DashMap::new(),
+            owners: DashMap::new(),
+        })
+    }
+
+    /// Store the owner of a freshly issued session and start its idle timer.
+    pub async fn start(
+        &self,
+        redis: &RedisClient,
+        claims: &SessionClaims,
+        user_id: &str,
+    ) -> Result<()> {
+        redis
+            .store_session_owner(
+                &format_session_id(claims.session_id),
+                user_id,
+                claims.exp.saturating_sub(now_sec()).max(1),
+            )
+            .await?;
+        self.owners
+            .insert(claims.session_id, (user_id.to_owned(), claims.exp));
+
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Ok(());
+        };
+
+        redis
+            .store_session_activity(
+                &format_session_id(claims.session_id),
+                idle_ttl(idle_timeout, claims.exp),
+            )
+            .await?;
+        self.touched.insert(claims.session_id, Instant::now());
+        Ok(())
+    }
+
+    /// Extend a session's idle timer on use. Returns `false` once it went idle.
+    /// Redis is asked at most every quarter of the idle timeout per node.
+    pub async fn touch(&self, redis: &RedisClient, claims: &SessionClaims) -> Result<bool> {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Ok(true);
+        };
+
+        if let Some(touched) = self.touched.get(&claims.session_id)
+            && touched.elapsed() < idle_timeout / 4
+        {
+            return Ok(true);
+        }
+
+        let active = redis
+            .refresh_session_activity(
+                &format_session_id(claims.session_id),
+                idle_ttl(idle_timeout, claims.exp),
+            )
+            .await?;
+        if active {
+            self.touched.insert(claims.session_id, Instant::now());
+        } else {
+            self.touched.remove(&claims.session_id);
+        }
+        Ok(active)
+    }
+
+    /// Whether a session has not gone idle, without extending it.
+    pub async fn is_active(&self, redis: &RedisClient, claims: &SessionClaims) -> Result<bool> {
+        if self.idle_timeout.is_none() {
+            return Ok(true);
+        }
+        redis
+            .is_session_active(&format_session_id(claims.session_id))
+            .await
+    }
+
+    /// The user a session was issued to, or `None` once its owner record expired.
+    pub async fn owner(
+        &self,
+        redis: &RedisClient,
+        claims: &SessionClaims,
+    ) -> Result<Option<String>> {
+        if let Some(owner) = self.owners.get(&claims.session_id) {
+            return Ok(Some(owner.0.clone()));
+        }
+
+        let owner = redis
+            .get_session_owner(&format_session_id(claims.session_id))
+            .await?;
+        if let Some(owner) = &owner {
+            self.owners
+                .insert(claims.session_id, (owner.clone(), claims.exp));
+        }
+        Ok(owner)
+    }
+
+    /// Issue a token on `device_id`, valid for `ttl_secs`. Its owner is stored by `start`.
+    pub fn issue(&self, device_id: Uuid, ttl_secs: u64) -> (String, SessionClaims) {
+        let claims = SessionClaims {
+            session_id: Uuid::new_v4().as_u64_pair().0,
+            device_id,
+            exp: now_sec() + ttl_secs,
+        };
+        let exp = u32::try_from(claims.exp).expect("session expiry fits in 32 bits");
+
+        let mut bytes = Vec::with_capacity(PAYLOAD_LEN + MAC_LEN);
+        bytes.push(TOKEN_VERSION);
+        bytes.extend_from_slice(&claims.session_id.to_be_bytes());
+        bytes.extend_from_slice(claims.device_id.as_bytes());
+        bytes.extend_from_slice(&exp.to_be_bytes());
+
+        let mut mac = self.mac.clone();
+        mac.update(&bytes);
+        bytes.extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+
+        let token = base32_encode(&bytes);
+        debug_assert!(token.len() <= MAX_LABEL_LEN);
+        (token, claims)
+    }
+
+    /// Verify signature, expiry and revocation of a token taken from the Host header.
+    pub fn verify(&self, token: &str) -> Result<SessionClaims> {
+        ensure!(token.len() <= MAX_LABEL_LEN, "invalid token length");
+        let bytes = base32_decode(token)?;
+        ensure!(bytes.len() == PAYLOAD_LEN + MAC_LEN, "invalid token length");
+
+        let (payload, tag) = bytes.split_at(PAYLOAD_LEN);
+        let mut mac = self.mac.clone();
+        mac.update(payload);
+        mac.verify_truncated_left(tag)
+            .map_err(|_| anyhow::anyhow!("invalid token signature"))?;
+
+        ensure!(payload[0] == TOKEN_VERSION, "unsupported token version");
+        let claims = SessionClaims {
+            session_id: u64::from_be_bytes(payload[1..9].try_into()?),
+            device_id: Uuid::from_slice(&payload[9..25])?,
+            exp: u32::from_be_bytes(payload[25..29].try_into()?).into(),
+        };
+
+        ensure!(claims.exp > now_sec(), "token expired");
+        ensure!(!self.is_revoked(claims.session_id), "token revoked");
+
+        Ok(claims)
+    }
+
+    pub fn is_revoked(&self, session_id: u64) -> bool {
+        self.revoked.contains_key(&session_id)
+    }
+
+    /// Revoke a session on every node until its token would have expired anyway.
+    pub async fn revoke(&self, redis: &RedisClient, claims: &SessionClaims) -> Result<()> {
+        self.revoked.insert(claims.session_id, claims.exp);
+        redis
+            .revoke_session(&format_session_id(claims.session_id), claims.exp)
+            .await
+    }
+
+    /// Keep the local revocation list in sync with Redis until `token` is cancelled.
+    pub async fn run_revocation_sync(
+        self: Arc<Self>,
+        redis: RedisClient,
+        token: CancellationToken,
+    ) {
+        loop {
+            match self.sync_revocations(&redis, &token).await {
+                Ok(()) => break,
+                Err(err) => tracing::warn!("session revocation sync failed: {err:#}"),
+            }
+
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep(REVOCATION_RETRY_DELAY) => {}
+            }
+        }
+    }
+
+    async fn sync_revocations(&self, redis: &RedisClient, token: &CancellationToken) -> Result<()> {
+        // Subscribe before loading the snapshot so no revocation falls in between.
+        let mut messages = redis.subscribe_revocations().await?;
+
+        for (session_id, exp) in redis.get_revoked_sessions().await? {
+            if let Ok(session_id) = parse_session_id(&session_id) {
+                self.revoked.insert(session_id, exp);
+            }
+        }
+        tracing::info!(
+            revoked = self.revoked.len(),
+            "session revocation list loaded"
+        );
+
+        let mut prune = tokio::time::interval(REVOCATION_PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                _ = prune.tick() => {
+                    let now = now_sec();
+                    self.revoked.retain(|_, exp| *exp > now);
+                    self.owners.retain(|_, (_, exp)| *exp > now);
+                    if let Some(idle_timeout) = self.idle_timeout {
+                        self.touched.retain(|_, touched| touched.elapsed() < idle_timeout);
+                    }
+                }
+                message = messages.next() => {
+                    let Some(message) = message else {
+                        bail!("revocation subscription closed");
+                    };
+                    let payload: String = message.get_payload()?;
+                    match parse_revocation(&payload) {
+                        Ok((session_id, exp)) => {
+                            self.revoked.insert(session_id, exp);
+                        }
+                        Err(err) => tracing::warn!(%payload, "invalid revocation message: {err:#}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Idle TTL capped by the time left until `exp`.
+fn idle_ttl(idle_timeout: Duration, exp: u64) -> u64 {
+    idle_timeout
+        .as_secs()
+        .min(exp.saturating_sub(now_sec()))
+        .max(1)
+}
+
+/// Revocation messages are `{session_id}:{exp}`.
+pub fn format_revocation(session_id: &str, exp: u64) -> String {
+    format!("{session_id}:{exp}")
+}
+
+fn parse_revocation(payload: &str) -> Result<(u64, u64)> {
+    let (session_id, exp) = payload
+        .split_once(':')
+        .context("missing expiry separator")?;
+    Ok((parse_session_id(session_id)?, exp.parse()?))
+}
+
+fn format_session_id(session_id: u64) -> String {
+    format!("{session_id:016x}")
+}
+
+fn parse_session_id(value: &str) -> Result<u64> {
+    u64::from_str_radix(value, 16).context("invalid session id")
+}
+
+// ── Encoding ──────────────────────────────────────────────────────────────
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    out
+}
+
+fn base32_decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => bail!("invalid base32 character"),
+        };
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    // Leftover bits must be zero padding, so each token has a single spelling.
+    ensure!(
+        bits < 5 && buffer & ((1 << bits) - 1) == 0,
+        "non-canonical base32"
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> SessionTokens {
+        SessionTokens::new("test-secret", None).unwrap()
+    }
+
+    fn policy(prefixes: &[&str]) -> SharePolicy {
+        SharePolicy {
+            path_prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            ..SharePolicy::default()
+        }
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        for len in 0..=40usize {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            let encoded = base32_encode(&bytes);
+            assert_eq!(encoded.len(), (len * 8).div_ceil(5));
+            assert!(encoded.bytes().all(|c| BASE32_ALPHABET.contains(&c)));
+            assert_eq!(base32_decode(&encoded).unwrap(), bytes);
+            assert_eq!(base32_decode(&encoded.to_ascii_uppercase()).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("MZXW6YTBOI").unwrap(), b"foobar");
+    }
+
+    #[test]
+    fn base32_rejects_invalid_characters() {
+        for encoded in ["abc1", "abc8", "ab=c", "ab.c", "ab-c", "ab c"] {
+            assert!(base32_decode(encoded).is_err(), "{encoded}");
+        }
+    }
+
+    #[test]
+    fn base32_rejects_non_canonical_padding() {
+        // "my" is 0b01100_11000: one byte plus two padding bits that must be zero.
+        assert_eq!(base32_decode("my").unwrap(), b"f");
+        assert!(base32_decode("mz").is_err());
+        // A trailing character that completes no byte.
+        assert!(base32_decode("mya").is_err());
+    }
+
+    #[test]
+    fn tokens_fit_in_one_dns_label() {
+        let (token, claims) = tokens().issue(Uuid::new_v4(), 3600);
+        assert!(token.len() <= MAX_LABEL_LEN);
+        assert!(
+            token
+                .bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        );
+        assert_eq!(tokens().verify(&token).unwrap(), claims);
+    }
+
+    #[test]
+    fn tokens_are_case_insensitive() {
+        let (token, claims) = tokens().issue(Uuid::new_v4(), 3600);
+        assert_eq!(
+            tokens().verify(&token.to_ascii_uppercase()).unwrap(),
+            claims
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let (token, _) = tokens().issue(Uuid::new_v4(), 3600);
+
+        for i in 0..token.len() {
+            let mut tampered = token.clone().into_bytes();
+            tampered[i] = if tampered[i] == b'a' { b'b' } else { b'a' };
+            let tampered = String::from_utf8(tampered).unwrap();
+            assert!(tokens().verify(&tampered).is_err(), "byte {i} changed");
+        }
+
+        assert!(tokens().verify(&token[..token.len() - 1]).is_err());
+        assert!(tokens().verify(&format!("{token}a")).is_err());
+        assert!(tokens().verify("").is_err());
+    }
+
+    #[test]
+    fn tokens_of_another_secret_are_rejected() {
+        let other = SessionTokens::new("other-secret", None).unwrap();
+        let (token, _) = other.issue(Uuid::new_v4(), 3600);
+        assert!(tokens().verify(&token).is_err());
+    }
+
+    #[test]
+    fn expired_and_revoked_tokens_are_rejected() {
+        let tokens = tokens();
+        let (token, _) = tokens.issue(Uuid::new_v4(), 0);
+        assert!(tokens.verify(&token).is_err());
+
+        let (token, claims) = tokens.issue(Uuid::new_v4(), 3600);
+        tokens.revoked.insert(claims.session_id, claims.exp);
+        assert!(tokens.verify(&token).is_err());
+    }
+
+    #[test]
+    fn revocations_round_trip() {
+        let session_id = 0x0123_4567_89ab_cdef;
+        let payload = format_revocation(&format_session_id(session_id), 1_700_000_000);
+        assert_eq!(
+            parse_revocation(&payload).unwrap(),
+            (session_id, 1_700_000_000)
+        );
+        assert!(parse_revocation("0123").is_err());
+        assert!(parse_revocation("xyz:1").is_err());
+    }
+
+    #[test]
+    fn share_paths_match_whole_segments() {
+        let policy = policy(&["/api/", "/docs"]);
+
+        assert!(policy.allows_path("/api"));
+        assert!(policy.allows_path("/api/items"));
+        assert!(policy.allows_path("/docs"));
+        assert!(policy.allows_path("/docs/index.html"));
+        assert!(!policy.allows_path("/apis"));
+        assert!(!policy.allows_path("/documents"));
+        assert!(!policy.allows_path("/"));
+        assert!(!policy.allows_path("/admin/api"));
+    }
+
+    #[test]
+    fn share_paths_reject_dot_segments() {
+        let policy = policy(&["/api"]);
+
+        for path in [
+            "/api/../admin",
+            "/api/./items",
+            "/api/..",
+            "/api/%2e%2e/admin",
+            "/api/%2E%2E/admin",
+            "/api/.%2e/admin",
+            "/api/%2e/items",
+        ] {
+            assert!(!policy.allows_path(path), "{path}");
+        }
+
+        assert!(policy.allows_path("/api/.well-known"));
+        assert!(policy.allows_path("/api/file..txt"));
+    }
+
+    #[test]
+    fn share_paths_reject_encoded_separators() {
+        let policy = policy(&["/api"]);
+
+        for path in [
+            "/api%2f..%2fadmin",
+            "/api/..%2Fadmin",
+            "/api/items%5c..%5cadmin",
+            "/api\\..\\admin",
+            "/api/items\\file",
+        ] {
+            assert!(!policy.allows_path(path), "{path}");
+        }
+    }
+
+    #[test]
+    fn empty_share_policy_allows_everything() {
+        let policy = SharePolicy::default();
+        assert!(policy.allows_method("DELETE"));
+        assert!(policy.allows_path("/anything/../at-all"));
+    }
+
+    #[test]
+    fn share_policy_is_normalized() {
+        let normalized = SharePolicy {
+            methods: vec!["get".to_owned(), "Head".to_owned()],
+            ..SharePolicy::default()
+        }
+        .normalized()
+        .unwrap();
+        assert_eq!(normalized.methods, ["GET", "HEAD"]);
+        assert!(normalized.allows_method("GET"));
+        assert!(!normalized.allows_method("POST"));
+
+        assert!(policy(&["api"]).normalized().is_err());
+        assert!(
+            SharePolicy {
+                max_uses: Some(0),
+                ..SharePolicy::default()
+            }
+            .normalized()
+            .is_err()
+        );
+        assert!(
+            SharePolicy {
+                methods: vec!["GE T".to_owned()],
+                ..SharePolicy::default()
+            }
+            .normalized()
+            .is_err()
+        );
+    }
+}
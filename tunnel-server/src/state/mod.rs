@@ -1,15 +1,27 @@
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use nexus_utils::time::now_sec;
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
 use crate::api::endpoint::TunnelEndpoint;
-use crate::config::{ApiConfig, AppConfig, AppSecrets};
+use crate::audit::{Auditor, StreamAudit};
+use crate::bandwidth::{Bandwidth, StreamLimiter};
+use crate::cluster::Cluster;
+use crate::config::{ApiConfig, AppConfig, AppSecrets, SessionTokenMode};
+use crate::error_page::ErrorPages;
+use crate::identity::IdentityHeaders;
+use crate::presence::Presence;
 use crate::redis::RedisClient;
-use crate::registry::DeviceRegistry;
+use crate::registry::{DeviceRegistry, SESSION_REVOKED_MESSAGE};
+use crate::rewrite::{ResponseRewriter, RewriteRules};
+use crate::session::SessionTokens;
+use crate::tls::TlsTerminator;
 
 /// JWT claims — must match the gateway's structure.
 #[derive(Debug, Serialize, Deserialize)]
@@ -21,6 +33,29 @@ pub struct Claims {
     pub jti: String,
 }
 
+/// Device token claims — issued per device and presented on `/device/connect`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceClaims {
+    /// Subject — the device ID the token was issued for.
+    pub sub: Uuid,
+    pub exp: u64,
+}
+
+/// Issue an `HS256` device token valid for `ttl_secs`.
+pub fn issue_device_token(secret: &str, device_id: Uuid, ttl_secs: u64) -> Result<String> {
+    let claims = DeviceClaims {
+        sub: device_id,
+        exp: now_sec() + ttl_secs,
+    };
+
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .map_err(Into::into)
+}
+
 // ── Builder ───────────────────────────────────────────────────────────────
 
 pub struct TunnelStateBuilder<MandatoryFields = (CancellationToken, RedisClient)> {
@@ -34,14 +69,96 @@ impl TunnelStateBuilder {
         let decoding_key = DecodingKey::from_rsa_pem(secrets.jwt_public_key.as_bytes())
             .context("invalid JWT public key")?;
 
+        let device_decoding_key = DecodingKey::from_secret(secrets.device_token_secret.as_bytes());
+
+        let session_tokens = match self.config.api.session_token_mode {
+            SessionTokenMode::Opaque => None,
+            SessionTokenMode::Signed => {
+                let secret = secrets
+                    .session_token_secret
+                    .as_deref()
+                    .context("SESSION_TOKEN_SECRET not set")?;
+                Some(Arc::new(SessionTokens::new(
+                    secret,
+                    self.config.api.session_idle_timeout_secs,
+                )?))
+            }
+        };
+
+        let admin_token = secrets.admin_api_token.clone();
+        let identity_secret = if self.config.api.forward_identity {
+            Some(
+                secrets
+                    .identity_header_secret
+                    .clone()
+                    .context("IDENTITY_HEADER_SECRET not set")?,
+            )
+        } else {
+            None
+        };
+
+        let auditor = Auditor::new(&self.config.audit)
+            .context("failed to set up audit sinks")?
+            .map(Arc::new);
+
+        let error_pages =
+            ErrorPages::new(&self.config.api.error_pages).context("failed to load error pages")?;
+
+        let rewrite_rules = RewriteRules::new(&self.config.api.response_rewrite)
+            .context("invalid response_rewrite config")?;
+
+        let tls = TlsTerminator::new(&self.config.api.tls)
+            .context("failed to load TLS certificate")?
+            .map(Arc::new);
+
         let (shutdown, redis_client) = self.mandatory_fields;
 
+        let bandwidth = self.config.api.bandwidth.enabled.then(|| {
+            Arc::new(Bandwidth::new(
+                self.config.api.bandwidth.clone(),
+                redis_client.clone(),
+            ))
+        });
+
+        let cluster = if self.config.cluster.enabled {
+            let cluster = Cluster::new(
+                &self.config.cluster,
+                self.config.api.internal_listen_addr,
+                redis_client.clone(),
+            )?;
+            Some(Arc::new(cluster))
+        } else {
+            None
+        };
+
+        let identity = identity_secret
+            .map(|secret| Arc::new(IdentityHeaders::new(secret, redis_client.clone())));
+
+        let presence = Presence::new(
+            &self.config.presence,
+            redis_client.clone(),
+            cluster.as_ref().map(|cluster| cluster.node_id().to_owned()),
+        )
+        .context("failed to set up presence sinks")?
+        .map(Arc::new);
+
         Ok(TunnelState {
             inner: Arc::new(Inner {
                 config: self.config,
                 decoding_key,
-                registry: Arc::new(DeviceRegistry::new()),
+                device_decoding_key,
+                registry: Arc::new(DeviceRegistry::new(presence.clone())),
+                presence,
                 redis_client,
+                cluster,
+                session_tokens,
+                admin_token,
+                identity,
+                auditor,
+                bandwidth,
+                rewrite_rules: Arc::new(rewrite_rules),
+                error_pages,
+                tls,
                 shutdown,
             }),
         })
@@ -104,10 +221,112 @@ impl TunnelState {
         &self.inner.registry
     }
 
+    /// Set when identity headers are forwarded to devices.
+    pub fn identity(&self) -> Option<&Arc<IdentityHeaders>> {
+        self.inner.identity.as_ref()
+    }
+
+    pub fn presence(&self) -> Option<&Arc<Presence>> {
+        self.inner.presence.as_ref()
+    }
+
     pub fn redis(&self) -> &RedisClient {
         &self.inner.redis_client
     }
 
+    pub fn cluster(&self) -> Option<&Arc<Cluster>> {
+        self.inner.cluster.as_ref()
+    }
+
+    /// Signed session token issuer, set in `signed` session token mode.
+    pub fn session_tokens(&self) -> Option<&Arc<SessionTokens>> {
+        self.inner.session_tokens.as_ref()
+    }
+
+    /// Audit record writer, set when any audit sink is configured.
+    pub fn auditor(&self) -> Option<&Arc<Auditor>> {
+        self.inner.auditor.as_ref()
+    }
+
+    /// Start the audit record of a stream; a no-op guard when auditing is disabled.
+    pub fn audit_stream(
+        &self,
+        stream_id: Uuid,
+        user_id: &str,
+        device_id: Uuid,
+        method: &str,
+        path_and_query: &str,
+    ) -> StreamAudit {
+        match self.auditor() {
+            Some(auditor) => auditor.start(stream_id, user_id, device_id, method, path_and_query),
+            None => StreamAudit::disabled(),
+        }
+    }
+
+    /// Bandwidth limiter, set when bandwidth limits are enabled.
+    pub fn bandwidth(&self) -> Option<&Arc<Bandwidth>> {
+        self.inner.bandwidth.as_ref()
+    }
+
+    /// Limits for a new stream; fails when the device has used up its monthly cap.
+    pub async fn stream_limiter(&self, device_id: Uuid, user_id: &str) -> Result<StreamLimiter> {
+        match self.bandwidth() {
+            Some(bandwidth) => bandwidth.stream_limiter(device_id, user_id).await,
+            None => Ok(StreamLimiter::unlimited()),
+        }
+    }
+
+    /// Rewriter adapting device responses to the session of `token`.
+    pub fn response_rewriter(&self, token: &str) -> ResponseRewriter {
+        ResponseRewriter::new(self.inner.rewrite_rules.clone(), self.api_config(), token)
+    }
+
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.inner.error_pages
+    }
+
+    /// TLS terminator, set when `api.tls` is enabled.
+    pub fn tls(&self) -> Option<&Arc<TlsTerminator>> {
+        self.inner.tls.as_ref()
+    }
+
+    /// Cancel the in-flight streams of a closed session on whichever node holds
+    /// the device.
+    pub async fn close_session_streams(&self, device_id: Uuid, token: &str) -> Result<()> {
+        if let Some(session) = self.registry().get(device_id) {
+            session
+                .abort_session_streams(token, SESSION_REVOKED_MESSAGE)
+                .await;
+        }
+
+        if self.cluster().is_some() {
+            self.redis()
+                .publish_session_closed(device_id, token)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the device is connected to this node or, in cluster mode, to any node.
+    pub async fn is_device_online(&self, device_id: Uuid) -> Result<bool> {
+        if self.registry().is_online(device_id) {
+            return Ok(true);
+        }
+
+        match self.cluster() {
+            Some(cluster) => cluster.is_online(device_id).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Whether `token` is the admin API token. Always false when none is configured.
+    pub fn verify_admin_token(&self, token: &str) -> bool {
+        self.inner
+            .admin_token
+            .as_deref()
+            .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(token.as_bytes())))
+    }
+
     pub fn decode_jwt(&self, token: &str) -> Result<Claims> {
         let mut validation = Validation::new(Algorithm::RS256);
         validation.validate_exp = true;
@@ -116,10 +335,22 @@ impl TunnelState {
             .map_err(Into::into)
     }
 
+    pub fn decode_device_token(&self, token: &str) -> Result<DeviceClaims> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_exp = true;
+        decode::<DeviceClaims>(token, &self.inner.device_decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(Into::into)
+    }
+
     pub async fn bind_socket(&self) -> std::io::Result<TcpListener> {
         TcpListener::bind(self.api_config().listen_addr).await
     }
 
+    pub async fn bind_internal_socket(&self) -> std::io::Result<TcpListener> {
+        TcpListener::bind(self.api_config().internal_listen_addr).await
+    }
+
     pub fn shutdown_token(&self) -> CancellationToken {
         self.inner.shutdown.clone()
     }
@@ -127,12 +358,27 @@ impl TunnelState {
     pub async fn bind_endpoint(&self) -> Result<TunnelEndpoint> {
         TunnelEndpoint::builder().bind(self.clone()).await
     }
+
+    pub async fn bind_internal_endpoint(&self) -> Result<TunnelEndpoint> {
+        TunnelEndpoint::builder().bind_internal(self.clone()).await
+    }
 }
 
 struct Inner {
     config: AppConfig,
     decoding_key: DecodingKey,
+    device_decoding_key: DecodingKey,
     registry: Arc<DeviceRegistry>,
+    presence: Option<Arc<Presence>>,
     redis_client: RedisClient,
+    cluster: Option<Arc<Cluster>>,
+    session_tokens: Option<Arc<SessionTokens>>,
+    admin_token: Option<String>,
+    identity: Option<Arc<IdentityHeaders>>,
+    auditor: Option<Arc<Auditor>>,
+    bandwidth: Option<Arc<Bandwidth>>,
+    rewrite_rules: Arc<RewriteRules>,
+    error_pages: ErrorPages,
+    tls: Option<Arc<TlsTerminator>>,
     shutdown: CancellationToken,
 }
@@ -0,0 +1,113 @@
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::registry::DeviceRegistry;
+
+/// Frames exchanged with devices, labeled by `direction`.
+pub const FRAMES_TOTAL: &str = "tunnel_frames_total";
+/// Body and stream data bytes exchanged with devices, labeled by `direction`.
+pub const BYTES_TOTAL: &str = "tunnel_bytes_total";
+/// Streams refused by `register_stream`, labeled by `reason`.
+pub const STREAM_REJECTIONS_TOTAL: &str = "tunnel_stream_rejections_total";
+/// Streams cancelled by the server.
+pub const STREAM_CANCELLATIONS_TOTAL: &str = "tunnel_stream_cancellations_total";
+/// Requests that gave up waiting for the device response head.
+pub const RESPONSE_HEAD_TIMEOUTS_TOTAL: &str = "tunnel_response_head_timeouts_total";
+/// Redis round trip latency on the request path, labeled by `op`.
+pub const REDIS_DURATION_SECONDS: &str = "tunnel_redis_duration_seconds";
+/// Device connections dropped for not answering pings.
+pub const DEVICE_PONG_TIMEOUTS_TOTAL: &str = "tunnel_device_pong_timeouts_total";
+/// Audit records that never reached a sink, labeled by `reason`.
+pub const AUDIT_RECORDS_DROPPED_TOTAL: &str = "tunnel_audit_records_dropped_total";
+/// Streams refused or cancelled for breaking a request limit, labeled by `limit`.
+pub const LIMIT_VIOLATIONS_TOTAL: &str = "tunnel_limit_violations_total";
+
+pub const DIRECTION_TO_DEVICE: &str = "to_device";
+pub const DIRECTION_FROM_DEVICE: &str = "from_device";
+
+const REDIS_DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the global Prometheus recorder backing `/metrics`.
+pub fn install() -> Result<()> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REDIS_DURATION_SECONDS.to_owned()),
+            REDIS_DURATION_BUCKETS,
+        )?
+        .install_recorder()
+        .context("failed to install metrics recorder")?;
+
+    let _ = HANDLE.set(handle);
+    Ok(())
+}
+
+/// Render recorded metrics plus gauges read straight from the registry, so
+/// devices that disconnected leave no stale series behind.
+pub fn render(registry: &DeviceRegistry) -> String {
+    let mut output = match HANDLE.get() {
+        Some(handle) => {
+            handle.run_upkeep();
+            handle.render()
+        }
+        None => String::new(),
+    };
+
+    let sessions = registry.sessions();
+
+    let _ = writeln!(
+        output,
+        "# HELP tunnel_connected_devices Devices with an open tunnel connection."
+    );
+    let _ = writeln!(output, "# TYPE tunnel_connected_devices gauge");
+    let _ = writeln!(output, "tunnel_connected_devices {}", sessions.len());
+
+    let _ = writeln!(
+        output,
+        "# HELP tunnel_active_streams In-flight streams per device."
+    );
+    let _ = writeln!(output, "# TYPE tunnel_active_streams gauge");
+    for (device_id, session) in &sessions {
+        let _ = writeln!(
+            output,
+            "tunnel_active_streams{{device_id=\"{device_id}\"}} {}",
+            session.active_streams()
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "# HELP tunnel_device_last_seen_seconds Seconds since the last message from each device."
+    );
+    let _ = writeln!(output, "# TYPE tunnel_device_last_seen_seconds gauge");
+    for (device_id, session) in &sessions {
+        let _ = writeln!(
+            output,
+            "tunnel_device_last_seen_seconds{{device_id=\"{device_id}\"}} {}",
+            session.silence().as_secs_f64()
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "# HELP tunnel_device_rtt_seconds Round trip time of the last answered ping per device."
+    );
+    let _ = writeln!(output, "# TYPE tunnel_device_rtt_seconds gauge");
+    for (device_id, session) in &sessions {
+        if let Some(rtt) = session.rtt() {
+            let _ = writeln!(
+                output,
+                "tunnel_device_rtt_seconds{{device_id=\"{device_id}\"}} {}",
+                rtt.as_secs_f64()
+            );
+        }
+    }
+
+    output
+}
@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerConfig, WebPkiClientVerifier};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+use crate::config::TlsConfig;
+
+/// Client certificate of a connection, verified against `device_client_ca_path`
+/// during the handshake.
+#[derive(Debug, Clone)]
+pub struct VerifiedClientCert {
+    /// DNS and URI subject alternative names, then the subject common names.
+    names: Vec<String>,
+}
+
+impl VerifiedClientCert {
+    /// Read the names of a leaf certificate. `None` if it does not parse.
+    pub fn from_der(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+
+        let mut names = Vec::new();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) | GeneralName::URI(name) => {
+                        names.push((*name).to_owned());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for cn in cert.subject().iter_common_name() {
+            if let Ok(cn) = cn.as_str() {
+                names.push(cn.to_owned());
+            }
+        }
+
+        Some(Self { names })
+    }
+
+    /// Whether the certificate was issued for `name`, e.g. a device ID.
+    pub fn is_issued_for(&self, name: &str) -> bool {
+        self.names
+            .iter()
+            .any(|issued| issued.eq_ignore_ascii_case(name))
+    }
+}
+
+// ── Terminator ────────────────────────────────────────────────────────────
+
+/// TLS settings for new connections, reloaded when the certificate, key or CA
+/// files change. Connections already open keep the settings they started with.
+pub struct TlsTerminator {
+    config: TlsConfig,
+    server_config: RwLock<Arc<ServerConfig>>,
+    /// Modification times of the files behind `server_config`.
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl TlsTerminator {
+    /// Load the configured files. Returns `None` when TLS is disabled.
+    pub fn new(config: &TlsConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let modified = modified(&files(config));
+        let server_config = load(config)?;
+        Ok(Some(Self {
+            config: config.clone(),
+            server_config: RwLock::new(server_config),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.read().expect("tls config lock").clone())
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.handshake_timeout_secs)
+    }
+
+    /// Whether devices must connect with a client certificate.
+    pub fn verifies_devices(&self) -> bool {
+        self.config.device_client_ca_path.is_some()
+    }
+
+    /// Check the files every `reload_interval_secs` until `token` is cancelled.
+    pub async fn run_reload(self: Arc<Self>, token: CancellationToken) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.reload_interval_secs.max(1)));
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return,
+                _ = interval.tick() => self.reload_if_changed(),
+            }
+        }
+    }
+
+    /// A failed load keeps the current settings and is retried on the next
+    /// check, e.g. when the certificate was replaced before its key.
+    fn reload_if_changed(&self) {
+        let modified = modified(&files(&self.config));
+        if *self.modified.lock().expect("tls modified lock") == modified {
+            return;
+        }
+
+        match load(&self.config) {
+            Ok(server_config) => {
+                *self.server_config.write().expect("tls config lock") = server_config;
+                *self.modified.lock().expect("tls modified lock") = modified;
+                tracing::info!(cert_path = %self.config.cert_path.display(), "TLS certificate reloaded");
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "failed to reload TLS certificate, keeping the current one: {err:#}"
+                );
+            }
+        }
+    }
+}
+
+fn files(config: &TlsConfig) -> Vec<PathBuf> {
+    let mut files = vec![config.cert_path.clone(), config.key_path.clone()];
+    files.extend(config.device_client_ca_path.clone());
+    files
+}
+
+/// Follows symlinks, so Kubernetes secret volumes swapping their data directory
+/// count as a change.
+fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
+}
+
+fn load(config: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+
+    let certs = load_certs(&config.cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .with_context(|| format!("failed to read TLS key {}", config.key_path.display()))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("unsupported TLS protocol versions")?;
+    let builder = match &config.device_client_ca_path {
+        // Browsers share the listener, so certificates are checked when presented
+        // and only required by `/device/connect`.
+        Some(ca_path) => builder.with_client_cert_verifier(client_verifier(ca_path, provider)?),
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .context("TLS key does not match the certificate")?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(server_config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificates in {}", path.display());
+    Ok(certs)
+}
+
+fn client_verifier(
+    ca_path: &Path,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid CA certificate in {}", ca_path.display()))?;
+    }
+
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .allow_unauthenticated()
+        .build()
+        .context("failed to build client certificate verifier")
+}
@@ -0,0 +1,392 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use anyhow::{Context, Result, ensure};
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderValue, Method};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::time::now_sec;
+
+// ── Headers ──────────────────────────────────────────────────────────────
+
+/// Nexus user browsing the device. Not set for share links.
+pub const USER_ID_HEADER: &str = "x-nexus-user-id";
+/// Email of the Nexus user browsing the device. Not set for share links.
+pub const USER_EMAIL_HEADER: &str = "x-nexus-user-email";
+/// Stable, non-secret identifier of the tunnel session.
+pub const SESSION_ID_HEADER: &str = "x-nexus-session-id";
+/// `owner` for the device owner's sessions, `share` for share links.
+pub const ACCESS_HEADER: &str = "x-nexus-access";
+/// Unix timestamp of the signature, in seconds.
+pub const TIMESTAMP_HEADER: &str = "x-nexus-identity-timestamp";
+/// Hex HMAC-SHA256 over the identity and the request, keyed with the device key.
+pub const SIGNATURE_HEADER: &str = "x-nexus-identity-signature";
+
+/// Every header tunnel-server sets; client-supplied copies are dropped.
+pub const IDENTITY_HEADERS: [&str; 6] = [
+    USER_ID_HEADER,
+    USER_EMAIL_HEADER,
+    SESSION_ID_HEADER,
+    ACCESS_HEADER,
+    TIMESTAMP_HEADER,
+    SIGNATURE_HEADER,
+];
+
+const SIGNATURE_VERSION: &str = "v1";
+
+/// Who a tunnelled request is made on behalf of.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    pub email: String,
+    pub session_id: String,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Access {
+    #[default]
+    Owner,
+    Share,
+}
+
+impl Access {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Owner => "owner",
+            Self::Share => "share",
+        }
+    }
+}
+
+/// Key a device verifies identity headers with, derived from the server secret so
+/// each device only ever learns its own.
+pub fn device_key(secret: &str, device_id: Uuid) -> String {
+    let mut mac = new_mac(secret.as_bytes());
+    mac.update(b"nexus-identity-key:");
+    mac.update(device_id.as_bytes());
+    to_hex(&mac.finalize().into_bytes())
+}
+
+/// Identifier of a session that does not reveal its token.
+pub fn session_id(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes())[..16])
+}
+
+/// Drop any identity headers, e.g. ones sent by the browser.
+pub fn strip_identity(headers: &mut HeaderMap) {
+    for name in IDENTITY_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Replace the identity headers with `identity`, signed for this request.
+pub fn insert_identity(
+    headers: &mut HeaderMap,
+    device_key: &str,
+    identity: &Identity,
+    method: &Method,
+    path_and_query: &str,
+) {
+    strip_identity(headers);
+
+    // Sign only what can be sent, or the device could never verify it.
+    let mut identity = identity.clone();
+    for value in [
+        &mut identity.user_id,
+        &mut identity.email,
+        &mut identity.session_id,
+    ] {
+        if HeaderValue::from_str(value).is_err() {
+            value.clear();
+        }
+    }
+
+    let timestamp = now_sec();
+    let signature = signature(device_key, &identity, timestamp, method, path_and_query);
+
+    let fields = [
+        (USER_ID_HEADER, identity.user_id.as_str()),
+        (USER_EMAIL_HEADER, identity.email.as_str()),
+        (SESSION_ID_HEADER, identity.session_id.as_str()),
+        (ACCESS_HEADER, identity.access.as_str()),
+    ];
+    for (name, value) in fields {
+        if !value.is_empty() {
+            headers.insert(name, HeaderValue::from_str(value).expect("checked above"));
+        }
+    }
+    headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+    headers.insert(
+        SIGNATURE_HEADER,
+        HeaderValue::from_str(&signature).expect("hex signature"),
+    );
+}
+
+/// Check the identity headers of a request against the device key. Fails on a
+/// missing or wrong signature, or one older than `max_age`.
+pub fn verify_identity(
+    headers: &HeaderMap,
+    device_key: &str,
+    method: &Method,
+    path_and_query: &str,
+    max_age: Duration,
+) -> Result<Identity> {
+    let header = |name: &str| -> Result<&str> {
+        match headers.get(name) {
+            Some(value) => value.to_str().with_context(|| format!("invalid {name}")),
+            None => Ok(""),
+        }
+    };
+
+    let signature_header = header(SIGNATURE_HEADER)?;
+    ensure!(!signature_header.is_empty(), "missing identity signature");
+    let timestamp: u64 = header(TIMESTAMP_HEADER)?
+        .parse()
+        .context("invalid identity timestamp")?;
+    ensure!(
+        now_sec().abs_diff(timestamp) <= max_age.as_secs(),
+        "identity signature expired"
+    );
+
+    let access = match header(ACCESS_HEADER)? {
+        "owner" => Access::Owner,
+        "share" => Access::Share,
+        other => anyhow::bail!("unknown access {other:?}"),
+    };
+    let identity = Identity {
+        user_id: header(USER_ID_HEADER)?.to_owned(),
+        email: header(USER_EMAIL_HEADER)?.to_owned(),
+        session_id: header(SESSION_ID_HEADER)?.to_owned(),
+        access,
+    };
+
+    let expected = signature(device_key, &identity, timestamp, method, path_and_query);
+    ensure!(
+        bool::from(expected.as_bytes().ct_eq(signature_header.as_bytes())),
+        "invalid identity signature"
+    );
+
+    Ok(identity)
+}
+
+/// The signature binds the identity to one method and path, so it cannot be
+/// replayed onto other requests.
+fn signature(
+    device_key: &str,
+    identity: &Identity,
+    timestamp: u64,
+    method: &Method,
+    path_and_query: &str,
+) -> String {
+    let mut mac = new_mac(device_key.as_bytes());
+    let message = [
+        SIGNATURE_VERSION,
+        &timestamp.to_string(),
+        method.as_str(),
+        path_and_query,
+        &identity.user_id,
+        &identity.email,
+        &identity.session_id,
+        identity.access.as_str(),
+    ]
+    .join("\n");
+    mac.update(message.as_bytes());
+    to_hex(&mac.finalize().into_bytes())
+}
+
+fn new_mac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+    const PATH: &str = "/api/items?page=2";
+
+    fn key() -> String {
+        device_key("server-secret", Uuid::from_u128(1))
+    }
+
+    fn owner() -> Identity {
+        Identity {
+            user_id: "user-1".to_owned(),
+            email: "user@example.com".to_owned(),
+            session_id: session_id("token"),
+            access: Access::Owner,
+        }
+    }
+
+    fn signed(identity: &Identity) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        insert_identity(&mut headers, &key(), identity, &Method::GET, PATH);
+        headers
+    }
+
+    fn verify(headers: &HeaderMap) -> Result<Identity> {
+        verify_identity(headers, &key(), &Method::GET, PATH, MAX_AGE)
+    }
+
+    /// Headers as `insert_identity` would have set them at `timestamp`.
+    fn signed_at(identity: &Identity, timestamp: u64) -> HeaderMap {
+        let mut headers = signed(identity);
+        let signature = signature(&key(), identity, timestamp, &Method::GET, PATH);
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+        headers
+    }
+
+    #[test]
+    fn signed_identity_verifies() {
+        let headers = signed(&owner());
+        assert_eq!(headers[ACCESS_HEADER], "owner");
+        assert_eq!(verify(&headers).unwrap(), owner());
+    }
+
+    #[test]
+    fn share_identity_omits_the_user() {
+        let share = Identity {
+            session_id: session_id("share-token"),
+            access: Access::Share,
+            ..Identity::default()
+        };
+        let headers = signed(&share);
+
+        assert!(!headers.contains_key(USER_ID_HEADER));
+        assert!(!headers.contains_key(USER_EMAIL_HEADER));
+        assert_eq!(verify(&headers).unwrap(), share);
+    }
+
+    #[test]
+    fn client_supplied_identity_is_replaced() {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_ID_HEADER, HeaderValue::from_static("admin"));
+        headers.insert(
+            USER_EMAIL_HEADER,
+            HeaderValue::from_static("admin@example.com"),
+        );
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_static("00"));
+
+        let share = Identity {
+            access: Access::Share,
+            ..Identity::default()
+        };
+        insert_identity(&mut headers, &key(), &share, &Method::GET, PATH);
+
+        assert!(!headers.contains_key(USER_ID_HEADER));
+        assert!(!headers.contains_key(USER_EMAIL_HEADER));
+        assert_eq!(verify(&headers).unwrap(), share);
+
+        strip_identity(&mut headers);
+        assert!(
+            IDENTITY_HEADERS
+                .iter()
+                .all(|name| !headers.contains_key(*name))
+        );
+    }
+
+    #[test]
+    fn values_that_are_not_header_safe_are_signed_empty() {
+        let identity = Identity {
+            email: "user\n@example.com".to_owned(),
+            ..owner()
+        };
+        let headers = signed(&identity);
+
+        assert!(!headers.contains_key(USER_EMAIL_HEADER));
+        assert_eq!(
+            verify(&headers).unwrap(),
+            Identity {
+                email: String::new(),
+                ..owner()
+            }
+        );
+    }
+
+    #[test]
+    fn tampered_headers_are_rejected() {
+        for (name, value) in [
+            (USER_ID_HEADER, "user-2"),
+            (USER_EMAIL_HEADER, "other@example.com"),
+            (SESSION_ID_HEADER, "0000"),
+            (ACCESS_HEADER, "share"),
+        ] {
+            let mut headers = signed(&owner());
+            headers.insert(name, HeaderValue::from_static(value));
+            assert!(verify(&headers).is_err(), "{name} changed");
+
+            let mut headers = signed(&owner());
+            headers.remove(name);
+            assert!(verify(&headers).is_err(), "{name} removed");
+        }
+
+        let mut headers = signed(&owner());
+        let timestamp: u64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp - 1));
+        assert!(verify(&headers).is_err());
+    }
+
+    #[test]
+    fn signatures_are_bound_to_the_request() {
+        let headers = signed(&owner());
+        assert!(verify_identity(&headers, &key(), &Method::POST, PATH, MAX_AGE).is_err());
+        assert!(verify_identity(&headers, &key(), &Method::GET, "/api/items", MAX_AGE).is_err());
+
+        let other_key = device_key("server-secret", Uuid::from_u128(2));
+        assert!(verify_identity(&headers, &other_key, &Method::GET, PATH, MAX_AGE).is_err());
+    }
+
+    #[test]
+    fn stale_and_future_signatures_are_rejected() {
+        let now = now_sec();
+        assert!(verify(&signed_at(&owner(), now - 30)).is_ok());
+        assert!(verify(&signed_at(&owner(), now - 120)).is_err());
+        assert!(verify(&signed_at(&owner(), now + 120)).is_err());
+    }
+
+    #[test]
+    fn missing_or_malformed_fields_are_rejected() {
+        let mut headers = signed(&owner());
+        headers.remove(SIGNATURE_HEADER);
+        assert!(verify(&headers).is_err());
+
+        let mut headers = signed(&owner());
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from_static("soon"));
+        assert!(verify(&headers).is_err());
+
+        let mut headers = signed(&owner());
+        headers.insert(ACCESS_HEADER, HeaderValue::from_static("admin"));
+        assert!(verify(&headers).is_err());
+
+        assert!(verify(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn keys_and_session_ids_are_stable_hex() {
+        assert_eq!(key(), device_key("server-secret", Uuid::from_u128(1)));
+        assert_ne!(key(), device_key("other-secret", Uuid::from_u128(1)));
+        assert_eq!(key().len(), 64);
+
+        let id = session_id("token");
+        assert_eq!(id, session_id("token"));
+        assert_ne!(id, session_id("other-token"));
+        assert_eq!(id.len(), 32);
+        assert!(
+            id.bytes()
+                .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+        );
+    }
+}
@@ -1,5 +1,7 @@
+pub mod identity;
 pub mod logger;
 pub mod serde;
 pub mod signal;
 pub mod time;
+pub mod trace;
 pub mod tunnel;
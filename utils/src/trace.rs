@@ -0,0 +1,261 @@
+use std::fmt;
+
+use http::{HeaderMap, HeaderValue};
+use uuid::Uuid;
+
+// ── Headers ──────────────────────────────────────────────────────────────
+
+/// Identifier of one browser request, echoed on the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// W3C Trace Context parent, see <https://www.w3.org/TR/trace-context/>.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Longest client-supplied request ID that is kept.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+const TRACE_VERSION: u8 = 0;
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// The request ID sent by the client, if it is usable, or a new one.
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned)
+}
+
+/// Visible ASCII only, so the ID can be logged and sent on as a header.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// ── Trace context ────────────────────────────────────────────────────────
+
+/// Position of one hop in a distributed trace: the trace it belongs to and the
+/// span that is the parent of the next hop.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    flags: u8,
+}
+
+impl TraceContext {
+    /// Start a new sampled trace.
+    pub fn new() -> Self {
+        Self {
+            trace_id: random_u128(),
+            span_id: random_u128() as u64 | 1,
+            flags: FLAG_SAMPLED,
+        }
+    }
+
+    /// Parse a `traceparent` value. Versions above `00` are read as `00`, as the
+    /// specification asks; invalid values yield `None`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let fields = value.get(..55)?;
+        let rest = &value[55..];
+
+        let mut parts = fields.split('-');
+        let version = parse_hex(parts.next()?, 2)? as u8;
+        let trace_id = parse_hex(parts.next()?, 32)?;
+        let span_id = parse_hex(parts.next()?, 16)? as u64;
+        let flags = parse_hex(parts.next()?, 2)? as u8;
+
+        let valid_rest = match version {
+            TRACE_VERSION => rest.is_empty(),
+            0xff => false,
+            _ => rest.is_empty() || rest.starts_with('-'),
+        };
+        if !valid_rest || trace_id == 0 || span_id == 0 {
+            return None;
+        }
+
+        Some(Self {
+            trace_id,
+            span_id,
+            flags,
+        })
+    }
+
+    /// The context sent by the client, if it is valid.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::parse)
+    }
+
+    /// A new span in the same trace, for the next hop.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_u128() as u64 | 1,
+            ..*self
+        }
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    pub fn to_header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.to_string()).expect("traceparent is ASCII")
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{TRACE_VERSION:02x}-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+/// Lowercase hex of exactly `len` digits.
+fn parse_hex(value: &str, len: usize) -> Option<u128> {
+    if value.len() != len
+        || !value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    u128::from_str_radix(value, 16).ok()
+}
+
+fn random_u128() -> u128 {
+    Uuid::new_v4().as_u128()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn traceparent_round_trips() {
+        let context = TraceContext::parse(TRACEPARENT).unwrap();
+        assert_eq!(context.trace_id(), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(context.to_string(), TRACEPARENT);
+        assert_eq!(context.to_header_value(), TRACEPARENT);
+        assert_eq!(
+            TraceContext::parse(&format!(" {TRACEPARENT} ")),
+            Some(context)
+        );
+    }
+
+    #[test]
+    fn invalid_traceparents_are_ignored() {
+        for value in [
+            "",
+            "00",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            // Uppercase hex.
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            // Zero trace and span IDs.
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            // Wrong separators and field lengths.
+            "00_0af7651916cd43dd8448eb211c80319c_b7ad6b7169203331_01",
+            "00-0af7651916cd43dd8448eb211c80319-cb7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333g-01",
+            "0x-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            // Version 00 has nothing after the flags.
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            // Version ff is forbidden.
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            // Multi-byte characters where a boundary would fall.
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-0é",
+        ] {
+            assert_eq!(TraceContext::parse(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn future_versions_are_read_as_version_00() {
+        let future = "cc-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let context = TraceContext::parse(future).unwrap();
+        assert_eq!(context.to_string(), TRACEPARENT);
+        assert_eq!(
+            TraceContext::parse(&format!("{future}-what-the-future-holds")),
+            Some(context)
+        );
+        assert_eq!(TraceContext::parse(&format!("{future}x")), None);
+    }
+
+    #[test]
+    fn child_stays_in_the_trace() {
+        let parent = TraceContext::parse(TRACEPARENT).unwrap();
+        let child = parent.child();
+        assert_eq!(child.trace_id(), parent.trace_id());
+        assert_ne!(child, parent);
+        assert!(child.to_string().ends_with("-01"));
+        assert_eq!(TraceContext::parse(&child.to_string()), Some(child));
+    }
+
+    #[test]
+    fn new_traces_are_valid_and_sampled() {
+        let context = TraceContext::new();
+        assert_eq!(TraceContext::parse(&context.to_string()), Some(context));
+        assert!(context.to_string().ends_with("-01"));
+        assert_ne!(context.trace_id(), TraceContext::new().trace_id());
+    }
+
+    #[test]
+    fn traceparent_is_read_from_headers() {
+        assert_eq!(
+            TraceContext::from_headers(&headers(TRACEPARENT_HEADER, TRACEPARENT)),
+            TraceContext::parse(TRACEPARENT)
+        );
+        assert_eq!(
+            TraceContext::from_headers(&headers(TRACEPARENT_HEADER, "garbage")),
+            None
+        );
+        assert_eq!(TraceContext::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn usable_request_ids_are_kept() {
+        for id in ["abc-123", "x", &"a".repeat(MAX_REQUEST_ID_LEN)] {
+            assert_eq!(request_id(&headers(REQUEST_ID_HEADER, id)), id);
+        }
+    }
+
+    #[test]
+    fn unusable_request_ids_are_replaced() {
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for id in ["", "with space", "tab\there", too_long.as_str()] {
+            let generated = request_id(&headers(REQUEST_ID_HEADER, id));
+            assert_ne!(generated, id);
+            assert!(Uuid::parse_str(&generated).is_ok(), "{id:?}");
+        }
+
+        let mut non_ascii = HeaderMap::new();
+        non_ascii.insert(
+            REQUEST_ID_HEADER,
+            HeaderValue::from_bytes("é".as_bytes()).unwrap(),
+        );
+        assert!(Uuid::parse_str(&request_id(&non_ascii)).is_ok());
+
+        let generated = request_id(&HeaderMap::new());
+        assert!(Uuid::parse_str(&generated).is_ok());
+        assert_ne!(generated, request_id(&HeaderMap::new()));
+    }
+}
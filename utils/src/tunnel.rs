@@ -1,22 +1,216 @@
+use std::sync::Arc;
+
 use anyhow::{Result, bail, ensure};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use http::header::HeaderName;
 use http::uri::PathAndQuery;
 use http::{HeaderMap, HeaderValue, Method};
+use tokio::sync::Semaphore;
 use uuid::Uuid;
 
 pub type Headers = HeaderMap<HeaderValue>;
 
+// ── Protocol ─────────────────────────────────────────────────────────────
+
+/// Wire protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest peer protocol version this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Set of optional protocol features a peer supports.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// HTTP upgrades (e.g. WebSocket) carried as a raw bidirectional byte stream.
+    pub const UPGRADE: Self = Self(1 << 0);
+
+    /// Per-stream credit-based flow control via `WindowUpdate` frames.
+    pub const FLOW_CONTROL: Self = Self(1 << 1);
+
+    /// Raw TCP streams to device-side targets opened with `OpenTcpStream`.
+    pub const TCP: Self = Self(1 << 2);
+
+    /// Request ID and W3C trace context carried in `OpenStream`.
+    pub const TRACE_CONTEXT: Self = Self(1 << 3);
+
+    /// Capabilities implemented by this build.
+    pub const SUPPORTED: Self =
+        Self(Self::UPGRADE.0 | Self::FLOW_CONTROL.0 | Self::TCP.0 | Self::TRACE_CONTEXT.0);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Parameters agreed on during the `Hello` exchange.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u16,
+    pub peer_version: String,
+    pub capabilities: Capabilities,
+}
+
+impl Handshake {
+    /// Validate the peer `Hello` and negotiate the common protocol version and capabilities.
+    pub fn negotiate(frame: Frame) -> Result<Self> {
+        let Frame::Hello {
+            protocol_version,
+            version,
+            capabilities,
+        } = frame
+        else {
+            bail!("expected hello frame");
+        };
+
+        ensure!(
+            protocol_version >= MIN_PROTOCOL_VERSION,
+            "unsupported protocol version {protocol_version}, minimum is {MIN_PROTOCOL_VERSION}"
+        );
+
+        Ok(Self {
+            protocol_version: protocol_version.min(PROTOCOL_VERSION),
+            peer_version: version,
+            capabilities: capabilities.intersection(Capabilities::SUPPORTED),
+        })
+    }
+
+    /// Whether a frame received from or sent to the peer is allowed by the negotiated capabilities.
+    pub fn allows(&self, frame: &Frame) -> bool {
+        self.capabilities.contains(frame.required_capabilities())
+    }
+}
+
+/// Whether the headers request an HTTP upgrade (`Connection: upgrade` plus `Upgrade`).
+pub fn is_upgrade_request(headers: &Headers) -> bool {
+    let connection_upgrade = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    connection_upgrade && headers.contains_key(http::header::UPGRADE)
+}
+
+// ── Flow control ─────────────────────────────────────────────────────────
+
+/// Bytes of body or stream data each side may send per stream before it
+/// must wait for a `WindowUpdate` from the receiver.
+pub const INITIAL_WINDOW_SIZE: u32 = 256 * 1024;
+
+/// Per-stream send credit, replenished by the peer's `WindowUpdate` frames.
+#[derive(Clone)]
+pub struct SendWindow {
+    credit: Option<Arc<Semaphore>>,
+}
+
+impl SendWindow {
+    /// Window starting at [`INITIAL_WINDOW_SIZE`].
+    pub fn new() -> Self {
+        Self {
+            credit: Some(Arc::new(Semaphore::new(INITIAL_WINDOW_SIZE as usize))),
+        }
+    }
+
+    /// Window that never blocks, for peers without flow control.
+    pub fn unlimited() -> Self {
+        Self { credit: None }
+    }
+
+    /// Wait until `len` bytes of credit are available and consume them.
+    pub async fn reserve(&self, len: usize) -> Result<()> {
+        let Some(credit) = &self.credit else {
+            return Ok(());
+        };
+
+        ensure!(
+            len <= INITIAL_WINDOW_SIZE as usize,
+            "chunk of {len} bytes exceeds the stream window"
+        );
+
+        credit
+            .acquire_many(len as u32)
+            .await
+            .map_err(|_| anyhow::anyhow!("stream window closed"))?
+            .forget();
+
+        Ok(())
+    }
+
+    /// Return `increment` bytes of credit granted by the peer.
+    pub fn grant(&self, increment: u32) {
+        if let Some(credit) = &self.credit {
+            credit.add_permits(increment as usize);
+        }
+    }
+
+    /// Wake pending reservations with an error once the stream is gone.
+    pub fn close(&self) {
+        if let Some(credit) = &self.credit {
+            credit.close();
+        }
+    }
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // ── Frame ────────────────────────────────────────────────────────────────
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Frame {
+    /// Connection-level handshake, sent once by each side before any stream frame.
+    Hello {
+        protocol_version: u16,
+        /// Software version of the sender.
+        version: String,
+        capabilities: Capabilities,
+    },
+    /// Connection-level notice that the sender is shutting down: no new streams will be
+    /// opened on this connection and the receiver should reconnect elsewhere while
+    /// in-flight streams finish.
+    GoAway {
+        message: String,
+    },
     OpenStream {
         stream_id: Uuid,
         method: Method,
         path_and_query: PathAndQuery,
         headers: Headers,
         content_length: Option<u64>,
+        /// `x-request-id` of the browser request.
+        request_id: Option<String>,
+        /// `traceparent` naming the server span as parent of the device's.
+        traceparent: Option<String>,
     },
     RequestBodyChunk {
         stream_id: Uuid,
@@ -45,19 +239,74 @@ pub enum Frame {
         status: u16,
         message: String,
     },
+    /// Raw TCP connection to `host:port` on the device side. The device answers with a
+    /// `200` `ResponseHead` once connected, or `ErrorStream`; bytes then flow as `StreamData`.
+    OpenTcpStream {
+        stream_id: Uuid,
+        host: String,
+        port: u16,
+    },
+    /// Raw bytes of an upgraded or TCP stream, sent in either direction once the stream is open.
+    StreamData {
+        stream_id: Uuid,
+        data: Bytes,
+    },
+    /// Half-close of an upgraded or TCP stream by the sender.
+    StreamEnd {
+        stream_id: Uuid,
+    },
+    /// Receiver grants the sender `increment` more bytes of data on the stream.
+    WindowUpdate {
+        stream_id: Uuid,
+        increment: u32,
+    },
+    /// Frame with a tag this build does not know; receivers ignore it.
+    Unknown {
+        tag: u8,
+    },
 }
 
 impl Frame {
-    pub fn stream_id(&self) -> Uuid {
+    /// `Hello` frame advertising this build's protocol version and capabilities.
+    pub fn hello(version: &str) -> Self {
+        Self::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: version.to_owned(),
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+
+    pub fn stream_id(&self) -> Option<Uuid> {
         match self {
             Self::OpenStream { stream_id, .. }
+            | Self::OpenTcpStream { stream_id, .. }
             | Self::RequestBodyChunk { stream_id, .. }
             | Self::RequestBodyEnd { stream_id }
             | Self::ResponseHead { stream_id, .. }
             | Self::ResponseBodyChunk { stream_id, .. }
             | Self::ResponseBodyEnd { stream_id }
             | Self::CancelStream { stream_id }
-            | Self::ErrorStream { stream_id, .. } => *stream_id,
+            | Self::ErrorStream { stream_id, .. }
+            | Self::StreamData { stream_id, .. }
+            | Self::StreamEnd { stream_id }
+            | Self::WindowUpdate { stream_id, .. } => Some(*stream_id),
+            Self::Hello { .. } | Self::GoAway { .. } | Self::Unknown { .. } => None,
+        }
+    }
+
+    /// Capabilities both peers must have negotiated before this frame may be exchanged.
+    pub fn required_capabilities(&self) -> Capabilities {
+        match self {
+            Self::StreamData { .. } | Self::StreamEnd { .. } => Capabilities::UPGRADE,
+            // TCP streams carry their bytes as `StreamData`.
+            Self::OpenTcpStream { .. } => Capabilities::TCP | Capabilities::UPGRADE,
+            Self::WindowUpdate { .. } => Capabilities::FLOW_CONTROL,
+            Self::OpenStream {
+                request_id,
+                traceparent,
+                ..
+            } if request_id.is_some() || traceparent.is_some() => Capabilities::TRACE_CONTEXT,
+            _ => Capabilities::empty(),
         }
     }
 }
@@ -72,6 +321,12 @@ const TAG_RESPONSE_BODY_CHUNK: u8 = 4;
 const TAG_RESPONSE_BODY_END: u8 = 5;
 const TAG_CANCEL_STREAM: u8 = 6;
 const TAG_ERROR_STREAM: u8 = 7;
+const TAG_HELLO: u8 = 8;
+const TAG_STREAM_DATA: u8 = 9;
+const TAG_STREAM_END: u8 = 10;
+const TAG_WINDOW_UPDATE: u8 = 11;
+const TAG_OPEN_TCP_STREAM: u8 = 12;
+const TAG_GO_AWAY: u8 = 13;
 
 // ── Encode ───────────────────────────────────────────────────────────────
 
@@ -79,12 +334,38 @@ pub fn encode_frame(frame: &Frame) -> Result<Vec<u8>> {
     let mut buf = BytesMut::with_capacity(256);
 
     match frame {
+        Frame::Hello {
+            protocol_version,
+            version,
+            capabilities,
+        } => {
+            // Tag
+            buf.put_u8(TAG_HELLO);
+
+            // Protocol version
+            buf.put_u16(*protocol_version);
+
+            // Software version
+            put_str(&mut buf, version)?;
+
+            // Capabilities
+            buf.put_u32(capabilities.bits());
+        }
+        Frame::GoAway { message } => {
+            // Tag
+            buf.put_u8(TAG_GO_AWAY);
+
+            // Reason
+            buf.put_slice(message.as_bytes());
+        }
         Frame::OpenStream {
             stream_id,
             method,
             path_and_query,
             headers,
             content_length,
+            request_id,
+            traceparent,
         } => {
             // TAG
             buf.put_u8(TAG_OPEN_STREAM);
@@ -93,16 +374,39 @@ pub fn encode_frame(frame: &Frame) -> Result<Vec<u8>> {
             put_uuid(&mut buf, stream_id);
 
             // Method
-            put_str(&mut buf, method.as_str());
+            put_str(&mut buf, method.as_str())?;
 
             // Path and Query
-            put_str(&mut buf, path_and_query.as_str());
+            put_str(&mut buf, path_and_query.as_str())?;
 
             // Length
             put_opt_u64(&mut buf, content_length);
 
             // Headers
-            put_headers(&mut buf, headers);
+            put_headers(&mut buf, headers)?;
+
+            // Trace context, only sent to peers that negotiated it
+            if request_id.is_some() || traceparent.is_some() {
+                put_opt_str(&mut buf, request_id.as_deref())?;
+                put_opt_str(&mut buf, traceparent.as_deref())?;
+            }
+        }
+        Frame::OpenTcpStream {
+            stream_id,
+            host,
+            port,
+        } => {
+            // Tag
+            buf.put_u8(TAG_OPEN_TCP_STREAM);
+
+            // Stream ID
+            put_uuid(&mut buf, stream_id);
+
+            // Target host
+            put_str(&mut buf, host)?;
+
+            // Target port
+            buf.put_u16(*port);
         }
         Frame::RequestBodyChunk { stream_id, data } => {
             // Tag
@@ -136,7 +440,7 @@ pub fn encode_frame(frame: &Frame) -> Result<Vec<u8>> {
             buf.put_u16(*status);
 
             // Headers
-            put_headers(&mut buf, headers);
+            put_headers(&mut buf, headers)?;
         }
         Frame::ResponseBodyChunk { stream_id, data } => {
             // Tag
@@ -179,6 +483,37 @@ pub fn encode_frame(frame: &Frame) -> Result<Vec<u8>> {
             // Error message
             buf.put_slice(message.as_bytes());
         }
+        Frame::StreamData { stream_id, data } => {
+            // Tag
+            buf.put_u8(TAG_STREAM_DATA);
+
+            // Stream ID
+            put_uuid(&mut buf, stream_id);
+
+            // Data chunk
+            buf.put_slice(data);
+        }
+        Frame::StreamEnd { stream_id } => {
+            // Tag
+            buf.put_u8(TAG_STREAM_END);
+
+            // Stream ID
+            put_uuid(&mut buf, stream_id);
+        }
+        Frame::WindowUpdate {
+            stream_id,
+            increment,
+        } => {
+            // Tag
+            buf.put_u8(TAG_WINDOW_UPDATE);
+
+            // Stream ID
+            put_uuid(&mut buf, stream_id);
+
+            // Increment
+            buf.put_u32(*increment);
+        }
+        Frame::Unknown { tag } => bail!("cannot encode unknown frame tag: {tag}"),
     }
 
     Ok(buf.to_vec())
@@ -191,7 +526,18 @@ pub fn decode_frame(bytes: &[u8]) -> Result<Frame> {
     let mut buf = bytes;
 
     let tag = buf.get_u8();
-    let stream_id = get_uuid(&mut buf)?;
+
+    let stream_id = match tag {
+        // Connection-level frames carry no stream ID.
+        TAG_HELLO => return decode_hello(buf),
+        TAG_GO_AWAY => return decode_go_away(buf),
+        TAG_OPEN_STREAM..=TAG_ERROR_STREAM
+        | TAG_STREAM_DATA
+        | TAG_STREAM_END
+        | TAG_WINDOW_UPDATE
+        | TAG_OPEN_TCP_STREAM => get_uuid(&mut buf)?,
+        _ => return Ok(Frame::Unknown { tag }),
+    };
 
     match tag {
         TAG_OPEN_STREAM => {
@@ -203,6 +549,11 @@ pub fn decode_frame(bytes: &[u8]) -> Result<Frame> {
                 .map_err(|e| anyhow::anyhow!("invalid path_and_query: {e}"))?;
             let content_length = get_opt_u64(&mut buf)?;
             let headers = get_headers(&mut buf)?;
+            let (request_id, traceparent) = if buf.has_remaining() {
+                (get_opt_str(&mut buf)?, get_opt_str(&mut buf)?)
+            } else {
+                (None, None)
+            };
             ensure!(
                 !buf.has_remaining(),
                 "unexpected trailing bytes in OpenStream"
@@ -213,6 +564,22 @@ pub fn decode_frame(bytes: &[u8]) -> Result<Frame> {
                 path_and_query,
                 headers,
                 content_length,
+                request_id,
+                traceparent,
+            })
+        }
+        TAG_OPEN_TCP_STREAM => {
+            let host = get_str(&mut buf)?.to_owned();
+            ensure!(buf.remaining() >= 2, "truncated tcp port");
+            let port = buf.get_u16();
+            ensure!(
+                !buf.has_remaining(),
+                "unexpected trailing bytes in OpenTcpStream"
+            );
+            Ok(Frame::OpenTcpStream {
+                stream_id,
+                host,
+                port,
             })
         }
         TAG_REQUEST_BODY_CHUNK => Ok(Frame::RequestBodyChunk {
@@ -273,10 +640,54 @@ pub fn decode_frame(bytes: &[u8]) -> Result<Frame> {
                 message,
             })
         }
-        _ => bail!("unknown frame tag: {tag}"),
+        TAG_STREAM_DATA => Ok(Frame::StreamData {
+            stream_id,
+            data: Bytes::copy_from_slice(buf),
+        }),
+        TAG_STREAM_END => {
+            ensure!(
+                !buf.has_remaining(),
+                "unexpected trailing bytes in StreamEnd"
+            );
+            Ok(Frame::StreamEnd { stream_id })
+        }
+        TAG_WINDOW_UPDATE => {
+            ensure!(buf.remaining() >= 4, "truncated window update");
+            let increment = buf.get_u32();
+            ensure!(
+                !buf.has_remaining(),
+                "unexpected trailing bytes in WindowUpdate"
+            );
+            Ok(Frame::WindowUpdate {
+                stream_id,
+                increment,
+            })
+        }
+        _ => unreachable!("stream tags are matched above"),
     }
 }
 
+fn decode_hello(mut buf: &[u8]) -> Result<Frame> {
+    ensure!(buf.remaining() >= 2, "truncated hello");
+    let protocol_version = buf.get_u16();
+    let version = get_str(&mut buf)?.to_owned();
+
+    ensure!(buf.remaining() >= 4, "truncated hello capabilities");
+    let capabilities = Capabilities::from_bits(buf.get_u32());
+
+    // Newer peers may append fields to Hello; ignore them.
+    Ok(Frame::Hello {
+        protocol_version,
+        version,
+        capabilities,
+    })
+}
+
+fn decode_go_away(buf: &[u8]) -> Result<Frame> {
+    let message = std::str::from_utf8(buf)?.to_owned();
+    Ok(Frame::GoAway { message })
+}
+
 // ── Primitives ───────────────────────────────────────────────────────────
 
 fn put_uuid(buf: &mut BytesMut, id: &Uuid) {
@@ -291,9 +702,11 @@ fn get_uuid(buf: &mut &[u8]) -> Result<Uuid> {
     Ok(Uuid::from_bytes(bytes))
 }
 
-fn put_str(buf: &mut BytesMut, s: &str) {
-    buf.put_u16(s.len() as u16);
+fn put_str(buf: &mut BytesMut, s: &str) -> Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| anyhow::anyhow!("string too long"))?;
+    buf.put_u16(len);
     buf.put_slice(s.as_bytes());
+    Ok(())
 }
 
 fn get_str<'a>(buf: &mut &'a [u8]) -> Result<&'a str> {
@@ -330,14 +743,37 @@ fn get_opt_u64(buf: &mut &[u8]) -> Result<Option<u64>> {
     }
 }
 
-fn put_headers(buf: &mut BytesMut, headers: &Headers) {
-    buf.put_u16(headers.len() as u16);
+fn put_opt_str(buf: &mut BytesMut, val: Option<&str>) -> Result<()> {
+    match val {
+        Some(v) => {
+            buf.put_u8(1);
+            put_str(buf, v)?;
+        }
+        None => buf.put_u8(0),
+    }
+    Ok(())
+}
+
+fn get_opt_str(buf: &mut &[u8]) -> Result<Option<String>> {
+    ensure!(buf.remaining() >= 1, "truncated option tag");
+    match buf.get_u8() {
+        0 => Ok(None),
+        1 => Ok(Some(get_str(buf)?.to_owned())),
+        t => bail!("invalid option tag: {t}"),
+    }
+}
+
+fn put_headers(buf: &mut BytesMut, headers: &Headers) -> Result<()> {
+    let count = u16::try_from(headers.len()).map_err(|_| anyhow::anyhow!("too many headers"))?;
+    buf.put_u16(count);
     for (name, value) in headers {
-        put_str(buf, name.as_str());
+        put_str(buf, name.as_str())?;
         let v = value.as_bytes();
-        buf.put_u16(v.len() as u16);
+        let vlen = u16::try_from(v.len()).map_err(|_| anyhow::anyhow!("header value too long"))?;
+        buf.put_u16(vlen);
         buf.put_slice(v);
     }
+    Ok(())
 }
 
 fn get_headers(buf: &mut &[u8]) -> Result<Headers> {
@@ -360,3 +796,429 @@ fn get_headers(buf: &mut &[u8]) -> Result<Headers> {
 
     Ok(headers)
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn stream_id() -> Uuid {
+        Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef)
+    }
+
+    fn headers() -> Headers {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html"),
+        );
+        headers.append(http::header::SET_COOKIE, HeaderValue::from_static("a=1"));
+        headers.append(http::header::SET_COOKIE, HeaderValue::from_static("b=2"));
+        headers
+    }
+
+    fn open_stream(request_id: Option<&str>, traceparent: Option<&str>) -> Frame {
+        Frame::OpenStream {
+            stream_id: stream_id(),
+            method: Method::POST,
+            path_and_query: PathAndQuery::from_static("/api/items?page=2"),
+            headers: headers(),
+            content_length: Some(42),
+            request_id: request_id.map(str::to_owned),
+            traceparent: traceparent.map(str::to_owned),
+        }
+    }
+
+    fn every_frame() -> Vec<Frame> {
+        vec![
+            Frame::hello("1.2.3"),
+            Frame::GoAway {
+                message: "restarting".to_owned(),
+            },
+            open_stream(None, None),
+            open_stream(
+                Some("req-1"),
+                Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            ),
+            open_stream(
+                None,
+                Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            ),
+            Frame::OpenStream {
+                stream_id: stream_id(),
+                method: Method::GET,
+                path_and_query: PathAndQuery::from_static("/"),
+                headers: HeaderMap::new(),
+                content_length: None,
+                request_id: None,
+                traceparent: None,
+            },
+            Frame::RequestBodyChunk {
+                stream_id: stream_id(),
+                data: Bytes::from_static(b"request body"),
+            },
+            Frame::RequestBodyEnd {
+                stream_id: stream_id(),
+            },
+            Frame::ResponseHead {
+                stream_id: stream_id(),
+                status: 200,
+                headers: headers(),
+            },
+            Frame::ResponseBodyChunk {
+                stream_id: stream_id(),
+                data: Bytes::from_static(b"response body"),
+            },
+            Frame::ResponseBodyEnd {
+                stream_id: stream_id(),
+            },
+            Frame::CancelStream {
+                stream_id: stream_id(),
+            },
+            Frame::ErrorStream {
+                stream_id: stream_id(),
+                status: 502,
+                message: "connection refused".to_owned(),
+            },
+            Frame::OpenTcpStream {
+                stream_id: stream_id(),
+                host: "127.0.0.1".to_owned(),
+                port: 22,
+            },
+            Frame::StreamData {
+                stream_id: stream_id(),
+                data: Bytes::from_static(&[0, 1, 2, 255]),
+            },
+            Frame::StreamEnd {
+                stream_id: stream_id(),
+            },
+            Frame::WindowUpdate {
+                stream_id: stream_id(),
+                increment: INITIAL_WINDOW_SIZE,
+            },
+        ]
+    }
+
+    /// Frames whose last field is not a free-form tail, so no shorter input is valid.
+    fn fixed_length_frames() -> Vec<Frame> {
+        every_frame()
+            .into_iter()
+            .filter(|frame| match frame {
+                Frame::GoAway { .. }
+                | Frame::RequestBodyChunk { .. }
+                | Frame::ResponseBodyChunk { .. }
+                | Frame::StreamData { .. }
+                | Frame::ErrorStream { .. } => false,
+                // Dropping the trace context leaves a valid frame from an older peer.
+                Frame::OpenStream {
+                    request_id,
+                    traceparent,
+                    ..
+                } => request_id.is_none() && traceparent.is_none(),
+                _ => true,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_frame_round_trips() {
+        for frame in every_frame() {
+            let bytes = encode_frame(&frame).unwrap();
+            assert_eq!(decode_frame(&bytes).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn empty_bodies_round_trip() {
+        for frame in [
+            Frame::RequestBodyChunk {
+                stream_id: stream_id(),
+                data: Bytes::new(),
+            },
+            Frame::GoAway {
+                message: String::new(),
+            },
+            Frame::ErrorStream {
+                stream_id: stream_id(),
+                status: 500,
+                message: String::new(),
+            },
+        ] {
+            let bytes = encode_frame(&frame).unwrap();
+            assert_eq!(decode_frame(&bytes).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn open_stream_without_trace_context_matches_older_peers() {
+        let plain = encode_frame(&open_stream(None, None)).unwrap();
+        let traced = encode_frame(&open_stream(Some("req-1"), None)).unwrap();
+
+        // Older peers stop after the headers and reject anything that follows.
+        assert!(traced.starts_with(&plain));
+        assert_eq!(decode_frame(&plain).unwrap(), open_stream(None, None));
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        assert!(decode_frame(&[]).is_err());
+
+        for frame in fixed_length_frames() {
+            let bytes = encode_frame(&frame).unwrap();
+            for len in 1..bytes.len() {
+                assert!(
+                    decode_frame(&bytes[..len]).is_err(),
+                    "{frame:?} decoded from {len} of {} bytes",
+                    bytes.len()
+                );
+            }
+        }
+
+        // The status of an error stream is not optional.
+        let bytes = encode_frame(&Frame::ErrorStream {
+            stream_id: stream_id(),
+            status: 502,
+            message: String::new(),
+        })
+        .unwrap();
+        assert!(decode_frame(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for frame in fixed_length_frames() {
+            if matches!(frame, Frame::Hello { .. }) {
+                continue;
+            }
+            let mut bytes = encode_frame(&frame).unwrap();
+            bytes.push(0);
+            assert!(
+                decode_frame(&bytes).is_err(),
+                "{frame:?} accepted a trailing byte"
+            );
+        }
+    }
+
+    #[test]
+    fn hello_ignores_appended_fields() {
+        let frame = Frame::hello("1.2.3");
+        let mut bytes = encode_frame(&frame).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(decode_frame(&bytes).unwrap(), frame);
+    }
+
+    #[test]
+    fn oversized_fields_are_rejected_when_encoding() {
+        let long = "a".repeat(u16::MAX as usize + 1);
+
+        let frame = Frame::OpenTcpStream {
+            stream_id: stream_id(),
+            host: long.clone(),
+            port: 22,
+        };
+        assert!(encode_frame(&frame).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-long", HeaderValue::from_str(&long).unwrap());
+        let frame = Frame::ResponseHead {
+            stream_id: stream_id(),
+            status: 200,
+            headers,
+        };
+        assert!(encode_frame(&frame).is_err());
+
+        let frame = open_stream(Some(&long), None);
+        assert!(encode_frame(&frame).is_err());
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_rejected() {
+        let mut bytes = vec![TAG_RESPONSE_HEAD];
+        bytes.extend_from_slice(stream_id().as_bytes());
+        bytes.extend_from_slice(&200u16.to_be_bytes());
+        // Two headers announced, none present.
+        bytes.extend_from_slice(&2u16.to_be_bytes());
+        assert!(decode_frame(&bytes).is_err());
+
+        let mut bytes = vec![TAG_OPEN_TCP_STREAM];
+        bytes.extend_from_slice(stream_id().as_bytes());
+        // Host length beyond the frame.
+        bytes.extend_from_slice(&u16::MAX.to_be_bytes());
+        bytes.extend_from_slice(b"localhost");
+        assert!(decode_frame(&bytes).is_err());
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        // Content length option tag other than 0 or 1.
+        let mut bytes = vec![TAG_OPEN_STREAM];
+        bytes.extend_from_slice(stream_id().as_bytes());
+        bytes.extend_from_slice(&[0, 3]);
+        bytes.extend_from_slice(b"GET");
+        bytes.extend_from_slice(&[0, 1]);
+        bytes.extend_from_slice(b"/");
+        bytes.push(2);
+        assert!(decode_frame(&bytes).is_err());
+
+        // Invalid UTF-8 in a string.
+        let mut bytes = vec![TAG_OPEN_TCP_STREAM];
+        bytes.extend_from_slice(stream_id().as_bytes());
+        bytes.extend_from_slice(&[0, 2, 0xff, 0xfe, 0, 22]);
+        assert!(decode_frame(&bytes).is_err());
+
+        // Header name with a space.
+        let mut bytes = vec![TAG_RESPONSE_HEAD];
+        bytes.extend_from_slice(stream_id().as_bytes());
+        bytes.extend_from_slice(&[0, 200, 0, 1, 0, 3]);
+        bytes.extend_from_slice(b"a b");
+        bytes.extend_from_slice(&[0, 1]);
+        bytes.extend_from_slice(b"c");
+        assert!(decode_frame(&bytes).is_err());
+    }
+
+    #[test]
+    fn unknown_tags_decode_but_do_not_encode() {
+        for tag in [TAG_GO_AWAY + 1, 0x7f, u8::MAX] {
+            let frame = decode_frame(&[tag, 1, 2, 3]).unwrap();
+            assert_eq!(frame, Frame::Unknown { tag });
+            assert_eq!(frame.stream_id(), None);
+            assert!(encode_frame(&frame).is_err());
+        }
+    }
+
+    #[test]
+    fn negotiate_keeps_common_version_and_capabilities() {
+        let handshake = Handshake::negotiate(Frame::Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            version: "9.9.9".to_owned(),
+            capabilities: Capabilities::UPGRADE | Capabilities::from_bits(1 << 31),
+        })
+        .unwrap();
+
+        assert_eq!(handshake.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(handshake.peer_version, "9.9.9");
+        assert_eq!(handshake.capabilities, Capabilities::UPGRADE);
+    }
+
+    #[test]
+    fn negotiate_rejects_old_versions_and_other_frames() {
+        let old = Frame::Hello {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            version: "0.0.1".to_owned(),
+            capabilities: Capabilities::SUPPORTED,
+        };
+        assert!(Handshake::negotiate(old).is_err());
+
+        let not_hello = Frame::CancelStream {
+            stream_id: stream_id(),
+        };
+        assert!(Handshake::negotiate(not_hello).is_err());
+    }
+
+    #[test]
+    fn handshake_allows_frames_by_capability() {
+        let handshake = Handshake::negotiate(Frame::hello("1.0.0")).unwrap();
+        for frame in every_frame() {
+            assert!(handshake.allows(&frame), "{frame:?}");
+        }
+
+        let handshake = Handshake::negotiate(Frame::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: "1.0.0".to_owned(),
+            capabilities: Capabilities::UPGRADE,
+        })
+        .unwrap();
+        let stream_end = Frame::StreamEnd {
+            stream_id: stream_id(),
+        };
+        let tcp = Frame::OpenTcpStream {
+            stream_id: stream_id(),
+            host: "127.0.0.1".to_owned(),
+            port: 22,
+        };
+        let window_update = Frame::WindowUpdate {
+            stream_id: stream_id(),
+            increment: 1,
+        };
+
+        assert!(handshake.allows(&open_stream(None, None)));
+        assert!(handshake.allows(&stream_end));
+        assert!(!handshake.allows(&tcp));
+        assert!(!handshake.allows(&window_update));
+        assert!(!handshake.allows(&open_stream(Some("req-1"), None)));
+    }
+
+    #[test]
+    fn send_window_waits_for_credit() {
+        let window = SendWindow::new();
+        window
+            .reserve(INITIAL_WINDOW_SIZE as usize)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let mut pending = Box::pin(window.reserve(10));
+        assert!(pending.as_mut().now_or_never().is_none());
+
+        window.grant(4);
+        assert!(pending.as_mut().now_or_never().is_none());
+
+        window.grant(6);
+        pending.as_mut().now_or_never().unwrap().unwrap();
+    }
+
+    #[test]
+    fn send_window_rejects_oversized_chunks() {
+        let window = SendWindow::new();
+        let result = window
+            .reserve(INITIAL_WINDOW_SIZE as usize + 1)
+            .now_or_never()
+            .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn closed_send_window_fails_waiting_reservations() {
+        let window = SendWindow::new();
+        window
+            .reserve(INITIAL_WINDOW_SIZE as usize)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let mut pending = Box::pin(window.reserve(1));
+        assert!(pending.as_mut().now_or_never().is_none());
+
+        window.close();
+        assert!(pending.as_mut().now_or_never().unwrap().is_err());
+    }
+
+    #[test]
+    fn unlimited_send_window_never_waits() {
+        let window = SendWindow::unlimited();
+        for _ in 0..4 {
+            window
+                .reserve(INITIAL_WINDOW_SIZE as usize)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn upgrade_requests_need_connection_and_upgrade_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(!is_upgrade_request(&headers));
+
+        headers.insert(
+            http::header::CONNECTION,
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        assert!(is_upgrade_request(&headers));
+
+        headers.remove(http::header::UPGRADE);
+        assert!(!is_upgrade_request(&headers));
+    }
+}